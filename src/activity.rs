@@ -0,0 +1,65 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    engine::{DepositState, Engine},
+    ClientId, TransactionId, TransactionRecord, TransactionType,
+};
+
+/// What a client's account has been through, for the extended account report.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AccountActivity {
+    pub open_disputes: usize,
+    pub deposited: Decimal,
+    pub withdrawn: Decimal,
+    pub chargebacks: u64,
+    /// The last transaction applied to the account, if any were this run.
+    pub last_tx: Option<TransactionId>,
+}
+
+/// Follows the transactions applied to each account over the run.
+///
+/// This is shared with the processor rather than owned by it, so that it is still around to
+/// report on once processing has finished.
+#[derive(Debug, Default)]
+pub struct Activity {
+    clients: RefCell<BTreeMap<ClientId, AccountActivity>>,
+}
+
+impl Activity {
+    /// Counts a transaction that has just been applied.
+    pub fn record(&self, transaction: &TransactionRecord) {
+        let mut clients = self.clients.borrow_mut();
+        let activity = clients.entry(transaction.client).or_default();
+
+        match transaction.r#type {
+            TransactionType::Deposit => {
+                activity.deposited += transaction.amount.unwrap_or_default();
+            }
+            TransactionType::Withdrawal => {
+                activity.withdrawn += transaction.amount.unwrap_or_default();
+            }
+            TransactionType::Chargeback => {
+                activity.chargebacks += 1;
+            }
+            TransactionType::Dispute | TransactionType::Resolve => (),
+        }
+
+        activity.last_tx = Some(transaction.tx);
+    }
+
+    /// The figures for every account, with open disputes counted from `engine`, which includes
+    /// any opened before a saved state was loaded.
+    pub fn finish(self, engine: &Engine) -> BTreeMap<ClientId, AccountActivity> {
+        let mut clients = self.clients.into_inner();
+
+        for (_, deposit) in engine.deposits() {
+            if deposit.state == DepositState::Dispute {
+                clients.entry(deposit.client).or_default().open_disputes += 1;
+            }
+        }
+
+        clients
+    }
+}
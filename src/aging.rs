@@ -0,0 +1,189 @@
+use std::{collections::BTreeMap, io, str::FromStr};
+
+use anyhow::{bail, Context};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    engine::{DepositState, DisputeAges, Engine},
+    output::Rounding,
+    ClientId,
+};
+
+/// The ages that split held funds into recent, aging, and old. These are counted in transactions,
+/// not days, since records carry no timestamps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AgingBuckets {
+    /// Disputes open for fewer transactions than this are recent.
+    pub aging: u64,
+    /// Disputes open for at least this many transactions are old.
+    pub old: u64,
+}
+
+impl TryFrom<String> for AgingBuckets {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Parses the two ages as `aging,old`.
+impl FromStr for AgingBuckets {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (aging, old) = s
+            .split_once(',')
+            .context("expected two ages, as aging,old")?;
+        let aging = aging.trim().parse().context("invalid age")?;
+        let old = old.trim().parse().context("invalid age")?;
+
+        if aging >= old {
+            bail!("the first age must be less than the second");
+        }
+
+        Ok(Self { aging, old })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct AgingRecord {
+    client: ClientId,
+    open_disputes: u64,
+    held: Decimal,
+    recent: Decimal,
+    aging: Decimal,
+    old: Decimal,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Held {
+    open_disputes: u64,
+    recent: Decimal,
+    aging: Decimal,
+    old: Decimal,
+}
+
+/// Reports the funds each client has held under dispute by how long they have been held, for
+/// reserving against old disputes.
+///
+/// Like [`crate::expiry::Expiry`], a dispute's age is the number of transactions that have come
+/// in since it was opened, as kept in [`DisputeAges`], so disputes already open in a loaded state
+/// keep the age they had when it was saved.
+pub struct AgingReport<'a> {
+    buckets: AgingBuckets,
+    rounding: Rounding,
+    writer: csv::Writer<Box<dyn io::Write + 'a>>,
+}
+
+impl<'a> AgingReport<'a> {
+    pub fn new(buckets: AgingBuckets, writer: impl io::Write + 'a, rounding: Rounding) -> Self {
+        Self {
+            buckets,
+            rounding,
+            writer: csv::Writer::from_writer(Box::new(writer)),
+        }
+    }
+
+    /// Writes one row per client with funds held under dispute in `engine`, aged by `ages`.
+    pub fn finish(&mut self, engine: &Engine, ages: &DisputeAges) -> Result<(), csv::Error> {
+        let mut clients = BTreeMap::<ClientId, Held>::new();
+
+        for (tx, deposit) in engine.deposits() {
+            if deposit.state != DepositState::Dispute {
+                continue;
+            }
+
+            // A dispute whose age isn't known is counted as old rather than recent.
+            let age = ages.age(tx).unwrap_or(u64::MAX);
+
+            let held = clients.entry(deposit.client).or_default();
+            held.open_disputes += 1;
+
+            let bucket = if age < self.buckets.aging {
+                &mut held.recent
+            } else if age < self.buckets.old {
+                &mut held.aging
+            } else {
+                &mut held.old
+            };
+            *bucket += deposit.amount();
+        }
+
+        for (client, held) in clients {
+            self.writer.serialize(AgingRecord {
+                client,
+                open_disputes: held.open_disputes,
+                held: self.rounding.round(held.recent + held.aging + held.old),
+                recent: self.rounding.round(held.recent),
+                aging: self.rounding.round(held.aging),
+                old: self.rounding.round(held.old),
+            })?;
+        }
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TransactionId;
+
+    #[test]
+    fn buckets_held_funds_by_age() {
+        let buckets = AgingBuckets { aging: 2, old: 4 };
+        let mut output = Vec::new();
+        let rounding = Rounding {
+            normalize: true,
+            ..Rounding::default()
+        };
+        let mut report = AgingReport::new(buckets, &mut output, rounding);
+        let mut engine = Engine::new();
+        let mut ages = DisputeAges::default();
+
+        for tx in 1_u16..=3 {
+            engine
+                .deposit(ClientId(1), TransactionId(tx.into()), Decimal::from(tx))
+                .unwrap();
+        }
+
+        // Each dispute is opened two transactions after the last, and the last is resolved.
+        for tx in 1_u16..=3 {
+            let tx = TransactionId(tx.into());
+
+            ages.tick();
+            engine.dispute(ClientId(1), tx).unwrap();
+            ages.open(tx);
+            ages.tick();
+        }
+
+        engine.resolve(ClientId(1), TransactionId(3)).unwrap();
+        ages.close(TransactionId(3));
+
+        report.finish(&engine, &ages).unwrap();
+        drop(report);
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,open_disputes,held,recent,aging,old\n1,2,3,0,2,1\n"
+        );
+    }
+
+    #[test]
+    fn parses_buckets() {
+        assert_eq!(
+            "7000, 30000".parse::<AgingBuckets>().unwrap(),
+            AgingBuckets {
+                aging: 7000,
+                old: 30000
+            }
+        );
+        assert!("30000,7000".parse::<AgingBuckets>().is_err());
+        assert!("7000".parse::<AgingBuckets>().is_err());
+    }
+}
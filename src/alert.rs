@@ -0,0 +1,114 @@
+use std::{collections::BTreeMap, io};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{clients::Clients, ClientId, TransactionId, TransactionRecord, TransactionType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertRule {
+    /// A single deposit or withdrawal exceeded the threshold.
+    Amount,
+    /// A client's deposits and withdrawals so far in the run, added together, exceeded the
+    /// threshold.
+    Cumulative,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct AlertRecord<'a> {
+    rule: AlertRule,
+    client: ClientId,
+    tx: TransactionId,
+    r#type: TransactionType,
+    amount: Decimal,
+    cumulative: Decimal,
+    threshold: Decimal,
+    /// Client details, only written when a clients file is given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tier: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<&'a str>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Thresholds {
+    pub amount: Option<Decimal>,
+    pub cumulative: Option<Decimal>,
+}
+
+/// Watches applied transactions for amounts that need compliance review.
+///
+/// Alerts are only reported; they never stop a transaction from being applied. The input carries
+/// no dates, so cumulative amounts cover the whole run, and each client raises at most one
+/// cumulative alert per run.
+pub struct Alerter<'a> {
+    thresholds: Thresholds,
+    cumulative: BTreeMap<ClientId, Decimal>,
+    writer: csv::Writer<Box<dyn io::Write + 'a>>,
+    clients: Option<&'a Clients>,
+}
+
+impl<'a> Alerter<'a> {
+    pub fn new(thresholds: Thresholds, writer: impl io::Write + 'a) -> Self {
+        Self {
+            thresholds,
+            cumulative: BTreeMap::new(),
+            writer: csv::Writer::from_writer(Box::new(writer)),
+            clients: None,
+        }
+    }
+
+    /// Adds each client's details from `clients` to their alerts.
+    pub fn with_clients(mut self, clients: &'a Clients) -> Self {
+        self.clients = Some(clients);
+        self
+    }
+
+    pub fn check(&mut self, transaction: &TransactionRecord) -> Result<(), csv::Error> {
+        let Some(amount) = transaction.amount else {
+            return Ok(());
+        };
+
+        let cumulative = self.cumulative.entry(transaction.client).or_default();
+        let previous = *cumulative;
+        *cumulative += amount;
+
+        let info = self.clients.map(|clients| clients.get(transaction.client));
+
+        let mut alert = |rule, threshold| {
+            self.writer.serialize(AlertRecord {
+                rule,
+                client: transaction.client,
+                tx: transaction.tx,
+                r#type: transaction.r#type,
+                amount,
+                cumulative: previous + amount,
+                threshold,
+                name: info.map(|info| info.name.as_str()),
+                tier: info.map(|info| info.tier.as_str()),
+                email: info.map(|info| info.email.as_str()),
+            })
+        };
+
+        if let Some(threshold) = self.thresholds.amount {
+            if amount > threshold {
+                alert(AlertRule::Amount, threshold)?;
+            }
+        }
+
+        if let Some(threshold) = self.thresholds.cumulative {
+            if previous <= threshold && previous + amount > threshold {
+                alert(AlertRule::Cumulative, threshold)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,103 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// An amount of money as the engine holds it.
+///
+/// By default this is a [`Decimal`]. With the `fixed-point` feature it is instead an `i64` count
+/// of ten-thousandths, which is half the size and much faster to add up, but cannot hold amounts
+/// with more than four decimal places or beyond about ±922 trillion. Either way, amounts are only
+/// converted at the engine's edges, and every sum is checked for overflow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct Amount(#[cfg_attr(not(feature = "fixed-point"), serde(with = "repr::bytes"))] Repr);
+
+#[cfg(not(feature = "fixed-point"))]
+mod repr {
+    use rust_decimal::Decimal;
+
+    pub type Repr = Decimal;
+
+    pub const ZERO: Repr = Decimal::ZERO;
+
+    #[expect(
+        clippy::unnecessary_wraps,
+        reason = "matches the fixed-point version, which can fail"
+    )]
+    pub fn from_decimal(amount: Decimal) -> Option<Repr> {
+        Some(amount)
+    }
+
+    pub fn to_decimal(repr: Repr) -> Decimal {
+        repr
+    }
+
+    /// Saves a decimal as its 16 byte form, since decimals otherwise need a self-describing
+    /// format to load from, which bincode isn't.
+    pub mod bytes {
+        use rust_decimal::Decimal;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            amount: &Decimal,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            amount.serialize().serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Decimal, D::Error> {
+            <[u8; 16]>::deserialize(deserializer).map(Decimal::deserialize)
+        }
+    }
+}
+
+#[cfg(feature = "fixed-point")]
+mod repr {
+    use rust_decimal::Decimal;
+
+    pub type Repr = i64;
+
+    pub const ZERO: Repr = 0;
+
+    /// Decimal places kept.
+    const SCALE: u32 = 4;
+
+    pub fn from_decimal(amount: Decimal) -> Option<Repr> {
+        let scaled = amount.checked_mul(Decimal::from(10_i64.pow(SCALE)))?;
+
+        if scaled != scaled.trunc() {
+            return None;
+        }
+
+        i64::try_from(scaled).ok()
+    }
+
+    /// Drops trailing zeros, so that whole amounts print as they do without the feature, rather
+    /// than always with four decimal places.
+    pub fn to_decimal(repr: Repr) -> Decimal {
+        Decimal::new(repr, SCALE).normalize()
+    }
+}
+
+use repr::Repr;
+
+impl Amount {
+    pub const ZERO: Self = Self(repr::ZERO);
+
+    /// Converts an amount from the input, or `None` if it can't be represented.
+    pub fn from_decimal(amount: Decimal) -> Option<Self> {
+        repr::from_decimal(amount).map(Self)
+    }
+
+    pub fn to_decimal(self) -> Decimal {
+        repr::to_decimal(self.0)
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+}
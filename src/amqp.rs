@@ -0,0 +1,604 @@
+use std::{
+    convert::Infallible,
+    io::{self, BufReader, Read, Write},
+    net::TcpStream,
+};
+
+use anyhow::{bail, Context};
+
+use crate::source::{Consumer, SourceOptions};
+
+/// Sent before anything else, to say which version of the protocol the client speaks.
+const PROTOCOL_HEADER: &[u8; 8] = b"AMQP\x00\x00\x09\x01";
+
+const FRAME_METHOD: u8 = 1;
+const FRAME_HEADER: u8 = 2;
+const FRAME_BODY: u8 = 3;
+const FRAME_HEARTBEAT: u8 = 8;
+const FRAME_END: u8 = 0xce;
+
+/// The largest frame to accept, if the server doesn't set a limit.
+const DEFAULT_FRAME_MAX: u32 = 128 * 1024;
+
+/// The channel that everything after the handshake happens on.
+const CHANNEL: u16 = 1;
+
+/// Methods, as their class and method IDs.
+type Method = (u16, u16);
+
+const CONNECTION_START: Method = (10, 10);
+const CONNECTION_START_OK: Method = (10, 11);
+const CONNECTION_TUNE: Method = (10, 30);
+const CONNECTION_TUNE_OK: Method = (10, 31);
+const CONNECTION_OPEN: Method = (10, 40);
+const CONNECTION_OPEN_OK: Method = (10, 41);
+const CONNECTION_CLOSE: Method = (10, 50);
+const CHANNEL_OPEN: Method = (20, 10);
+const CHANNEL_OPEN_OK: Method = (20, 11);
+const CHANNEL_CLOSE: Method = (20, 40);
+const BASIC_QOS: Method = (60, 10);
+const BASIC_QOS_OK: Method = (60, 11);
+const BASIC_CONSUME: Method = (60, 20);
+const BASIC_CONSUME_OK: Method = (60, 21);
+const BASIC_DELIVER: Method = (60, 60);
+const BASIC_ACK: Method = (60, 80);
+const BASIC_REJECT: Method = (60, 90);
+
+/// Consumes transaction files from an AMQP 0-9-1 queue, acking each message once it has been
+/// applied and rejecting it if it can't be.
+///
+/// Rejected messages aren't requeued, so the broker dead-letters them if the queue has a
+/// dead-letter exchange, and drops them otherwise. At most `options.prefetch` messages are
+/// delivered ahead of being acked.
+///
+/// The connection is plain TCP, without TLS, and asks the broker for no heartbeats, so a
+/// connection that drops without closing may go unnoticed until TCP gives up on it.
+pub fn run(options: &SourceOptions, mut consumer: Consumer) -> Result<Infallible, anyhow::Error> {
+    let broker = options
+        .broker
+        .as_deref()
+        .context("--source amqp requires --broker")?;
+    let queue = options
+        .queue
+        .as_deref()
+        .context("--source amqp requires --queue")?;
+
+    let mut connection = Connection::open(
+        broker,
+        options.user.as_deref().unwrap_or("guest"),
+        options.password.as_deref().unwrap_or("guest"),
+        options.vhost.as_deref().unwrap_or("/"),
+    )?;
+
+    connection.call(
+        CHANNEL,
+        CHANNEL_OPEN,
+        &Args::new().short_str("")?,
+        CHANNEL_OPEN_OK,
+    )?;
+    connection.call(
+        CHANNEL,
+        BASIC_QOS,
+        &Args::new().u32(0).u16(options.prefetch).u8(0),
+        BASIC_QOS_OK,
+    )?;
+    // Explicit acks, and no flags otherwise.
+    connection.call(
+        CHANNEL,
+        BASIC_CONSUME,
+        &Args::new()
+            .u16(0)
+            .short_str(queue)?
+            .short_str("")?
+            .u8(0)
+            .empty_table(),
+        BASIC_CONSUME_OK,
+    )?;
+
+    loop {
+        let (tag, body) = connection.next_delivery()?;
+
+        let name = format!("message {tag} from {queue}");
+        let reply = if consumer.apply(&body, &name)? {
+            BASIC_ACK
+        } else {
+            BASIC_REJECT
+        };
+
+        // Neither acks several messages at once or requeues them.
+        connection.send_method(CHANNEL, reply, &Args::new().u64(tag).u8(0))?;
+    }
+}
+
+/// The arguments of a method, as they are written.
+#[derive(Default)]
+struct Args(Vec<u8>);
+
+impl Args {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn u8(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn short_str(mut self, value: &str) -> Result<Self, anyhow::Error> {
+        let len = u8::try_from(value.len()).with_context(|| format!("too long: {value}"))?;
+        self.0.push(len);
+        self.0.extend_from_slice(value.as_bytes());
+        Ok(self)
+    }
+
+    fn long_str(mut self, value: &[u8]) -> Result<Self, anyhow::Error> {
+        let len = u32::try_from(value.len()).context("string too long")?;
+        self.0.extend_from_slice(&len.to_be_bytes());
+        self.0.extend_from_slice(value);
+        Ok(self)
+    }
+
+    fn empty_table(self) -> Self {
+        self.u32(0)
+    }
+}
+
+/// Reads the arguments of a method or the fields of a content header.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], anyhow::Error> {
+        if self.0.len() < len {
+            bail!("frame too short");
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, anyhow::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, anyhow::Error> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, anyhow::Error> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, anyhow::Error> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn short_str(&mut self) -> Result<&'a [u8], anyhow::Error> {
+        let len = self.u8()?;
+        self.take(len.into())
+    }
+
+    fn long_str(&mut self) -> Result<&'a [u8], anyhow::Error> {
+        let len = self.u32()?;
+        self.take(len.try_into()?)
+    }
+}
+
+struct Frame {
+    kind: u8,
+    channel: u16,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// The method a method frame carries, and its arguments.
+    fn method(&self) -> Result<(Method, Fields<'_>), anyhow::Error> {
+        let mut fields = Fields(&self.payload);
+        let method = (fields.u16()?, fields.u16()?);
+        Ok((method, fields))
+    }
+}
+
+fn read_frame(reader: &mut impl Read, frame_max: u32) -> Result<Frame, anyhow::Error> {
+    let mut header = [0; 7];
+    reader.read_exact(&mut header).map_err(|err| {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            anyhow::anyhow!("AMQP closed the connection")
+        } else {
+            anyhow::Error::new(err).context("failed to read from AMQP")
+        }
+    })?;
+
+    let kind = header[0];
+    let channel = u16::from_be_bytes([header[1], header[2]]);
+    let size = u32::from_be_bytes([header[3], header[4], header[5], header[6]]);
+
+    if size > frame_max {
+        bail!("frame too large (size: {size}, max: {frame_max})");
+    }
+
+    let mut payload = vec![0; size as usize + 1];
+    reader
+        .read_exact(&mut payload)
+        .context("failed to read from AMQP")?;
+
+    if payload.pop() != Some(FRAME_END) {
+        bail!("invalid frame from AMQP");
+    }
+
+    Ok(Frame {
+        kind,
+        channel,
+        payload,
+    })
+}
+
+fn write_frame(
+    writer: &mut impl Write,
+    kind: u8,
+    channel: u16,
+    payload: &[u8],
+) -> Result<(), anyhow::Error> {
+    let size = u32::try_from(payload.len()).context("frame too large")?;
+
+    let mut frame = Vec::with_capacity(payload.len() + 8);
+    frame.push(kind);
+    frame.extend_from_slice(&channel.to_be_bytes());
+    frame.extend_from_slice(&size.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.push(FRAME_END);
+
+    writer.write_all(&frame).context("failed to write to AMQP")
+}
+
+fn method_payload((class, method): Method, args: &Args) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(args.0.len() + 4);
+    payload.extend_from_slice(&class.to_be_bytes());
+    payload.extend_from_slice(&method.to_be_bytes());
+    payload.extend_from_slice(&args.0);
+    payload
+}
+
+/// A connection speaking AMQP 0-9-1, without TLS or heartbeats.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    frame_max: u32,
+}
+
+impl Connection {
+    fn open(broker: &str, user: &str, password: &str, vhost: &str) -> Result<Self, anyhow::Error> {
+        let stream =
+            TcpStream::connect(broker).with_context(|| format!("failed to connect to {broker}"))?;
+        let mut connection = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            frame_max: DEFAULT_FRAME_MAX,
+        };
+
+        connection
+            .writer
+            .write_all(PROTOCOL_HEADER)
+            .context("failed to write to AMQP")?;
+
+        let start = connection.expect_method(0, CONNECTION_START)?;
+        let mut fields = Fields(&start);
+        _ = fields.take(2)?;
+        let properties = fields.u32()?;
+        _ = fields.take(properties.try_into()?)?;
+        let mechanisms = fields.long_str()?;
+        if !mechanisms
+            .split(|&byte| byte == b' ')
+            .any(|mechanism| mechanism == b"PLAIN")
+        {
+            bail!("{broker} doesn't accept PLAIN authentication");
+        }
+
+        let mut response = Vec::new();
+        for part in [user, password] {
+            response.push(0);
+            response.extend_from_slice(part.as_bytes());
+        }
+        connection.send_method(
+            0,
+            CONNECTION_START_OK,
+            &Args::new()
+                .empty_table()
+                .short_str("PLAIN")?
+                .long_str(&response)?
+                .short_str("en_US")?,
+        )?;
+
+        let tune = connection.expect_method(0, CONNECTION_TUNE)?;
+        let mut fields = Fields(&tune);
+        let channel_max = fields.u16()?;
+        let frame_max = fields.u32()?;
+        if frame_max != 0 {
+            connection.frame_max = frame_max;
+        }
+
+        // A heartbeat of zero turns them off, since nothing would send them between messages.
+        connection.send_method(
+            0,
+            CONNECTION_TUNE_OK,
+            &Args::new()
+                .u16(channel_max)
+                .u32(connection.frame_max)
+                .u16(0),
+        )?;
+        connection.call(
+            0,
+            CONNECTION_OPEN,
+            &Args::new().short_str(vhost)?.short_str("")?.u8(0),
+            CONNECTION_OPEN_OK,
+        )?;
+
+        Ok(connection)
+    }
+
+    fn send_method(
+        &mut self,
+        channel: u16,
+        method: Method,
+        args: &Args,
+    ) -> Result<(), anyhow::Error> {
+        write_frame(
+            &mut self.writer,
+            FRAME_METHOD,
+            channel,
+            &method_payload(method, args),
+        )
+    }
+
+    /// Sends a method and waits for the one that answers it.
+    fn call(
+        &mut self,
+        channel: u16,
+        method: Method,
+        args: &Args,
+        answer: Method,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        self.send_method(channel, method, args)?;
+        self.expect_method(channel, answer)
+    }
+
+    /// Reads the next frame that isn't a heartbeat.
+    fn next_frame(&mut self) -> Result<Frame, anyhow::Error> {
+        loop {
+            let frame = read_frame(&mut self.reader, self.frame_max)?;
+
+            if frame.kind != FRAME_HEARTBEAT {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /// Reads `method` on `channel`, returning its arguments, or fails if anything else comes.
+    fn expect_method(&mut self, channel: u16, method: Method) -> Result<Vec<u8>, anyhow::Error> {
+        let frame = self.next_frame()?;
+        let (actual, fields) = check_method(&frame)?;
+
+        if frame.channel != channel || actual != method {
+            bail!(
+                "unexpected method from AMQP (channel: {}, method: {actual:?})",
+                frame.channel
+            );
+        }
+
+        Ok(fields.0.to_vec())
+    }
+
+    /// Reads up to the next delivery, returning its tag and body.
+    fn next_delivery(&mut self) -> Result<(u64, Vec<u8>), anyhow::Error> {
+        let frame = self.next_frame()?;
+        let (method, mut fields) = check_method(&frame)?;
+
+        if method != BASIC_DELIVER {
+            bail!(
+                "unexpected method from AMQP (channel: {}, method: {method:?})",
+                frame.channel
+            );
+        }
+
+        _ = fields.short_str()?;
+        let tag = fields.u64()?;
+
+        let header = self.next_frame()?;
+        if header.kind != FRAME_HEADER {
+            bail!("expected a content header from AMQP");
+        }
+        let mut fields = Fields(&header.payload);
+        _ = fields.take(4)?;
+        let size: usize = fields.u64()?.try_into()?;
+
+        let mut body = Vec::with_capacity(size);
+        while body.len() < size {
+            let frame = self.next_frame()?;
+            if frame.kind != FRAME_BODY {
+                bail!("expected a content body from AMQP");
+            }
+            body.extend_from_slice(&frame.payload);
+        }
+
+        Ok((tag, body))
+    }
+}
+
+/// Reads the method in a frame that should hold one, failing with the broker's reason if it
+/// closes the channel or connection.
+fn check_method(frame: &Frame) -> Result<(Method, Fields<'_>), anyhow::Error> {
+    if frame.kind != FRAME_METHOD {
+        bail!("unexpected frame from AMQP (type: {})", frame.kind);
+    }
+
+    let (method, mut fields) = frame.method()?;
+
+    if method == CONNECTION_CLOSE || method == CHANNEL_CLOSE {
+        let code = fields.u16()?;
+        let text = String::from_utf8_lossy(fields.short_str()?);
+        bail!(
+            "AMQP closed the {} ({code}: {text})",
+            if method == CHANNEL_CLOSE {
+                "channel"
+            } else {
+                "connection"
+            }
+        );
+    }
+
+    Ok((method, fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, net::TcpListener, process, thread};
+
+    use super::*;
+    use crate::{
+        input::ReadOptions,
+        output::Rounding,
+        policy::{Policy, Preset},
+        processor::Processor,
+        source::{self, SourceKind},
+    };
+
+    /// Reads a method from a client, checking that it's `expected`, and returns its arguments.
+    fn expect(reader: &mut impl Read, expected: Method) -> Vec<u8> {
+        let frame = read_frame(reader, DEFAULT_FRAME_MAX).unwrap();
+        let (method, fields) = frame.method().unwrap();
+        assert_eq!(method, expected);
+        fields.0.to_vec()
+    }
+
+    fn send(writer: &mut impl Write, channel: u16, method: Method, args: &Args) {
+        write_frame(writer, FRAME_METHOD, channel, &method_payload(method, args)).unwrap();
+    }
+
+    fn deliver(writer: &mut impl Write, tag: u64, body: &str) {
+        let args = Args::new()
+            .short_str("spork")
+            .unwrap()
+            .u64(tag)
+            .u8(0)
+            .short_str("")
+            .unwrap()
+            .short_str("payments")
+            .unwrap();
+        send(writer, CHANNEL, BASIC_DELIVER, &args);
+
+        let header = Args::new()
+            .u16(BASIC_DELIVER.0)
+            .u16(0)
+            .u64(body.len() as u64)
+            .u16(0);
+        write_frame(writer, FRAME_HEADER, CHANNEL, &header.0).unwrap();
+        write_frame(writer, FRAME_BODY, CHANNEL, body.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn acks_applied_messages_and_rejects_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+
+            let mut header = [0; 8];
+            reader.read_exact(&mut header).unwrap();
+            assert_eq!(&header, PROTOCOL_HEADER);
+
+            let start = Args::new()
+                .u8(0)
+                .u8(9)
+                .empty_table()
+                .long_str(b"AMQPLAIN PLAIN")
+                .unwrap()
+                .long_str(b"en_US")
+                .unwrap();
+            send(&mut writer, 0, CONNECTION_START, &start);
+            let start_ok = expect(&mut reader, CONNECTION_START_OK);
+            assert!(start_ok.ends_with(b"\x00guest\x00guest\x05en_US"));
+
+            let tune = Args::new().u16(0).u32(0).u16(60);
+            send(&mut writer, 0, CONNECTION_TUNE, &tune);
+            _ = expect(&mut reader, CONNECTION_TUNE_OK);
+            _ = expect(&mut reader, CONNECTION_OPEN);
+            send(&mut writer, 0, CONNECTION_OPEN_OK, &Args::new().u8(0));
+
+            _ = expect(&mut reader, CHANNEL_OPEN);
+            send(&mut writer, CHANNEL, CHANNEL_OPEN_OK, &Args::new().u32(0));
+            let qos = expect(&mut reader, BASIC_QOS);
+            assert_eq!(qos, [0, 0, 0, 0, 0, 10, 0]);
+            send(&mut writer, CHANNEL, BASIC_QOS_OK, &Args::new());
+            _ = expect(&mut reader, BASIC_CONSUME);
+            let consume_ok = Args::new().short_str("spork").unwrap();
+            send(&mut writer, CHANNEL, BASIC_CONSUME_OK, &consume_ok);
+
+            let mut replies = Vec::new();
+            for (tag, body) in [
+                (1, "type,client,tx,amount\ndeposit,1,1,10\n"),
+                (2, "type,client,tx,amount\nnot a row\n"),
+            ] {
+                deliver(&mut writer, tag, body);
+
+                let frame = read_frame(&mut reader, DEFAULT_FRAME_MAX).unwrap();
+                let (method, mut fields) = frame.method().unwrap();
+                assert_eq!(fields.u64().unwrap(), tag);
+                replies.push(method);
+            }
+
+            replies
+        });
+
+        let report = env::temp_dir().join(format!("spork-amqp-report-{}", process::id()));
+        let options = SourceOptions {
+            broker: Some(broker),
+            queue: Some("payments".to_owned()),
+            prefetch: 10,
+            report: Some(report.clone()),
+            ..SourceOptions::default()
+        };
+        let read = ReadOptions::default();
+        let processor = Processor::new(0, Policy::from(Preset::SpecCompat));
+
+        // The server hangs up once it has had both replies.
+        let err = source::run(
+            SourceKind::Amqp,
+            &options,
+            &read,
+            processor,
+            None,
+            Rounding::default(),
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "AMQP closed the connection");
+
+        let replies = server.join().unwrap();
+        let accounts = fs::read_to_string(&report);
+        fs::remove_file(&report).unwrap();
+
+        assert_eq!(replies, [BASIC_ACK, BASIC_REJECT]);
+        assert_eq!(
+            accounts.unwrap(),
+            "client,available,held,total,locked\n1,10,0,10,false\n"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Serialize;
+
+use crate::{
+    cli::AnonymizeArgs, ClientId, RawClientId, RawTransactionId, TransactionId, TransactionRecord,
+    TransactionType,
+};
+
+/// A row of the standard columns. Sequence numbers and wallets are dropped, since they are not
+/// needed to reproduce balances and wallet names may identify a client.
+#[derive(Clone, Debug, Serialize)]
+struct AnonymizedRecord {
+    r#type: TransactionType,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Option<Decimal>,
+}
+
+/// Rewrites a transaction file so it can be shared outside production.
+///
+/// Client and transaction IDs are renumbered from 1 in order of first appearance. Every amount
+/// belonging to a client is multiplied by the same random factor between 0.50 and 1.50, so
+/// whether a withdrawal or dispute succeeds is mostly unchanged. Amounts are then rounded down
+/// to four decimal places, as input amounts are given, so a withdrawal is never larger than a
+/// deposit it was no larger than. Several deposits may round down to a ten-thousandth or so less
+/// than a withdrawal of all of them, though.
+pub fn run(args: &AnonymizeArgs) -> Result<(), anyhow::Error> {
+    let seed = args.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| {
+                elapsed.as_secs() ^ u64::from(elapsed.subsec_nanos())
+            })
+    });
+
+    let mut rng = SplitMix64(seed);
+    let mut clients = BTreeMap::new();
+    let mut txs = BTreeMap::new();
+
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(&args.path)?;
+
+    let output: Box<dyn io::Write> = match &args.output {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut csv_writer = csv::Writer::from_writer(output);
+
+    for record_res in csv_reader.deserialize::<TransactionRecord>() {
+        let record = record_res?;
+
+        let next_client = clients.len() + 1;
+        let (client, factor) = match clients.entry(record.client) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => {
+                let client =
+                    ClientId(RawClientId::try_from(next_client).context("too many clients")?);
+                let factor = Decimal::new(50 + i64::from(rng.below(101)), 2);
+                *entry.insert((client, factor))
+            }
+        };
+
+        let next_tx = txs.len() + 1;
+        let tx = match txs.entry(record.tx) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => *entry.insert(TransactionId(
+                RawTransactionId::try_from(next_tx).context("too many transactions")?,
+            )),
+        };
+
+        csv_writer.serialize(AnonymizedRecord {
+            r#type: record.r#type,
+            client,
+            tx,
+            amount: record.amount.map(|amount| {
+                (amount * factor).round_dp_with_strategy(4, RoundingStrategy::ToZero)
+            }),
+        })?;
+    }
+
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+/// A small, seedable generator; this only needs to be unpredictable without the seed, not
+/// cryptographically strong.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u32) -> u32 {
+        u32::try_from(self.next() % u64::from(bound)).unwrap_or(0)
+    }
+}
@@ -0,0 +1,69 @@
+use std::io;
+
+use anyhow::{bail, Context};
+
+use crate::{
+    cli::{BalanceAtArgs, BalancePoint},
+    clients::Clients,
+    input, input_paths, load_initial, output, replay_processor,
+};
+
+/// Replays transaction files up to and including a given row, and prints one client's account as
+/// it was then.
+///
+/// The input is replayed with the same options as processing it, starting from the same saved
+/// state if one is given. Rows are counted in the order they appear in the files, so the point is
+/// the same however the reorder window would have rearranged them.
+pub fn run(args: &BalanceAtArgs) -> Result<(), anyhow::Error> {
+    let replay = &args.replay;
+
+    let paths = input_paths(replay)?;
+
+    let clients = match &replay.clients_file {
+        Some(path) => Some(Clients::load(path)?),
+        None => None,
+    };
+
+    let mut processor = replay_processor(replay, load_initial(replay)?)?;
+    let mut rows = 0;
+    let mut reached = false;
+
+    input::for_each_record(&paths, &replay.read, processor.policy(), |transaction| {
+        if reached {
+            return Ok(());
+        }
+
+        rows += 1;
+        reached = match args.point {
+            BalancePoint::Tx { tx, r#type } => {
+                transaction.tx == tx && r#type.is_none_or(|r#type| transaction.r#type == r#type)
+            }
+            BalancePoint::Row(row) => rows == row,
+        };
+
+        processor.push(transaction)
+    })?;
+
+    if !reached {
+        match args.point {
+            BalancePoint::Tx { tx, .. } => bail!("transaction not found: {tx}"),
+            BalancePoint::Row(row) => bail!("row not found: {row}"),
+        }
+    }
+
+    let engine = processor.finish()?;
+
+    let account = engine
+        .account(args.client)
+        .with_context(|| format!("client not found: {}", args.client))?;
+
+    output::write_accounts(
+        io::stdout().lock(),
+        [(&args.client, account)],
+        replay.rounding,
+        clients.as_ref(),
+        None,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,1257 @@
+use std::{
+    env,
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    aging::AgingBuckets,
+    config::Config,
+    engine::{BalanceLimits, Retention},
+    flags::Heuristics,
+    input::{DecimalSeparator, ReadOptions},
+    output::{AccountReport, Rounding},
+    policy::{ClientLimits, CreditClients, Policy},
+    source::SourceOptions,
+    top::TopBy,
+    ClientId, RawTransactionId, TransactionId, TransactionType,
+};
+
+const DEFAULT_REORDER_WINDOW: usize = 1024;
+const DEFAULT_WRITE_BUFFER: usize = 64 * 1024;
+/// As many decimal places as the `fixed-point` feature can hold.
+const DEFAULT_MAX_SCALE: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Emit {
+    /// One row per account after all input is processed.
+    Final,
+    /// One row every time an account's balances change.
+    Deltas,
+}
+
+impl FromStr for Emit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "final" => Ok(Self::Final),
+            "deltas" => Ok(Self::Deltas),
+            _ => bail!("expected one of: final, deltas"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Applies transaction files and reports the resulting accounts.
+    Process(Box<ProcessArgs>),
+    /// Rewrites a transaction file with its IDs and amounts disguised.
+    Anonymize(AnonymizeArgs),
+    /// Shows accounts from a saved state.
+    Accounts(AccountsArgs),
+    /// Shows a deposit from a saved state.
+    Tx(TxArgs),
+    /// Shows everything that locked an account in a saved state, with the same options as
+    /// `accounts`.
+    Locks(AccountsArgs),
+    /// Combines saved states from sharded runs.
+    Merge(MergeArgs),
+    /// Summarises a transaction file without applying it.
+    Stats(StatsArgs),
+    /// Partitions a transaction file into per-shard files by client.
+    Split(SplitArgs),
+    /// Applies transactions typed in interactively.
+    Repl(ReplArgs),
+    /// Shows every transaction that touched one client, with running balances.
+    Explain(ExplainArgs),
+    /// Shows one client's account as it was just after a given transaction.
+    BalanceAt(BalanceAtArgs),
+    /// Compares a saved state with balances produced elsewhere.
+    Reconcile(ReconcileArgs),
+    /// Checks a transaction file against the input format without applying it.
+    Validate(ValidateArgs),
+}
+
+impl Command {
+    pub fn parse(args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut args = args.skip(1).peekable();
+
+        match args.peek().and_then(|arg| arg.to_str()) {
+            Some("anonymize") => {
+                _ = args.next();
+                Ok(Self::Anonymize(AnonymizeArgs::parse(args)?))
+            }
+            Some("accounts") => {
+                _ = args.next();
+                Ok(Self::Accounts(AccountsArgs::parse(args)?))
+            }
+            Some("tx") => {
+                _ = args.next();
+                Ok(Self::Tx(TxArgs::parse(args)?))
+            }
+            Some("locks") => {
+                _ = args.next();
+                Ok(Self::Locks(AccountsArgs::parse(args)?))
+            }
+            Some("merge") => {
+                _ = args.next();
+                Ok(Self::Merge(MergeArgs::parse(args)?))
+            }
+            Some("stats") => {
+                _ = args.next();
+                Ok(Self::Stats(StatsArgs::parse(args)?))
+            }
+            Some("split") => {
+                _ = args.next();
+                Ok(Self::Split(SplitArgs::parse(args)?))
+            }
+            Some("repl") => {
+                _ = args.next();
+                Ok(Self::Repl(ReplArgs::parse(args)?))
+            }
+            Some("explain") => {
+                _ = args.next();
+                Ok(Self::Explain(ExplainArgs::parse(args)?))
+            }
+            Some("balance-at") => {
+                _ = args.next();
+                Ok(Self::BalanceAt(BalanceAtArgs::parse(args)?))
+            }
+            Some("reconcile") => {
+                _ = args.next();
+                Ok(Self::Reconcile(ReconcileArgs::parse(args)?))
+            }
+            Some("validate") => {
+                _ = args.next();
+                Ok(Self::Validate(ValidateArgs::parse(args)?))
+            }
+            _ => Ok(Self::Process(Box::new(ProcessArgs::parse(args)?))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ProcessArgs {
+    pub paths: Vec<PathBuf>,
+    pub reorder_window: usize,
+    /// How long records may wait on a gap in their client's sequence before it is skipped.
+    pub reorder_timeout: Option<Duration>,
+    pub sharded: bool,
+    pub read: ReadOptions,
+    /// Bytes of output to collect before writing them out.
+    pub write_buffer: usize,
+    pub monitor_interval: Option<Duration>,
+    pub emit: Emit,
+    /// Only report this many of the largest accounts.
+    pub top: Option<usize>,
+    pub top_by: TopBy,
+    pub alert_amount: Option<Decimal>,
+    pub alert_cumulative_amount: Option<Decimal>,
+    pub alerts_path: Option<PathBuf>,
+    pub heuristics: Heuristics,
+    pub flags_path: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    /// A mapping of clients to the shared accounts they transact against.
+    pub joint_accounts: Option<PathBuf>,
+    /// Client details to join into the account report and alerts.
+    pub clients_file: Option<PathBuf>,
+    pub policy: Policy,
+    pub rounding: Rounding,
+    /// A saved state to start from, instead of no accounts.
+    pub state: Option<PathBuf>,
+    /// Save the result back over `state`. This implies `report.only_changed`.
+    pub append: bool,
+    pub report: AccountReport,
+    pub save_state: Option<PathBuf>,
+    pub credit_clients: CreditClients,
+    pub balance_limits: BalanceLimits,
+    pub retention: Retention,
+    /// Interest rate to credit available balances with once all input is processed.
+    pub accrue_interest: Option<Decimal>,
+    /// Where to write each client's net settlement figures.
+    pub settlement: Option<PathBuf>,
+    /// Where to write the sums of every account's balances.
+    pub trial_balance: Option<PathBuf>,
+    /// Where to write each client's dispute and chargeback history.
+    pub risk_report: Option<PathBuf>,
+    /// Where to write every applied transaction as ledger postings.
+    pub ledger: Option<PathBuf>,
+    /// Where to write how each transaction changed the engine.
+    pub diffs: Option<PathBuf>,
+    /// Where to write wallet balances, which are kept apart from the main accounts.
+    pub wallets: Option<PathBuf>,
+    /// Where to write what the run read, wrote, and was set up with.
+    pub run_metadata: Option<PathBuf>,
+    /// Where to write the transactions that refer to deposits that can't be found.
+    pub quarantine: Option<PathBuf>,
+    /// Defer the transactions that refer to deposits that can't be found, and retry them once the
+    /// input ends.
+    pub retry_unmatched: bool,
+    /// Transactions a dispute may stay open for before it lapses and is resolved.
+    pub dispute_expiry: Option<u64>,
+    /// Where to write the disputes that lapse.
+    pub lapsed_disputes: Option<PathBuf>,
+    /// Where to write each client's held funds by how long they have been held.
+    pub held_aging: Option<PathBuf>,
+    /// The ages that split held funds into recent, aging, and old. Records carry no timestamps, so
+    /// ages are counted in transactions, not days.
+    pub aging_buckets: Option<AgingBuckets>,
+    /// Keep reading the input as it grows, rewriting the account report at this path.
+    pub follow: Option<PathBuf>,
+    /// Apply every file that appears in this directory, in place of input files.
+    pub watch: Option<PathBuf>,
+    /// Where to consume transactions from a message broker, in place of input files. Brokers are
+    /// connected to without TLS, and NATS servers that require it are refused. AMQP connections
+    /// have heartbeats turned off.
+    pub source: SourceOptions,
+}
+
+impl ProcessArgs {
+    fn parse(args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let args: Vec<_> = args.collect();
+
+        let mut parsed = Self::from_config(load_config(&args)?);
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--config" | "--preset") => _ = args.next(),
+                Some(
+                    flag @ ("--invalid-amount" | "--disable-type" | "--malformed-row"
+                    | "--unknown-type" | "--control-mismatch" | "--duplicate-id"
+                    | "--over-limit"),
+                ) => parse_policy(&mut parsed.policy, flag, &mut args)?,
+                Some(flag @ ("--reorder-window" | "--reorder-timeout")) => {
+                    parse_reorder_option(&mut parsed, flag, &mut args)?;
+                }
+                Some("--sharded") => parsed.sharded = true,
+                Some("--mmap") => parsed.read.mmap = true,
+                Some("--read-buffer") => {
+                    parsed.read.buffer_capacity = parse_value(&mut args, "--read-buffer")?;
+                }
+                Some("--lenient-amounts") => parsed.read.lenient_amounts = true,
+                Some("--control-trailer") => parsed.read.control_trailer = true,
+                Some("--type-aliases") => {
+                    parsed.read.type_aliases = parse_value(&mut args, "--type-aliases")?;
+                }
+                Some("--decimal-separator") => {
+                    parsed.read.decimal_separator = parse_value(&mut args, "--decimal-separator")?;
+                }
+                Some("--write-buffer") => {
+                    parsed.write_buffer = parse_value(&mut args, "--write-buffer")?;
+                }
+                Some("--monitor-interval") => {
+                    let secs = parse_value(&mut args, "--monitor-interval")?;
+                    parsed.monitor_interval = Some(Duration::from_secs(secs));
+                }
+                Some("--scale") => parsed.rounding.scale = Some(parse_value(&mut args, "--scale")?),
+                Some("--rounding") => parsed.rounding.mode = parse_value(&mut args, "--rounding")?,
+                Some("--normalize-amounts") => parsed.rounding.normalize = true,
+                Some("--state") => parsed.state = Some(parse_value(&mut args, "--state")?),
+                Some("--append") => {
+                    parsed.paths.push(parse_value(&mut args, "--append")?);
+                    parsed.append = true;
+                    parsed.report.only_changed = true;
+                }
+                Some("--only-changed") => parsed.report.only_changed = true,
+                Some("--extended-output") => parsed.report.extended = true,
+                Some("--defer-unmatched" | "--retry-quarantined") => parsed.retry_unmatched = true,
+                Some(
+                    flag @ ("--save-state" | "--settlement" | "--trial-balance" | "--risk-report"
+                    | "--ledger" | "--diffs" | "--wallets" | "--run-metadata"
+                    | "--quarantine" | "--lapsed-disputes" | "--held-aging"),
+                ) => parse_output(&mut parsed, flag, &mut args)?,
+                Some("--accrue-interest") => {
+                    parsed.accrue_interest = Some(parse_value(&mut args, "--accrue-interest")?);
+                }
+                Some(flag @ ("--credit-clients" | "--max-balance" | "--client-max-balance")) => {
+                    parse_balance_option(&mut parsed, flag, &mut args)?;
+                }
+                Some("--forget-chargebacks") => parsed.retention.forget_chargebacks = true,
+                Some("--forget-resolved-after") => {
+                    parsed.retention.forget_resolved_after =
+                        Some(parse_value(&mut args, "--forget-resolved-after")?);
+                }
+                Some(flag @ ("--dispute-expiry" | "--aging-buckets")) => {
+                    parse_dispute_option(&mut parsed, flag, &mut args)?;
+                }
+                Some("--emit") => parsed.emit = parse_value(&mut args, "--emit")?,
+                Some("--top") => parsed.top = Some(parse_value(&mut args, "--top")?),
+                Some("--top-by") => parsed.top_by = parse_value(&mut args, "--top-by")?,
+                Some("--alert-amount") => {
+                    parsed.alert_amount = Some(parse_value(&mut args, "--alert-amount")?);
+                }
+                Some("--alert-cumulative-amount") => {
+                    parsed.alert_cumulative_amount =
+                        Some(parse_value(&mut args, "--alert-cumulative-amount")?);
+                }
+                Some("--alerts") => parsed.alerts_path = Some(parse_value(&mut args, "--alerts")?),
+                Some("--flags") => parsed.flags_path = Some(parse_value(&mut args, "--flags")?),
+                Some(flag) if flag.starts_with("--flag-") => {
+                    parse_heuristic(&mut parsed.heuristics, flag, &mut args)?;
+                }
+                Some("--manifest") => parsed.manifest = Some(parse_value(&mut args, "--manifest")?),
+                Some("--joint-accounts") => {
+                    parsed.joint_accounts = Some(parse_value(&mut args, "--joint-accounts")?);
+                }
+                Some("--clients-file") => {
+                    parsed.clients_file = Some(parse_value(&mut args, "--clients-file")?);
+                }
+                Some("--follow") => parsed.follow = Some(parse_value(&mut args, "--follow")?),
+                Some("--watch") => parsed.watch = Some(parse_value(&mut args, "--watch")?),
+                Some(
+                    flag @ ("--source" | "--broker" | "--broker-user" | "--broker-password"
+                    | "--stream" | "--durable" | "--queue" | "--vhost" | "--prefetch"
+                    | "--report"),
+                ) => parse_source_option(&mut parsed.source, flag, &mut args)?,
+                Some(flag) if flag.starts_with("--") => bail!("unknown option: {flag}"),
+                _ => parsed.paths.push(PathBuf::from(arg)),
+            }
+        }
+
+        parsed.validate()?;
+
+        Ok(parsed)
+    }
+
+    /// Rejects combinations of settings that cannot work together.
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        match (&self.manifest, self.paths.is_empty()) {
+            (None, true) if self.watch.is_none() && self.source.kind.is_none() => {
+                bail!("missing argument: path to transactions");
+            }
+            (Some(_), false) => bail!("input files cannot be given together with --manifest"),
+            _ => (),
+        }
+
+        if self
+            .balance_limits
+            .default
+            .is_some_and(|limit| limit < Decimal::ZERO)
+        {
+            bail!("--max-balance cannot be negative");
+        }
+
+        if self.append && self.state.is_none() {
+            bail!("--append requires --state");
+        }
+
+        if self.report.only_changed && self.state.is_none() {
+            bail!("--only-changed requires --state");
+        }
+
+        if self.sharded && self.state.is_some() {
+            bail!("--state cannot be combined with --sharded");
+        }
+
+        // The extended columns are only kept for the final account report of a single engine.
+        if self.report.extended && (self.sharded || self.continuous() || self.top.is_some()) {
+            bail!(
+                "--extended-output cannot be combined with --sharded, --follow, --watch, \
+                --source, or --top"
+            );
+        }
+
+        if self.sharded && self.emit == Emit::Deltas {
+            bail!("--emit deltas cannot be combined with --sharded");
+        }
+
+        if self.top.is_some() {
+            if self.emit == Emit::Deltas {
+                bail!("--top cannot be combined with --emit deltas");
+            }
+
+            // The top accounts are written as a single processor finishes.
+            if self.sharded || self.continuous() {
+                bail!("--top cannot be combined with --sharded, --follow, --watch, or --source");
+            }
+        }
+
+        if self.sharded && self.alerts_enabled() {
+            bail!("alert thresholds cannot be combined with --sharded");
+        }
+
+        if self.sharded && self.heuristics.enabled() {
+            bail!("flag heuristics cannot be combined with --sharded");
+        }
+
+        if self.sharded && self.settlement.is_some() {
+            bail!("--settlement cannot be combined with --sharded");
+        }
+
+        if self.sharded && self.risk_report.is_some() {
+            bail!("--risk-report cannot be combined with --sharded");
+        }
+
+        if self.sharded && self.ledger.is_some() {
+            bail!("--ledger cannot be combined with --sharded");
+        }
+
+        if self.sharded && self.diffs.is_some() {
+            bail!("--diffs cannot be combined with --sharded");
+        }
+
+        if self.sharded && self.joint_accounts.is_some() {
+            bail!("--joint-accounts cannot be combined with --sharded");
+        }
+
+        if self.sharded && self.wallets.is_some() {
+            bail!("--wallets cannot be combined with --sharded");
+        }
+
+        // Wallet engines aren't saved, so their balances would be lost between runs.
+        if self.wallets.is_some() && (self.state.is_some() || self.save_state.is_some()) {
+            bail!("--wallets cannot be combined with --state or --save-state");
+        }
+
+        // Wallet engines are kept apart from the accounts, so they would escape the limits.
+        if self.wallets.is_some() && self.balance_limits != BalanceLimits::default() {
+            bail!("--max-balance and --client-max-balance cannot be combined with --wallets");
+        }
+
+        if self.sharded && (self.quarantine.is_some() || self.retry_unmatched) {
+            bail!("--quarantine and --defer-unmatched cannot be combined with --sharded");
+        }
+
+        // Each shard would only count its own transactions towards a dispute's age.
+        if self.sharded && self.dispute_expiry.is_some() {
+            bail!("--dispute-expiry cannot be combined with --sharded");
+        }
+
+        if self.lapsed_disputes.is_some() && self.dispute_expiry.is_none() {
+            bail!("--lapsed-disputes requires --dispute-expiry");
+        }
+
+        // Each shard would only count its own transactions towards a dispute's age.
+        if self.sharded && self.held_aging.is_some() {
+            bail!("--held-aging cannot be combined with --sharded");
+        }
+
+        if self.held_aging.is_some() && self.aging_buckets.is_none() {
+            bail!("--held-aging requires --aging-buckets");
+        }
+
+        // Anywhere else, every held record is released by the end of its file anyway.
+        if self.reorder_timeout.is_some() && self.follow.is_none() {
+            bail!("--reorder-timeout requires --follow");
+        }
+
+        self.validate_continuous()
+    }
+
+    /// Rejects settings that don't fit `--follow` or `--watch`, whose input never ends.
+    fn validate_continuous(&self) -> Result<(), anyhow::Error> {
+        if self.follow.is_some() {
+            if self.sharded || self.manifest.is_some() || self.paths.len() != 1 {
+                bail!("--follow takes exactly one input file");
+            }
+
+            if self.save_state.is_some() || self.append {
+                bail!("--save-state and --append cannot be combined with --follow");
+            }
+
+            if self.accrue_interest.is_some() {
+                bail!("--accrue-interest cannot be combined with --follow");
+            }
+
+            if self.trial_balance.is_some() {
+                bail!("--trial-balance cannot be combined with --follow");
+            }
+
+            if self.run_metadata.is_some() {
+                bail!("--run-metadata cannot be combined with --follow");
+            }
+
+            if self.retry_unmatched {
+                bail!("--defer-unmatched cannot be combined with --follow");
+            }
+
+            // A file that is still growing has no last row to check.
+            if self.read.control_trailer {
+                bail!("--control-trailer cannot be combined with --follow");
+            }
+        }
+
+        if self.watch.is_some() {
+            if self.sharded || self.follow.is_some() {
+                bail!("--watch cannot be combined with --sharded or --follow");
+            }
+
+            if self.manifest.is_some() || !self.paths.is_empty() {
+                bail!("input files cannot be given together with --watch");
+            }
+
+            if self.append {
+                bail!("--append cannot be combined with --watch");
+            }
+
+            if self.accrue_interest.is_some() {
+                bail!("--accrue-interest cannot be combined with --watch");
+            }
+
+            if self.trial_balance.is_some() {
+                bail!("--trial-balance cannot be combined with --watch");
+            }
+
+            if self.run_metadata.is_some() {
+                bail!("--run-metadata cannot be combined with --watch");
+            }
+
+            if self.retry_unmatched {
+                bail!("--defer-unmatched cannot be combined with --watch");
+            }
+        }
+
+        self.validate_source()?;
+
+        // A file or message that fails is rolled back in the engine, but what these have already
+        // written about it can't be taken back, and would be written again when it is retried.
+        if (self.watch.is_some() || self.source.kind.is_some())
+            && (self.ledger.is_some()
+                || self.emit == Emit::Deltas
+                || self.diffs.is_some()
+                || self.quarantine.is_some()
+                || self.dispute_expiry.is_some()
+                || self.alerts_enabled()
+                || self.heuristics.enabled())
+        {
+            bail!(
+                "--ledger, --emit deltas, --diffs, --quarantine, --dispute-expiry, alert \
+                thresholds, and flag heuristics cannot be combined with --watch or --source"
+            );
+        }
+
+        // These reports are only written once the input ends.
+        if self.continuous()
+            && (self.settlement.is_some()
+                || self.risk_report.is_some()
+                || self.held_aging.is_some()
+                || self.wallets.is_some())
+        {
+            bail!(
+                "--settlement, --risk-report, --held-aging, and --wallets cannot be combined with \
+                --follow, --watch, or --source"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options that only apply when consuming from a broker.
+    fn validate_source(&self) -> Result<(), anyhow::Error> {
+        let Some(source) = self.source.kind else {
+            return Ok(());
+        };
+
+        if let Some(feature) = source.missing_feature() {
+            bail!("--source {feature} requires a build with the {feature} feature");
+        }
+
+        if self.sharded || self.follow.is_some() || self.watch.is_some() {
+            bail!("--source cannot be combined with --sharded, --follow, or --watch");
+        }
+
+        if self.manifest.is_some() || !self.paths.is_empty() {
+            bail!("input files cannot be given together with --source");
+        }
+
+        if self.source.report.is_none() {
+            bail!("--source requires --report");
+        }
+
+        if self.source.prefetch == 0 {
+            bail!("--prefetch must be at least 1");
+        }
+
+        if self.append {
+            bail!("--append cannot be combined with --source");
+        }
+
+        if self.accrue_interest.is_some() {
+            bail!("--accrue-interest cannot be combined with --source");
+        }
+
+        if self.trial_balance.is_some() {
+            bail!("--trial-balance cannot be combined with --source");
+        }
+
+        if self.run_metadata.is_some() {
+            bail!("--run-metadata cannot be combined with --source");
+        }
+
+        if self.retry_unmatched {
+            bail!("--defer-unmatched cannot be combined with --source");
+        }
+
+        // Control records are only checked when whole files are read.
+        if self.read.control_trailer {
+            bail!("--control-trailer cannot be combined with --source");
+        }
+
+        Ok(())
+    }
+
+    /// Whether the input never ends.
+    fn continuous(&self) -> bool {
+        self.follow.is_some() || self.watch.is_some() || self.source.kind.is_some()
+    }
+
+    fn from_config(config: Config) -> Self {
+        let preset = Policy::from(config.preset.unwrap_or_default());
+
+        Self {
+            paths: Vec::new(),
+            reorder_window: config
+                .input
+                .reorder_window
+                .unwrap_or(DEFAULT_REORDER_WINDOW),
+            reorder_timeout: config.input.reorder_timeout.map(Duration::from_secs),
+            sharded: config.input.sharded.unwrap_or(false),
+            read: ReadOptions {
+                mmap: config.input.mmap.unwrap_or(false),
+                buffer_capacity: config
+                    .input
+                    .read_buffer
+                    .unwrap_or(ReadOptions::default().buffer_capacity),
+                lenient_amounts: config.input.lenient_amounts.unwrap_or(false),
+                decimal_separator: config
+                    .input
+                    .decimal_separator
+                    .unwrap_or(DecimalSeparator::Point),
+                type_aliases: config.input.type_aliases.unwrap_or_default(),
+                control_trailer: config.input.control_trailer.unwrap_or(false),
+            },
+            write_buffer: config.output.write_buffer.unwrap_or(DEFAULT_WRITE_BUFFER),
+            monitor_interval: config.monitor_interval.map(Duration::from_secs),
+            emit: config.output.emit.unwrap_or(Emit::Final),
+            top: config.output.top,
+            top_by: config.output.top_by.unwrap_or_default(),
+            alert_amount: config.alerts.amount,
+            alert_cumulative_amount: config.alerts.cumulative_amount,
+            alerts_path: config.alerts.path,
+            flags_path: config.flags.path.clone(),
+            heuristics: Heuristics::from(config.flags),
+            manifest: config.input.manifest,
+            joint_accounts: config.input.joint_accounts,
+            clients_file: config.input.clients_file,
+            policy: Policy {
+                invalid_amount: config
+                    .policy
+                    .invalid_amount
+                    .unwrap_or(preset.invalid_amount),
+                malformed_row: config.policy.malformed_row.unwrap_or(preset.malformed_row),
+                unknown_type: config.policy.unknown_type.unwrap_or(preset.unknown_type),
+                control_mismatch: config
+                    .policy
+                    .control_mismatch
+                    .unwrap_or(preset.control_mismatch),
+                duplicate_id: config.policy.duplicate_id.unwrap_or(preset.duplicate_id),
+                over_limit: config.policy.over_limit.unwrap_or(preset.over_limit),
+                disabled_types: config
+                    .policy
+                    .disabled_types
+                    .unwrap_or(preset.disabled_types),
+            },
+            rounding: Rounding {
+                scale: config.output.scale,
+                mode: config.output.rounding.unwrap_or_default(),
+                normalize: config.output.normalize_amounts.unwrap_or(false),
+            },
+            state: config.input.state,
+            append: false,
+            report: AccountReport {
+                only_changed: config.output.only_changed.unwrap_or(false),
+                extended: config.output.extended_output.unwrap_or(false),
+            },
+            save_state: config.output.state,
+            credit_clients: config.policy.credit_clients.unwrap_or_default(),
+            balance_limits: BalanceLimits {
+                default: config.policy.max_balance,
+                clients: config.policy.client_max_balance.unwrap_or_default().0,
+            },
+            retention: Retention {
+                forget_chargebacks: config.policy.forget_chargebacks.unwrap_or(false),
+                forget_resolved_after: config.policy.forget_resolved_after,
+            },
+            accrue_interest: config.accrue_interest,
+            settlement: config.output.settlement,
+            trial_balance: config.output.trial_balance,
+            risk_report: config.output.risk_report,
+            ledger: config.output.ledger,
+            diffs: config.output.diffs,
+            wallets: config.output.wallets,
+            run_metadata: config.output.run_metadata,
+            quarantine: config.output.quarantine,
+            retry_unmatched: config.policy.retry_unmatched.unwrap_or(false),
+            dispute_expiry: config.policy.dispute_expiry,
+            lapsed_disputes: config.output.lapsed_disputes,
+            held_aging: config.output.held_aging,
+            aging_buckets: config.output.aging_buckets,
+            follow: None,
+            watch: None,
+            source: SourceOptions::from(config.source),
+        }
+    }
+
+    pub fn alerts_enabled(&self) -> bool {
+        self.alert_amount.is_some() || self.alert_cumulative_amount.is_some()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AnonymizeArgs {
+    pub path: PathBuf,
+    pub output: Option<PathBuf>,
+    pub seed: Option<u64>,
+}
+
+impl AnonymizeArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut path = None;
+        let mut output = None;
+        let mut seed = None;
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("-o" | "--output") => output = Some(parse_value(&mut args, "--output")?),
+                Some("--seed") => seed = Some(parse_value(&mut args, "--seed")?),
+                Some(flag) if flag.starts_with('-') => bail!("unknown option: {flag}"),
+                _ if path.is_none() => path = Some(PathBuf::from(arg)),
+                _ => bail!("unexpected argument: {}", arg.to_string_lossy()),
+            }
+        }
+
+        Ok(Self {
+            path: path.context("missing argument: path to transactions")?,
+            output,
+            seed,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AccountsArgs {
+    pub state: PathBuf,
+    pub client: Option<ClientId>,
+}
+
+impl AccountsArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut state = None;
+        let mut client = None;
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--state") => state = Some(parse_value(&mut args, "--state")?),
+                Some("--client") => client = Some(ClientId(parse_value(&mut args, "--client")?)),
+                _ => bail!("unexpected argument: {}", arg.to_string_lossy()),
+            }
+        }
+
+        Ok(Self {
+            state: state.context("missing option: --state")?,
+            client,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TxArgs {
+    pub state: PathBuf,
+    pub tx: TransactionId,
+}
+
+impl TxArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut state = None;
+        let mut tx = None;
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--state") => state = Some(parse_value(&mut args, "--state")?),
+                Some(flag) if flag.starts_with('-') => bail!("unknown option: {flag}"),
+                Some(value) if tx.is_none() => {
+                    let id = value
+                        .parse::<RawTransactionId>()
+                        .with_context(|| format!("invalid transaction ID: {value}"))?;
+                    tx = Some(TransactionId(id));
+                }
+                _ => bail!("unexpected argument: {}", arg.to_string_lossy()),
+            }
+        }
+
+        Ok(Self {
+            state: state.context("missing option: --state")?,
+            tx: tx.context("missing argument: transaction ID")?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MergeArgs {
+    pub states: Vec<PathBuf>,
+    pub output: PathBuf,
+}
+
+impl MergeArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut states = Vec::new();
+        let mut output = None;
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("-o" | "--output") => output = Some(parse_value(&mut args, "--output")?),
+                Some(flag) if flag.starts_with('-') => bail!("unknown option: {flag}"),
+                _ => states.push(PathBuf::from(arg)),
+            }
+        }
+
+        if states.len() < 2 {
+            bail!("missing argument: at least two states to merge");
+        }
+
+        Ok(Self {
+            states,
+            output: output.context("missing option: --output")?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StatsArgs {
+    pub path: PathBuf,
+}
+
+impl StatsArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let path = args
+            .next()
+            .context("missing argument: path to transactions")?;
+
+        if let Some(arg) = args.next() {
+            bail!("unexpected argument: {}", arg.to_string_lossy());
+        }
+
+        Ok(Self {
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SplitArgs {
+    pub path: PathBuf,
+    pub shards: usize,
+    pub output_dir: Option<PathBuf>,
+}
+
+impl SplitArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut path = None;
+        let mut shards = None;
+        let mut output_dir = None;
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--shards") => shards = Some(parse_value(&mut args, "--shards")?),
+                Some("--output-dir") => output_dir = Some(parse_value(&mut args, "--output-dir")?),
+                Some(flag) if flag.starts_with('-') => bail!("unknown option: {flag}"),
+                _ if path.is_none() => path = Some(PathBuf::from(arg)),
+                _ => bail!("unexpected argument: {}", arg.to_string_lossy()),
+            }
+        }
+
+        let shards = shards.context("missing option: --shards")?;
+        if shards == 0 {
+            bail!("invalid value for --shards: 0");
+        }
+
+        Ok(Self {
+            path: path.context("missing argument: path to transactions")?,
+            shards,
+            output_dir,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ValidateArgs {
+    pub path: PathBuf,
+    /// Decimal places allowed in amounts.
+    pub max_scale: u32,
+}
+
+impl ValidateArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut path = None;
+        let mut max_scale = DEFAULT_MAX_SCALE;
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--max-scale") => max_scale = parse_value(&mut args, "--max-scale")?,
+                Some(flag) if flag.starts_with('-') => bail!("unknown option: {flag}"),
+                _ if path.is_none() => path = Some(PathBuf::from(arg)),
+                _ => bail!("unexpected argument: {}", arg.to_string_lossy()),
+            }
+        }
+
+        Ok(Self {
+            path: path.context("missing argument: path to transactions")?,
+            max_scale,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplArgs {
+    /// A saved state to start from, instead of no accounts.
+    pub state: Option<PathBuf>,
+}
+
+impl ReplArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut state = None;
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--state") => state = Some(parse_value(&mut args, "--state")?),
+                _ => bail!("unexpected argument: {}", arg.to_string_lossy()),
+            }
+        }
+
+        Ok(Self { state })
+    }
+}
+
+/// Where `explain` finds a client's transactions.
+#[derive(Clone, Debug)]
+pub enum ExplainSource {
+    /// Input to replay, which takes the same options as processing it.
+    Replay(Box<ProcessArgs>),
+    /// A diff log recorded by `--diffs` to read instead of replaying transactions.
+    Diffs(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct ExplainArgs {
+    pub client: ClientId,
+    pub source: ExplainSource,
+}
+
+impl ExplainArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut client = None;
+        let mut diffs = None;
+        let mut rest = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--client") => client = Some(ClientId(parse_value(&mut args, "--client")?)),
+                Some("--diffs") => diffs = Some(parse_value(&mut args, "--diffs")?),
+                _ => rest.push(arg),
+            }
+        }
+
+        let source = match diffs {
+            None => ExplainSource::Replay(Box::new(ProcessArgs::parse(rest.into_iter())?)),
+            Some(path) if rest.is_empty() => ExplainSource::Diffs(path),
+            Some(_) => bail!("input files and other options cannot be given together with --diffs"),
+        };
+
+        Ok(Self {
+            client: client.context("missing option: --client")?,
+            source,
+        })
+    }
+}
+
+/// Which row of the input `balance-at` stops after.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BalancePoint {
+    /// The first row with this ID, and this type if one is given, since disputes, resolves and
+    /// chargebacks share the ID of the deposit they refer to.
+    Tx {
+        tx: TransactionId,
+        r#type: Option<TransactionType>,
+    },
+    /// The row with this number, counting from 1 across every file. Rows that are skipped don't
+    /// count.
+    Row(u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct BalanceAtArgs {
+    pub client: ClientId,
+    pub point: BalancePoint,
+    /// How to replay the input, which takes the same options as processing it.
+    pub replay: Box<ProcessArgs>,
+}
+
+impl BalanceAtArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut client = None;
+        let mut tx = None;
+        let mut r#type = None;
+        let mut row = None;
+        let mut rest = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--client") => client = Some(ClientId(parse_value(&mut args, "--client")?)),
+                Some("--tx") => tx = Some(TransactionId(parse_value(&mut args, "--tx")?)),
+                Some("--type") => r#type = Some(parse_value(&mut args, "--type")?),
+                Some("--row") => row = Some(parse_value(&mut args, "--row")?),
+                _ => rest.push(arg),
+            }
+        }
+
+        let point = match (tx, r#type, row) {
+            (Some(tx), r#type, None) => BalancePoint::Tx { tx, r#type },
+            (None, None, Some(row)) => BalancePoint::Row(row),
+            (None, Some(_), _) => bail!("--type requires --tx"),
+            (Some(_), _, Some(_)) => bail!("--tx and --row cannot be given together"),
+            (None, None, None) => bail!("missing option: --tx or --row"),
+        };
+
+        Ok(Self {
+            client: client.context("missing option: --client")?,
+            point,
+            replay: Box::new(ProcessArgs::parse(rest.into_iter())?),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReconcileArgs {
+    pub state: PathBuf,
+    pub expected: PathBuf,
+    /// How far apart amounts may be and still match.
+    pub tolerance: Decimal,
+}
+
+impl ReconcileArgs {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, anyhow::Error> {
+        let mut state = None;
+        let mut expected = None;
+        let mut tolerance = Decimal::ZERO;
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--state") => state = Some(parse_value(&mut args, "--state")?),
+                Some("--expected") => expected = Some(parse_value(&mut args, "--expected")?),
+                Some("--tolerance") => tolerance = parse_value(&mut args, "--tolerance")?,
+                _ => bail!("unexpected argument: {}", arg.to_string_lossy()),
+            }
+        }
+
+        Ok(Self {
+            state: state.context("missing option: --state")?,
+            expected: expected.context("missing option: --expected")?,
+            tolerance,
+        })
+    }
+}
+
+/// Parses one of the options naming a file to write a report to.
+fn parse_output(
+    parsed: &mut ProcessArgs,
+    flag: &str,
+    args: &mut impl Iterator<Item = OsString>,
+) -> Result<(), anyhow::Error> {
+    let path = Some(parse_value(args, flag)?);
+
+    match flag {
+        "--save-state" => parsed.save_state = path,
+        "--settlement" => parsed.settlement = path,
+        "--trial-balance" => parsed.trial_balance = path,
+        "--risk-report" => parsed.risk_report = path,
+        "--ledger" => parsed.ledger = path,
+        "--diffs" => parsed.diffs = path,
+        "--wallets" => parsed.wallets = path,
+        "--run-metadata" => parsed.run_metadata = path,
+        "--quarantine" => parsed.quarantine = path,
+        "--lapsed-disputes" => parsed.lapsed_disputes = path,
+        "--held-aging" => parsed.held_aging = path,
+        _ => bail!("unknown option: {flag}"),
+    }
+
+    Ok(())
+}
+
+/// Parses one of the options that set how long disputes stay open, and how their age is reported.
+fn parse_dispute_option(
+    parsed: &mut ProcessArgs,
+    flag: &str,
+    args: &mut impl Iterator<Item = OsString>,
+) -> Result<(), anyhow::Error> {
+    match flag {
+        "--dispute-expiry" => parsed.dispute_expiry = Some(parse_value(args, flag)?),
+        "--aging-buckets" => parsed.aging_buckets = Some(parse_value(args, flag)?),
+        _ => bail!("unknown option: {flag}"),
+    }
+
+    Ok(())
+}
+
+/// Parses one of the options that set how far an account's balance may go.
+fn parse_balance_option(
+    parsed: &mut ProcessArgs,
+    flag: &str,
+    args: &mut impl Iterator<Item = OsString>,
+) -> Result<(), anyhow::Error> {
+    match flag {
+        "--credit-clients" => parsed.credit_clients = parse_value(args, flag)?,
+        "--max-balance" => parsed.balance_limits.default = Some(parse_value(args, flag)?),
+        "--client-max-balance" => {
+            let limits: ClientLimits = parse_value(args, flag)?;
+            parsed.balance_limits.clients = limits.0;
+        }
+        _ => bail!("unknown option: {flag}"),
+    }
+
+    Ok(())
+}
+
+/// Parses one of the options that override a single policy.
+fn parse_policy(
+    policy: &mut Policy,
+    flag: &str,
+    args: &mut impl Iterator<Item = OsString>,
+) -> Result<(), anyhow::Error> {
+    match flag {
+        "--invalid-amount" => policy.invalid_amount = parse_value(args, flag)?,
+        "--disable-type" => policy.disabled_types.insert(parse_value(args, flag)?),
+        "--malformed-row" => policy.malformed_row = parse_value(args, flag)?,
+        "--unknown-type" => policy.unknown_type = parse_value(args, flag)?,
+        "--control-mismatch" => policy.control_mismatch = parse_value(args, flag)?,
+        "--duplicate-id" => policy.duplicate_id = parse_value(args, flag)?,
+        "--over-limit" => policy.over_limit = parse_value(args, flag)?,
+        _ => bail!("unknown option: {flag}"),
+    }
+
+    Ok(())
+}
+
+/// Parses one of the options that set how records are put back in sequence.
+fn parse_reorder_option(
+    parsed: &mut ProcessArgs,
+    flag: &str,
+    args: &mut impl Iterator<Item = OsString>,
+) -> Result<(), anyhow::Error> {
+    match flag {
+        "--reorder-window" => parsed.reorder_window = parse_value(args, flag)?,
+        "--reorder-timeout" => {
+            parsed.reorder_timeout = Some(Duration::from_secs(parse_value(args, flag)?));
+        }
+        _ => bail!("unknown option: {flag}"),
+    }
+
+    Ok(())
+}
+
+/// Parses one of the options that say where and how to consume from a broker.
+fn parse_source_option(
+    options: &mut SourceOptions,
+    flag: &str,
+    args: &mut impl Iterator<Item = OsString>,
+) -> Result<(), anyhow::Error> {
+    match flag {
+        "--source" => options.kind = Some(parse_value(args, flag)?),
+        "--broker" => options.broker = Some(parse_value(args, flag)?),
+        "--broker-user" => options.user = Some(parse_value(args, flag)?),
+        "--broker-password" => options.password = Some(parse_value(args, flag)?),
+        "--stream" => options.stream = Some(parse_value(args, flag)?),
+        "--durable" => options.durable = Some(parse_value(args, flag)?),
+        "--queue" => options.queue = Some(parse_value(args, flag)?),
+        "--vhost" => options.vhost = Some(parse_value(args, flag)?),
+        "--prefetch" => options.prefetch = parse_value(args, flag)?,
+        "--report" => options.report = Some(parse_value(args, flag)?),
+        _ => bail!("unknown option: {flag}"),
+    }
+
+    Ok(())
+}
+
+/// Parses one of the `--flag-*` options that turn on a heuristic.
+fn parse_heuristic(
+    heuristics: &mut Heuristics,
+    flag: &str,
+    args: &mut impl Iterator<Item = OsString>,
+) -> Result<(), anyhow::Error> {
+    match flag {
+        "--flag-deposit-withdrawn" => heuristics.deposit_withdrawn = true,
+        "--flag-rapid-dispute" => {
+            heuristics.rapid_dispute = Some(parse_value(args, "--flag-rapid-dispute")?);
+        }
+        "--flag-small-deposit" => {
+            heuristics.small_deposit = Some(parse_value(args, "--flag-small-deposit")?);
+        }
+        "--flag-small-deposits" => {
+            heuristics.small_deposits = parse_value(args, "--flag-small-deposits")?;
+        }
+        "--flag-large-withdrawal" => {
+            heuristics.large_withdrawal = Some(parse_value(args, "--flag-large-withdrawal")?);
+        }
+        "--flag-lock" => heuristics.lock = true,
+        _ => bail!("unknown option: {flag}"),
+    }
+
+    Ok(())
+}
+
+/// Loads the config file, environment and preset that the rest of the flags override.
+fn load_config(args: &[OsString]) -> Result<Config, anyhow::Error> {
+    // The config file provides the defaults that the rest of the flags override, so it has to be
+    // found first.
+    let config_path = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => Some(
+            args.get(i + 1)
+                .context("missing value for --config")?
+                .into(),
+        ),
+        None => env::var_os("SPORK_CONFIG"),
+    };
+
+    let mut config = match config_path {
+        Some(path) => Config::load(Path::new(&path))?,
+        None => Config::default(),
+    };
+
+    config.apply_env()?;
+
+    // Likewise, individual policies override the preset wherever they are set.
+    if let Some(i) = args.iter().position(|arg| arg == "--preset") {
+        let mut rest = args[i + 1..].iter().cloned();
+        config.preset = Some(parse_value(&mut rest, "--preset")?);
+    }
+
+    Ok(config)
+}
+
+fn parse_value<T>(args: &mut impl Iterator<Item = OsString>, flag: &str) -> Result<T, anyhow::Error>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    let value = args
+        .next()
+        .with_context(|| format!("missing value for {flag}"))?;
+    let value = value
+        .to_str()
+        .with_context(|| format!("invalid value for {flag}"))?;
+    value
+        .parse::<T>()
+        // Not every parse error implements `std::error::Error`; `rust_decimal`'s doesn't without
+        // its `std` feature.
+        .map_err(|err| anyhow!("{err:#}"))
+        .with_context(|| format!("invalid value for {flag}: {value}"))
+}
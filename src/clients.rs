@@ -0,0 +1,64 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::ClientId;
+
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub name: String,
+    pub tier: String,
+    pub email: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ClientInfoRecord {
+    client: ClientId,
+    name: String,
+    tier: String,
+    email: String,
+}
+
+/// Reference details for clients, joined into reports so they can be read without a separate
+/// lookup.
+#[derive(Debug, Default)]
+pub struct Clients(BTreeMap<ClientId, ClientInfo>);
+
+impl Clients {
+    /// Reads a CSV file with `client`, `name`, `tier`, and `email` columns.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let load = || -> Result<Self, anyhow::Error> {
+            let mut clients = BTreeMap::new();
+
+            for record_res in csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_path(path)?
+                .deserialize()
+            {
+                let record: ClientInfoRecord = record_res?;
+                let info = ClientInfo {
+                    name: record.name,
+                    tier: record.tier,
+                    email: record.email,
+                };
+                _ = clients.insert(record.client, info);
+            }
+
+            Ok(Self(clients))
+        };
+
+        load().with_context(|| format!("failed to load clients from {}", path.display()))
+    }
+
+    /// The details for `client`, or blank details if it is not listed.
+    pub fn get(&self, client: ClientId) -> &ClientInfo {
+        static UNKNOWN: ClientInfo = ClientInfo {
+            name: String::new(),
+            tier: String::new(),
+            email: String::new(),
+        };
+
+        self.0.get(&client).unwrap_or(&UNKNOWN)
+    }
+}
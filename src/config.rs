@@ -0,0 +1,333 @@
+use std::{env, fmt, fs, path::Path, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    aging::AgingBuckets,
+    cli::Emit,
+    input::{DecimalSeparator, TypeAliases},
+    output::RoundingMode,
+    policy::{
+        AmountPolicy, ClientLimits, ControlPolicy, CreditClients, DuplicatePolicy, LimitPolicy,
+        Preset, RowPolicy, TypeSet,
+    },
+    source::SourceKind,
+    top::TopBy,
+};
+
+/// Settings for processing that can be kept in a TOML file rather than given as flags.
+///
+/// Every setting is optional; anything left out falls back to the command line default. Each
+/// setting can also be given as a `SPORK_*` environment variable, which overrides the file, and
+/// any flag given on the command line overrides both.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// The starting point for every `[policy]` setting.
+    pub preset: Option<Preset>,
+    pub policy: PolicyConfig,
+    pub input: InputConfig,
+    pub output: OutputConfig,
+    pub alerts: AlertConfig,
+    pub flags: FlagConfig,
+    pub source: SourceConfig,
+    /// Seconds between progress reports.
+    pub monitor_interval: Option<u64>,
+    /// Interest rate to credit available balances with at the end of the run.
+    pub accrue_interest: Option<Decimal>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PolicyConfig {
+    pub invalid_amount: Option<AmountPolicy>,
+    pub malformed_row: Option<RowPolicy>,
+    pub unknown_type: Option<RowPolicy>,
+    pub control_mismatch: Option<ControlPolicy>,
+    pub duplicate_id: Option<DuplicatePolicy>,
+    pub over_limit: Option<LimitPolicy>,
+    pub disabled_types: Option<TypeSet>,
+    pub credit_clients: Option<CreditClients>,
+    /// The most any account may hold, unless it has a limit of its own.
+    pub max_balance: Option<Decimal>,
+    /// Limits for particular clients, as `client=limit` pairs.
+    pub client_max_balance: Option<ClientLimits>,
+    pub forget_chargebacks: Option<bool>,
+    /// Transactions to wait before forgetting a resolved deposit.
+    pub forget_resolved_after: Option<u64>,
+    /// Defer transactions whose deposits can't be found, and retry them once the input ends.
+    pub retry_unmatched: Option<bool>,
+    /// Transactions a dispute may stay open for before it lapses and is resolved.
+    pub dispute_expiry: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct InputConfig {
+    pub reorder_window: Option<usize>,
+    /// Seconds records may wait on a gap in their client's sequence.
+    pub reorder_timeout: Option<u64>,
+    pub sharded: Option<bool>,
+    pub mmap: Option<bool>,
+    /// Bytes to read from each input file at a time.
+    pub read_buffer: Option<usize>,
+    /// Accept amounts like `$1,234.56` or `(12.50)`.
+    pub lenient_amounts: Option<bool>,
+    pub decimal_separator: Option<DecimalSeparator>,
+    /// Other names for transaction types, such as `credit = "deposit"`.
+    pub type_aliases: Option<TypeAliases>,
+    /// Expect each file to end with a control record of its row count and amount sum.
+    pub control_trailer: Option<bool>,
+    /// A saved state to start from.
+    pub state: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    pub joint_accounts: Option<PathBuf>,
+    pub clients_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OutputConfig {
+    pub emit: Option<Emit>,
+    /// Decimal places to round written amounts to.
+    pub scale: Option<u32>,
+    pub rounding: Option<RoundingMode>,
+    /// Write every amount with the same number of decimal places.
+    pub normalize_amounts: Option<bool>,
+    /// Only report the accounts that changed since the saved state the run started from.
+    pub only_changed: Option<bool>,
+    /// Add each account's open disputes, deposit and withdrawal totals, chargebacks, and last
+    /// transaction to the account report.
+    pub extended_output: Option<bool>,
+    /// Bytes of output to collect before writing them out.
+    pub write_buffer: Option<usize>,
+    /// Where to save the engine state after processing.
+    pub state: Option<PathBuf>,
+    /// Where to write each client's net settlement figures.
+    pub settlement: Option<PathBuf>,
+    /// Only report this many of the largest accounts.
+    pub top: Option<usize>,
+    pub top_by: Option<TopBy>,
+    /// Where to write the sums of every account's balances.
+    pub trial_balance: Option<PathBuf>,
+    /// Where to write each client's dispute and chargeback history.
+    pub risk_report: Option<PathBuf>,
+    /// Where to write every applied transaction as ledger postings.
+    pub ledger: Option<PathBuf>,
+    /// Where to write how each transaction changed the engine.
+    pub diffs: Option<PathBuf>,
+    /// Where to write wallet balances.
+    pub wallets: Option<PathBuf>,
+    /// Where to write the run's inputs, outputs, and settings digest.
+    pub run_metadata: Option<PathBuf>,
+    /// Where to write transactions that refer to deposits that can't be found.
+    pub quarantine: Option<PathBuf>,
+    /// Where to write disputes that lapse.
+    pub lapsed_disputes: Option<PathBuf>,
+    /// Where to write held funds by how long they have been held.
+    pub held_aging: Option<PathBuf>,
+    /// The ages that split held funds into recent, aging, and old, as `aging,old`. Records carry
+    /// no timestamps, so ages are counted in transactions, not days.
+    pub aging_buckets: Option<AgingBuckets>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AlertConfig {
+    pub amount: Option<Decimal>,
+    pub cumulative_amount: Option<Decimal>,
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FlagConfig {
+    pub deposit_withdrawn: Option<bool>,
+    /// Transactions after a deposit within which a dispute of it is flagged.
+    pub rapid_dispute: Option<u64>,
+    pub small_deposit: Option<Decimal>,
+    pub small_deposits: Option<usize>,
+    pub large_withdrawal: Option<Decimal>,
+    /// Lock the accounts of flagged clients.
+    pub lock: Option<bool>,
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SourceConfig {
+    /// The kind of message broker to consume transactions from, in place of input files. Brokers
+    /// are connected to without TLS, and NATS servers that require it are refused. AMQP
+    /// connections have heartbeats turned off.
+    pub kind: Option<SourceKind>,
+    /// The broker's address, as `host:port`.
+    pub broker: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub stream: Option<String>,
+    pub durable: Option<String>,
+    pub queue: Option<String>,
+    pub vhost: Option<String>,
+    /// How many messages to have delivered ahead of the one being applied.
+    pub prefetch: Option<u16>,
+    /// Where to rewrite the account report after every message.
+    pub report: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+
+        toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Overrides settings from environment variables named after each setting's section and key,
+    /// e.g. `SPORK_ALERTS_CUMULATIVE_AMOUNT` for `cumulative-amount` under `[alerts]`.
+    pub fn apply_env(&mut self) -> Result<(), anyhow::Error> {
+        env_var("SPORK_PRESET", &mut self.preset)?;
+        self.policy.apply_env()?;
+        env_var("SPORK_INPUT_REORDER_WINDOW", &mut self.input.reorder_window)?;
+        env_var(
+            "SPORK_INPUT_REORDER_TIMEOUT",
+            &mut self.input.reorder_timeout,
+        )?;
+        env_var("SPORK_INPUT_SHARDED", &mut self.input.sharded)?;
+        env_var("SPORK_INPUT_MMAP", &mut self.input.mmap)?;
+        env_var("SPORK_INPUT_READ_BUFFER", &mut self.input.read_buffer)?;
+        env_var(
+            "SPORK_INPUT_LENIENT_AMOUNTS",
+            &mut self.input.lenient_amounts,
+        )?;
+        env_var(
+            "SPORK_INPUT_DECIMAL_SEPARATOR",
+            &mut self.input.decimal_separator,
+        )?;
+        env_var("SPORK_INPUT_TYPE_ALIASES", &mut self.input.type_aliases)?;
+        env_var(
+            "SPORK_INPUT_CONTROL_TRAILER",
+            &mut self.input.control_trailer,
+        )?;
+        env_var("SPORK_INPUT_STATE", &mut self.input.state)?;
+        env_var("SPORK_INPUT_MANIFEST", &mut self.input.manifest)?;
+        env_var("SPORK_INPUT_JOINT_ACCOUNTS", &mut self.input.joint_accounts)?;
+        env_var("SPORK_INPUT_CLIENTS_FILE", &mut self.input.clients_file)?;
+        env_var("SPORK_OUTPUT_EMIT", &mut self.output.emit)?;
+        env_var("SPORK_OUTPUT_SCALE", &mut self.output.scale)?;
+        env_var("SPORK_OUTPUT_ROUNDING", &mut self.output.rounding)?;
+        env_var(
+            "SPORK_OUTPUT_NORMALIZE_AMOUNTS",
+            &mut self.output.normalize_amounts,
+        )?;
+        env_var("SPORK_OUTPUT_ONLY_CHANGED", &mut self.output.only_changed)?;
+        env_var(
+            "SPORK_OUTPUT_EXTENDED_OUTPUT",
+            &mut self.output.extended_output,
+        )?;
+        env_var("SPORK_OUTPUT_WRITE_BUFFER", &mut self.output.write_buffer)?;
+        env_var("SPORK_OUTPUT_STATE", &mut self.output.state)?;
+        env_var("SPORK_OUTPUT_SETTLEMENT", &mut self.output.settlement)?;
+        env_var("SPORK_OUTPUT_TOP", &mut self.output.top)?;
+        env_var("SPORK_OUTPUT_TOP_BY", &mut self.output.top_by)?;
+        env_var("SPORK_OUTPUT_TRIAL_BALANCE", &mut self.output.trial_balance)?;
+        env_var("SPORK_OUTPUT_RISK_REPORT", &mut self.output.risk_report)?;
+        env_var("SPORK_OUTPUT_LEDGER", &mut self.output.ledger)?;
+        env_var("SPORK_OUTPUT_DIFFS", &mut self.output.diffs)?;
+        env_var("SPORK_OUTPUT_WALLETS", &mut self.output.wallets)?;
+        env_var("SPORK_OUTPUT_RUN_METADATA", &mut self.output.run_metadata)?;
+        env_var("SPORK_OUTPUT_QUARANTINE", &mut self.output.quarantine)?;
+        env_var(
+            "SPORK_OUTPUT_LAPSED_DISPUTES",
+            &mut self.output.lapsed_disputes,
+        )?;
+        env_var("SPORK_OUTPUT_HELD_AGING", &mut self.output.held_aging)?;
+        env_var("SPORK_OUTPUT_AGING_BUCKETS", &mut self.output.aging_buckets)?;
+        env_var("SPORK_ALERTS_AMOUNT", &mut self.alerts.amount)?;
+        env_var(
+            "SPORK_ALERTS_CUMULATIVE_AMOUNT",
+            &mut self.alerts.cumulative_amount,
+        )?;
+        env_var("SPORK_ALERTS_PATH", &mut self.alerts.path)?;
+        env_var(
+            "SPORK_FLAGS_DEPOSIT_WITHDRAWN",
+            &mut self.flags.deposit_withdrawn,
+        )?;
+        env_var("SPORK_FLAGS_RAPID_DISPUTE", &mut self.flags.rapid_dispute)?;
+        env_var("SPORK_FLAGS_SMALL_DEPOSIT", &mut self.flags.small_deposit)?;
+        env_var("SPORK_FLAGS_SMALL_DEPOSITS", &mut self.flags.small_deposits)?;
+        env_var(
+            "SPORK_FLAGS_LARGE_WITHDRAWAL",
+            &mut self.flags.large_withdrawal,
+        )?;
+        env_var("SPORK_FLAGS_LOCK", &mut self.flags.lock)?;
+        env_var("SPORK_FLAGS_PATH", &mut self.flags.path)?;
+        env_var("SPORK_SOURCE_KIND", &mut self.source.kind)?;
+        env_var("SPORK_SOURCE_BROKER", &mut self.source.broker)?;
+        env_var("SPORK_SOURCE_USER", &mut self.source.user)?;
+        env_var("SPORK_SOURCE_PASSWORD", &mut self.source.password)?;
+        env_var("SPORK_SOURCE_STREAM", &mut self.source.stream)?;
+        env_var("SPORK_SOURCE_DURABLE", &mut self.source.durable)?;
+        env_var("SPORK_SOURCE_QUEUE", &mut self.source.queue)?;
+        env_var("SPORK_SOURCE_VHOST", &mut self.source.vhost)?;
+        env_var("SPORK_SOURCE_PREFETCH", &mut self.source.prefetch)?;
+        env_var("SPORK_SOURCE_REPORT", &mut self.source.report)?;
+        env_var("SPORK_MONITOR_INTERVAL", &mut self.monitor_interval)?;
+        env_var("SPORK_ACCRUE_INTEREST", &mut self.accrue_interest)?;
+
+        Ok(())
+    }
+}
+
+impl PolicyConfig {
+    /// Overrides settings from the `SPORK_POLICY_*` environment variables.
+    fn apply_env(&mut self) -> Result<(), anyhow::Error> {
+        env_var("SPORK_POLICY_INVALID_AMOUNT", &mut self.invalid_amount)?;
+        env_var("SPORK_POLICY_MALFORMED_ROW", &mut self.malformed_row)?;
+        env_var("SPORK_POLICY_UNKNOWN_TYPE", &mut self.unknown_type)?;
+        env_var("SPORK_POLICY_CONTROL_MISMATCH", &mut self.control_mismatch)?;
+        env_var("SPORK_POLICY_DUPLICATE_ID", &mut self.duplicate_id)?;
+        env_var("SPORK_POLICY_OVER_LIMIT", &mut self.over_limit)?;
+        env_var("SPORK_POLICY_DISABLED_TYPES", &mut self.disabled_types)?;
+        env_var("SPORK_POLICY_CREDIT_CLIENTS", &mut self.credit_clients)?;
+        env_var("SPORK_POLICY_MAX_BALANCE", &mut self.max_balance)?;
+        env_var(
+            "SPORK_POLICY_CLIENT_MAX_BALANCE",
+            &mut self.client_max_balance,
+        )?;
+        env_var(
+            "SPORK_POLICY_FORGET_CHARGEBACKS",
+            &mut self.forget_chargebacks,
+        )?;
+        env_var(
+            "SPORK_POLICY_FORGET_RESOLVED_AFTER",
+            &mut self.forget_resolved_after,
+        )?;
+        env_var("SPORK_POLICY_RETRY_UNMATCHED", &mut self.retry_unmatched)?;
+        env_var("SPORK_POLICY_DISPUTE_EXPIRY", &mut self.dispute_expiry)?;
+
+        Ok(())
+    }
+}
+
+fn env_var<T>(name: &str, setting: &mut Option<T>) -> Result<(), anyhow::Error>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = match env::var(name) {
+        Ok(value) => value,
+        Err(env::VarError::NotPresent) => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("invalid value for {name}")),
+    };
+
+    *setting = Some(
+        value
+            .parse::<T>()
+            .map_err(|err| anyhow!("{err:#}"))
+            .with_context(|| format!("invalid value for {name}: {value}"))?,
+    );
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use std::{fs::File, io, path::Path};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    engine::{Account, DepositState, Engine},
+    ClientId, TransactionId, TransactionRecord, TransactionType,
+};
+
+/// What one transaction did to the engine, for stepping through a run after the fact.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct DiffRecord {
+    /// The transaction's position among those that reached the engine, counting from 1.
+    step: u64,
+    tx: TransactionId,
+    r#type: TransactionType,
+    client: ClientId,
+    /// `applied`, or why the transaction was not.
+    result: String,
+    available_before: Decimal,
+    held_before: Decimal,
+    total_before: Decimal,
+    locked_before: bool,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    /// The state of the deposit with the transaction's ID, if there is one.
+    deposit_before: Option<DepositState>,
+    deposit: Option<DepositState>,
+}
+
+/// The parts of the engine that a transaction may change, as they were before it.
+#[derive(Clone, Copy, Debug)]
+pub struct Before {
+    account: Account,
+    deposit: Option<DepositState>,
+}
+
+impl Before {
+    pub fn new(engine: &Engine, transaction: &TransactionRecord) -> Self {
+        Self {
+            account: engine
+                .account(transaction.client)
+                .copied()
+                .unwrap_or_default(),
+            deposit: engine
+                .deposit_by_id(transaction.tx)
+                .map(|deposit| deposit.state),
+        }
+    }
+}
+
+/// Writes how every transaction changed its account and deposit, whether or not it was applied.
+///
+/// Amounts are written exactly as the engine holds them, without rounding.
+pub struct DiffRecorder<'a> {
+    steps: u64,
+    writer: csv::Writer<Box<dyn io::Write + 'a>>,
+}
+
+impl<'a> DiffRecorder<'a> {
+    pub fn new(writer: impl io::Write + 'a) -> Self {
+        Self {
+            steps: 0,
+            writer: csv::Writer::from_writer(Box::new(writer)),
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        transaction: &TransactionRecord,
+        result: &str,
+        before: Before,
+        engine: &Engine,
+    ) -> Result<(), csv::Error> {
+        self.steps += 1;
+
+        let after = Before::new(engine, transaction);
+
+        self.writer.serialize(DiffRecord {
+            step: self.steps,
+            tx: transaction.tx,
+            r#type: transaction.r#type,
+            client: transaction.client,
+            result: result.to_owned(),
+            available_before: before.account.available(),
+            held_before: before.account.held(),
+            total_before: before.account.total(),
+            locked_before: before.account.locked(),
+            available: after.account.available(),
+            held: after.account.held(),
+            total: after.account.total(),
+            locked: after.account.locked(),
+            deposit_before: before.deposit,
+            deposit: after.deposit,
+        })
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes one client's steps from a recorded diff log, which is much quicker than replaying the
+/// input it was recorded from.
+pub fn explain(path: &Path, client: ClientId, writer: impl io::Write) -> Result<(), anyhow::Error> {
+    let file = File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut csv_reader = csv::Reader::from_reader(file);
+    let mut csv_writer = csv::Writer::from_writer(writer);
+
+    for record_res in csv_reader.deserialize::<DiffRecord>() {
+        let record = record_res.with_context(|| format!("failed to read {}", path.display()))?;
+
+        if record.client == client {
+            csv_writer.serialize(record)?;
+        }
+    }
+
+    csv_writer.flush()?;
+
+    Ok(())
+}
@@ -1,8 +1,13 @@
-use std::collections::{btree_map::Entry, BTreeMap};
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet, VecDeque};
 
+use derive_more::Display;
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 
-use crate::{ClientId, TransactionId};
+use crate::{
+    amount::Amount, store::TransactionStore, ClientId, TransactionId, TransactionRecord,
+    TransactionType,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -19,6 +24,14 @@ pub enum Error {
     #[error("duplicate transaction ID: {0}")]
     DuplicateTransactionId(TransactionId),
 
+    /// The ID was already used by a transaction with a different type, client, or amount, which
+    /// points to corruption upstream rather than a replay.
+    ///
+    /// Withdrawals' clients and amounts aren't kept, so a withdrawal repeating another's ID is
+    /// always reported as [`Error::DuplicateTransactionId`].
+    #[error("conflicting duplicate transaction ID: {0}")]
+    ConflictingDuplicate(TransactionId),
+
     #[error(
         "insufficient funds (client: {client}, available: {available}, requested: {requested})"
     )]
@@ -28,130 +41,1133 @@ pub enum Error {
         requested: Decimal,
     },
 
+    #[error("balance limit exceeded (client: {client}, limit: {limit}, requested: {requested})")]
+    BalanceLimitExceeded {
+        client: ClientId,
+        limit: Decimal,
+        requested: Decimal,
+    },
+
+    #[error("invalid amount (tx: {tx}, amount: {amount})")]
+    InvalidAmount { tx: TransactionId, amount: Decimal },
+
+    #[error("missing amount (tx: {0})")]
+    MissingAmount(TransactionId),
+
     #[error("account locked: {0}")]
     Locked(ClientId),
 
+    #[error("balance out of range (client: {0})")]
+    Overflow(ClientId),
+
+    #[error("settlement balance out of range")]
+    SettlementOverflow,
+
     #[error("transaction not disputed: {0}")]
     NotDisputed(TransactionId),
 
     #[error("transaction not found: {0}")]
     TransactionNotFound(TransactionId),
+
+    #[error("savepoint already rolled back or released")]
+    StaleSavepoint,
+
+    #[error("not enough operations to undo (available: {available})")]
+    UndoUnavailable { available: usize },
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum DepositState {
+#[derive(Debug, thiserror::Error)]
+pub enum MergeError {
+    #[error("client present in both engines: {0}")]
+    ClientConflict(ClientId),
+
+    #[error("combined settlement balance out of range")]
+    SettlementOverflow,
+
+    #[error("duplicate transaction ID: {0}")]
+    DuplicateTransactionId(TransactionId),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DepositState {
     Ok,
     Dispute,
     Chargeback,
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Deposit {
-    client: ClientId,
-    amount: Decimal,
-    state: DepositState,
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Deposit {
+    pub client: ClientId,
+    amount: Amount,
+    pub state: DepositState,
 }
 
-#[derive(Clone, Copy, Debug)]
+impl Deposit {
+    pub fn amount(&self) -> Decimal {
+        self.amount.to_decimal()
+    }
+}
+
+/// Why an account was locked.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockReason {
+    /// A deposit was charged back.
+    #[display("chargeback")]
+    Chargeback,
+    /// An operator locked it by hand.
+    #[display("admin")]
+    Admin,
+    /// A fraud heuristic flagged one of its transactions.
+    #[display("risk_rule")]
+    RiskRule,
+}
+
+/// Something that locked an account.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Lock {
+    pub reason: LockReason,
+    /// The transaction that set it off, if one did.
+    pub tx: Option<TransactionId>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Account {
-    pub total: Decimal,
-    pub held: Decimal,
-    pub locked: bool,
+    total: Amount,
+    held: Amount,
+    /// The first thing that locked the account, if anything has.
+    lock: Option<Lock>,
 }
 
 impl Account {
+    pub fn total(&self) -> Decimal {
+        self.total.to_decimal()
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held.to_decimal()
+    }
+
     pub fn available(&self) -> Decimal {
-        self.total - self.held
+        self.total() - self.held()
+    }
+
+    pub fn locked(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    pub fn lock(&self) -> Option<Lock> {
+        self.lock
     }
 }
 
 impl Default for Account {
     fn default() -> Self {
         Self {
-            total: Decimal::ZERO,
-            held: Decimal::ZERO,
-            locked: false,
+            total: Amount::ZERO,
+            held: Amount::ZERO,
+            lock: None,
         }
     }
 }
 
-#[derive(Debug)]
+/// Which deposits the engine may forget, trading the ability to dispute them again for memory.
+///
+/// A forgotten deposit is as if it had never been made, except for its effect on the balances:
+/// disputing it fails with [`Error::TransactionNotFound`], and its ID can be reused.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Forget deposits as soon as they are charged back.
+    pub forget_chargebacks: bool,
+    /// Forget resolved deposits once this many more transactions have been applied, unless they
+    /// are disputed again first.
+    pub forget_resolved_after: Option<u64>,
+}
+
+/// The most each account's total may reach through deposits, for regulated e-money wallet
+/// limits. Accounts already over their limit keep their funds, but can't take more deposits.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BalanceLimits {
+    /// The limit for every client without one of their own.
+    pub default: Option<Decimal>,
+    pub clients: BTreeMap<ClientId, Decimal>,
+}
+
+impl BalanceLimits {
+    pub fn limit(&self, client: ClientId) -> Option<Decimal> {
+        self.clients.get(&client).copied().or(self.default)
+    }
+}
+
+/// How long each open dispute has been open. Records carry no timestamps, so ages are counted in
+/// transactions that have come in since, across every run that saved and loaded the state.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DisputeAges {
+    /// Transactions that have come in so far.
+    seen: u64,
+    /// The count each open dispute was opened at.
+    opened: BTreeMap<TransactionId, u64>,
+}
+
+impl DisputeAges {
+    /// Counts a transaction coming in.
+    pub fn tick(&mut self) {
+        self.seen += 1;
+    }
+
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// Starts aging a dispute that has just been opened. A deposit disputed again after a resolve
+    /// is aged from the second time.
+    pub fn open(&mut self, tx: TransactionId) {
+        _ = self.opened.insert(tx, self.seen);
+    }
+
+    pub fn close(&mut self, tx: TransactionId) {
+        _ = self.opened.remove(&tx);
+    }
+
+    /// The count an open dispute was opened at.
+    pub fn opened_at(&self, tx: TransactionId) -> Option<u64> {
+        self.opened.get(&tx).copied()
+    }
+
+    /// How many transactions have come in since a dispute was opened, if it is open.
+    pub fn age(&self, tx: TransactionId) -> Option<u64> {
+        self.opened_at(tx).map(|opened_at| self.seen - opened_at)
+    }
+
+    /// Every open dispute, with the count it was opened at.
+    pub fn iter(&self) -> impl Iterator<Item = (TransactionId, u64)> + '_ {
+        self.opened.iter().map(|(&tx, &opened_at)| (tx, opened_at))
+    }
+
+    /// Combines the ages kept by engines that processed disjoint sets of clients.
+    fn merge(&mut self, other: DisputeAges) {
+        self.seen = self.seen.max(other.seen);
+        self.opened.extend(other.opened);
+    }
+}
+
+/// The summed amounts of the operations an engine has applied and rejected, by type.
+///
+/// Disputes, resolutions, and chargebacks count the amount of the deposit they refer to, if it
+/// is known.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TypeTotals {
+    applied: [Decimal; 5],
+    rejected: [Decimal; 5],
+}
+
+impl TypeTotals {
+    pub fn applied(&self, r#type: TransactionType) -> Decimal {
+        self.applied[r#type as usize]
+    }
+
+    pub fn rejected(&self, r#type: TransactionType) -> Decimal {
+        self.rejected[r#type as usize]
+    }
+
+    fn add(&mut self, r#type: TransactionType, amount: Decimal, applied: bool) {
+        let sums = if applied {
+            &mut self.applied
+        } else {
+            &mut self.rejected
+        };
+
+        // Rejected amounts can be as large as the input allows.
+        sums[r#type as usize] = sums[r#type as usize].saturating_add(amount);
+    }
+
+    fn merge(&mut self, other: &TypeTotals) {
+        for (sum, other) in self.applied.iter_mut().zip(other.applied) {
+            *sum = sum.saturating_add(other);
+        }
+
+        for (sum, other) in self.rejected.iter_mut().zip(other.rejected) {
+            *sum = sum.saturating_add(other);
+        }
+    }
+}
+
+/// What something was before an operation changed it, so that the change can be undone.
+#[derive(Clone, Copy, Debug)]
+enum Change {
+    /// An account as it was, or `None` if it didn't exist.
+    Account(ClientId, Option<Account>),
+    /// A deposit as it was, or `None` if it didn't exist.
+    Deposit(TransactionId, Option<Deposit>),
+    /// A withdrawal ID that didn't exist.
+    Withdrawal(TransactionId),
+    /// A resolved deposit was queued to be forgotten.
+    Resolved,
+    /// A resolved deposit was taken off the front of the queue.
+    Unqueued(u64, TransactionId),
+    /// A lock was added to the lock history.
+    Locked,
+}
+
+/// A point that an engine can be rolled back to, from [`Engine::savepoint`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Savepoint(u64);
+
+/// How an engine was at some point, for rolling back to.
+#[derive(Clone, Copy, Debug)]
+struct Mark {
+    /// How many changes had been recorded, counting any that have since been dropped.
+    position: usize,
+    operations: u64,
+    totals: TypeTotals,
+    settlement: Amount,
+}
+
+/// A savepoint that hasn't been rolled back or released yet.
+#[derive(Clone, Copy, Debug)]
+struct OpenSavepoint {
+    savepoint: Savepoint,
+    mark: Mark,
+}
+
+/// An operation in the undo log.
+#[derive(Clone, Copy, Debug)]
+struct Step {
+    mark: Mark,
+    applied: bool,
+}
+
+/// Changes that could still be undone.
+#[derive(Debug, Default)]
+struct Journal {
+    changes: VecDeque<Change>,
+    /// How many changes have been dropped from the front, once nothing could undo them.
+    dropped: usize,
+    /// Oldest first.
+    savepoints: Vec<OpenSavepoint>,
+    /// Operations in the undo log, oldest first.
+    steps: VecDeque<Step>,
+    /// How many of the steps were applied.
+    applied: usize,
+}
+
+impl Journal {
+    fn position(&self) -> usize {
+        self.dropped + self.changes.len()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Engine {
     accounts: BTreeMap<ClientId, Account>,
-    deposits: BTreeMap<TransactionId, Deposit>,
+    deposits: TransactionStore<Deposit>,
+    /// Every withdrawal ID, since they share one ID space with deposits.
+    withdrawals: TransactionStore<()>,
+    /// The counterparty of every deposit, withdrawal, and chargeback, standing for the funds
+    /// held on behalf of every client. It is always minus the sum of every account's total.
+    settlement: Amount,
+    /// Everything that has locked an account, in order, including locks of accounts that were
+    /// already locked.
+    locks: Vec<(ClientId, Lock)>,
+    /// The next sequence number expected from each client whose input carries them, so that
+    /// reordering carries on where it left off.
+    sequences: BTreeMap<ClientId, u64>,
+    /// How long each open dispute has been open, so that disputes carry on aging where an
+    /// earlier run left off.
+    dispute_ages: DisputeAges,
+    /// Who made each deposit on an account shared by several clients, so that only they can
+    /// dispute it in a later run.
+    joint_origins: BTreeMap<TransactionId, ClientId>,
+    /// Configuration rather than state, so it isn't saved.
+    #[serde(skip)]
+    reject_non_positive_amounts: bool,
+    #[serde(skip)]
+    credit_clients: BTreeSet<ClientId>,
+    #[serde(skip)]
+    balance_limits: BalanceLimits,
+    #[serde(skip)]
+    partial_deposits: bool,
+    #[serde(skip)]
+    retention: Retention,
+    /// Resolved deposits waiting to be forgotten, with the operation count they were resolved at.
+    /// Like the retention itself, these are not saved, so a loaded engine keeps them all.
+    #[serde(skip)]
+    resolved: VecDeque<(u64, TransactionId)>,
+    /// How many operations have been attempted.
+    #[serde(skip)]
+    operations: u64,
+    /// Like the operation count, these only cover this run.
+    #[serde(skip)]
+    totals: TypeTotals,
+    /// Only kept while there are open savepoints or an undo log.
+    #[serde(skip)]
+    journal: Option<Journal>,
+    #[serde(skip)]
+    next_savepoint: u64,
+    /// How many applied operations to keep in the undo log, if there is one.
+    #[serde(skip)]
+    undo_limit: Option<usize>,
 }
 
 impl Engine {
     pub fn new() -> Self {
         Self {
             accounts: BTreeMap::new(),
-            deposits: BTreeMap::new(),
+            deposits: TransactionStore::new(),
+            withdrawals: TransactionStore::new(),
+            settlement: Amount::ZERO,
+            locks: Vec::new(),
+            sequences: BTreeMap::new(),
+            dispute_ages: DisputeAges::default(),
+            joint_origins: BTreeMap::new(),
+            reject_non_positive_amounts: false,
+            credit_clients: BTreeSet::new(),
+            balance_limits: BalanceLimits::default(),
+            partial_deposits: false,
+            retention: Retention::default(),
+            resolved: VecDeque::new(),
+            operations: 0,
+            totals: TypeTotals::default(),
+            journal: None,
+            next_savepoint: 0,
+            undo_limit: None,
+        }
+    }
+
+    /// A new engine with no accounts, set up the same way as this one.
+    pub fn new_like(&self) -> Self {
+        Self::new()
+            .reject_non_positive_amounts(self.reject_non_positive_amounts)
+            .credit_clients(self.credit_clients.clone())
+            .balance_limits(self.balance_limits.clone())
+            .partial_deposits(self.partial_deposits)
+            .retention(self.retention)
+    }
+
+    /// Makes deposits and withdrawals of zero or negative amounts fail with
+    /// [`Error::InvalidAmount`] instead of being applied.
+    pub fn reject_non_positive_amounts(mut self, reject: bool) -> Self {
+        self.reject_non_positive_amounts = reject;
+        self
+    }
+
+    /// Lets these clients withdraw more than they have available, instead of failing with
+    /// [`Error::InsufficientFunds`].
+    pub fn credit_clients(mut self, clients: BTreeSet<ClientId>) -> Self {
+        self.credit_clients = clients;
+        self
+    }
+
+    /// Makes deposits that would take an account over its limit fail with
+    /// [`Error::BalanceLimitExceeded`].
+    pub fn balance_limits(mut self, limits: BalanceLimits) -> Self {
+        self.balance_limits = limits;
+        self
+    }
+
+    /// Accepts as much of a deposit as fits under the account's limit, instead of failing. The
+    /// deposit is kept with the amount accepted, so that is what a dispute holds.
+    pub fn partial_deposits(mut self, partial: bool) -> Self {
+        self.partial_deposits = partial;
+        self
+    }
+
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Keeps the last `limit` applied operations, so that they can be undone with
+    /// [`Engine::undo`].
+    pub fn undo_limit(mut self, limit: usize) -> Self {
+        self.undo_limit = Some(limit);
+        _ = self.journal.get_or_insert_with(Journal::default);
+        self
+    }
+
+    /// Combines two engines that processed disjoint sets of clients.
+    ///
+    /// A client may appear in both engines as long as its account is untouched in one of them,
+    /// which happens when a shard sees a rejected operation for a client it does not own.
+    pub fn merge(mut self, other: Engine) -> Result<Engine, MergeError> {
+        self.totals.merge(&other.totals);
+
+        self.settlement = self
+            .settlement
+            .checked_add(other.settlement)
+            .ok_or(MergeError::SettlementOverflow)?;
+
+        for (tx, deposit) in other.deposits.into_entries() {
+            if self.withdrawals.contains_key(tx) || !self.deposits.insert_new(tx, deposit) {
+                return Err(MergeError::DuplicateTransactionId(tx));
+            }
         }
+
+        for (tx, ()) in other.withdrawals.into_entries() {
+            if self.is_known_id(tx) || !self.withdrawals.insert_new(tx, ()) {
+                return Err(MergeError::DuplicateTransactionId(tx));
+            }
+        }
+
+        self.locks.extend(other.locks);
+
+        for (client, next) in other.sequences {
+            let existing = self.sequences.entry(client).or_insert(next);
+            *existing = (*existing).max(next);
+        }
+
+        self.dispute_ages.merge(other.dispute_ages);
+        self.joint_origins.extend(other.joint_origins);
+
+        for (client, account) in other.accounts {
+            match self.accounts.entry(client) {
+                Entry::Vacant(entry) => {
+                    _ = entry.insert(account);
+                }
+                Entry::Occupied(mut entry) => {
+                    if *entry.get() == Account::default() {
+                        _ = entry.insert(account);
+                    } else if account != Account::default() {
+                        return Err(MergeError::ClientConflict(client));
+                    }
+                }
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Lists every reason [`Engine::merge`] would refuse to combine these engines.
+    pub fn merge_conflicts(&self, other: &Engine) -> Vec<MergeError> {
+        let clients = other
+            .accounts
+            .iter()
+            .filter(|&(client, account)| {
+                self.accounts.get(client).is_some_and(|existing| {
+                    *existing != Account::default() && *account != Account::default()
+                })
+            })
+            .map(|(&client, _)| MergeError::ClientConflict(client));
+
+        let txs = other
+            .deposits
+            .iter()
+            .map(|(tx, _)| tx)
+            .chain(other.withdrawals.iter().map(|(tx, ())| tx))
+            .filter(|&tx| self.is_known_id(tx))
+            .map(MergeError::DuplicateTransactionId);
+
+        let settlement = self
+            .settlement
+            .checked_add(other.settlement)
+            .is_none()
+            .then_some(MergeError::SettlementOverflow);
+
+        clients.chain(txs).chain(settlement).collect()
+    }
+
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    pub fn deposit_by_id(&self, tx: TransactionId) -> Option<&Deposit> {
+        self.deposits.get(tx)
+    }
+
+    /// The highest transaction ID of any deposit or withdrawal that hasn't been forgotten.
+    pub fn last_id(&self) -> Option<TransactionId> {
+        self.deposits.last_id().max(self.withdrawals.last_id())
+    }
+
+    /// The most `client`'s account may hold, if it is limited.
+    pub fn balance_limit(&self, client: ClientId) -> Option<Decimal> {
+        self.balance_limits.limit(client)
     }
 
     pub fn accounts(&self) -> impl Iterator<Item = (&ClientId, &Account)> {
         self.accounts.iter()
     }
 
+    /// Everything that has locked an account, in the order it happened.
+    pub fn locks(&self) -> &[(ClientId, Lock)] {
+        &self.locks
+    }
+
+    /// The next sequence number expected from each client, as of the last time they were set.
+    pub fn sequences(&self) -> &BTreeMap<ClientId, u64> {
+        &self.sequences
+    }
+
+    /// Records the next sequence number expected from each client. This is not undone by
+    /// rolling back, so it should only be set once nothing will be.
+    pub fn set_sequences(&mut self, sequences: BTreeMap<ClientId, u64>) {
+        self.sequences = sequences;
+    }
+
+    /// How long each open dispute has been open, as of the last time they were set.
+    pub fn dispute_ages(&self) -> &DisputeAges {
+        &self.dispute_ages
+    }
+
+    /// Records how long each open dispute has been open. Like the sequence numbers, this is not
+    /// undone by rolling back.
+    pub fn set_dispute_ages(&mut self, ages: DisputeAges) {
+        self.dispute_ages = ages;
+    }
+
+    /// Who made each deposit on a shared account, as of the last time they were set.
+    pub fn joint_origins(&self) -> &BTreeMap<TransactionId, ClientId> {
+        &self.joint_origins
+    }
+
+    /// Records who made each deposit on a shared account. Like the sequence numbers, this is not
+    /// undone by rolling back.
+    pub fn set_joint_origins(&mut self, origins: BTreeMap<TransactionId, ClientId>) {
+        self.joint_origins = origins;
+    }
+
+    /// The balance of the settlement account, which every account's total sums against to zero.
+    pub fn settlement(&self) -> Decimal {
+        self.settlement.to_decimal()
+    }
+
+    pub fn totals(&self) -> &TypeTotals {
+        &self.totals
+    }
+
+    /// Every deposit that hasn't been forgotten, in no particular order.
+    pub fn deposits(&self) -> impl Iterator<Item = (TransactionId, &Deposit)> {
+        self.deposits.iter()
+    }
+
+    /// Applies a transaction of any type.
+    pub fn apply(&mut self, transaction: &TransactionRecord) -> Result<(), Error> {
+        let TransactionRecord {
+            r#type, client, tx, ..
+        } = *transaction;
+        let amount = || transaction.amount.ok_or(Error::MissingAmount(tx));
+
+        match r#type {
+            TransactionType::Deposit => self.deposit(client, tx, amount()?),
+            TransactionType::Withdrawal => self.withdraw(client, tx, amount()?),
+            TransactionType::Dispute => self.dispute(client, tx),
+            TransactionType::Resolve => self.resolve(client, tx),
+            TransactionType::Chargeback => self.chargeback(client, tx),
+        }
+    }
+
+    /// Applies every transaction in `batch`, or none of them. If one fails, the engine is left as
+    /// it was before the batch, and the error is returned.
+    pub fn apply_atomic<'t>(
+        &mut self,
+        batch: impl IntoIterator<Item = &'t TransactionRecord>,
+    ) -> Result<(), Error> {
+        let savepoint = self.savepoint();
+
+        for transaction in batch {
+            if let Err(err) = self.apply(transaction) {
+                self.rollback_to(savepoint)
+                    .expect("batch savepoint is still open");
+                return Err(err);
+            }
+        }
+
+        self.release(savepoint)
+            .expect("batch savepoint is still open");
+
+        Ok(())
+    }
+
+    /// Marks how the engine is now, so that it can be rolled back to later.
+    ///
+    /// Every change is recorded for as long as any savepoint is open, so savepoints should be
+    /// rolled back or released once they are no longer needed.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let savepoint = Savepoint(self.next_savepoint);
+        self.next_savepoint += 1;
+
+        let mark = self.mark();
+        self.journal
+            .get_or_insert_with(Journal::default)
+            .savepoints
+            .push(OpenSavepoint { savepoint, mark });
+
+        savepoint
+    }
+
+    /// Undoes every change since `savepoint`, which is closed along with any savepoints taken
+    /// after it.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), Error> {
+        let open = self.close(savepoint)?;
+        self.rewind(open.mark);
+        self.trim();
+
+        Ok(())
+    }
+
+    /// Keeps every change since `savepoint`, which is closed along with any savepoints taken
+    /// after it.
+    pub fn release(&mut self, savepoint: Savepoint) -> Result<(), Error> {
+        _ = self.close(savepoint)?;
+        self.trim();
+
+        Ok(())
+    }
+
+    /// Reverses the last `n` applied operations, along with any rejected operations since. This
+    /// only reaches back as far as the undo limit.
+    pub fn undo(&mut self, n: usize) -> Result<(), Error> {
+        let Some(index) = n.checked_sub(1) else {
+            return Ok(());
+        };
+
+        let journal = self.journal.as_ref();
+        let step = journal
+            .and_then(|journal| {
+                journal
+                    .steps
+                    .iter()
+                    .rev()
+                    .filter(|step| step.applied)
+                    .nth(index)
+            })
+            .ok_or(Error::UndoUnavailable {
+                available: journal.map_or(0, |journal| journal.applied),
+            })?;
+
+        self.rewind(step.mark);
+
+        Ok(())
+    }
+
+    /// Runs an operation, keeping it in the undo log if there is one.
+    fn step(
+        &mut self,
+        operation: impl FnOnce(&mut Self) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if self.undo_limit.is_none() {
+            return operation(self);
+        }
+
+        let mark = self.mark();
+        let journal = self.journal.get_or_insert_with(Journal::default);
+        journal.steps.push_back(Step {
+            mark,
+            applied: false,
+        });
+
+        let res = operation(self);
+
+        if res.is_ok() {
+            if let Some(journal) = &mut self.journal {
+                if let Some(step) = journal.steps.back_mut() {
+                    step.applied = true;
+                    journal.applied += 1;
+                }
+            }
+
+            self.trim();
+        }
+
+        res
+    }
+
+    fn mark(&self) -> Mark {
+        Mark {
+            position: self.journal.as_ref().map_or(0, Journal::position),
+            operations: self.operations,
+            totals: self.totals,
+            settlement: self.settlement,
+        }
+    }
+
+    /// Removes `savepoint` and every later one from the open savepoints.
+    fn close(&mut self, savepoint: Savepoint) -> Result<OpenSavepoint, Error> {
+        let journal = self.journal.as_mut().ok_or(Error::StaleSavepoint)?;
+        let index = journal
+            .savepoints
+            .iter()
+            .position(|open| open.savepoint == savepoint)
+            .ok_or(Error::StaleSavepoint)?;
+
+        let open = journal.savepoints[index];
+        journal.savepoints.truncate(index);
+
+        Ok(open)
+    }
+
+    /// Undoes every change since `mark`, forgetting any savepoints and steps that came after it.
+    fn rewind(&mut self, mark: Mark) {
+        let Some(mut journal) = self.journal.take() else {
+            return;
+        };
+
+        while journal.position() > mark.position {
+            let Some(change) = journal.changes.pop_back() else {
+                break;
+            };
+
+            self.undo_change(change);
+        }
+
+        while let Some(step) = journal.steps.back() {
+            if step.mark.position < mark.position {
+                break;
+            }
+
+            if step.applied {
+                journal.applied -= 1;
+            }
+
+            _ = journal.steps.pop_back();
+        }
+
+        journal
+            .savepoints
+            .retain(|open| open.mark.position <= mark.position);
+
+        self.operations = mark.operations;
+        self.totals = mark.totals;
+        self.settlement = mark.settlement;
+        self.journal = Some(journal);
+    }
+
+    /// Drops whatever can no longer be undone, and the journal itself if nothing needs it.
+    fn trim(&mut self) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+
+        let Some(limit) = self.undo_limit else {
+            if journal.savepoints.is_empty() {
+                self.journal = None;
+            }
+
+            return;
+        };
+
+        while journal.applied > limit {
+            let Some(step) = journal.steps.pop_front() else {
+                break;
+            };
+
+            if step.applied {
+                journal.applied -= 1;
+            }
+        }
+
+        // Undoing the oldest applied step never needs anything from before it.
+        while journal.steps.front().is_some_and(|step| !step.applied) {
+            _ = journal.steps.pop_front();
+        }
+
+        let keep = journal
+            .steps
+            .front()
+            .map(|step| step.mark.position)
+            .into_iter()
+            .chain(journal.savepoints.first().map(|open| open.mark.position))
+            .min()
+            .unwrap_or(journal.position());
+
+        while journal.dropped < keep && journal.changes.pop_front().is_some() {
+            journal.dropped += 1;
+        }
+    }
+
+    fn undo_change(&mut self, change: Change) {
+        match change {
+            Change::Account(client, Some(account)) => _ = self.accounts.insert(client, account),
+            Change::Account(client, None) => _ = self.accounts.remove(&client),
+            Change::Deposit(tx, Some(deposit)) => match self.deposits.get_mut(tx) {
+                Some(current) => *current = deposit,
+                None => _ = self.deposits.insert_new(tx, deposit),
+            },
+            Change::Deposit(tx, None) => self.deposits.remove(tx),
+            Change::Withdrawal(tx) => self.withdrawals.remove(tx),
+            Change::Resolved => _ = self.resolved.pop_back(),
+            Change::Unqueued(resolved_at, tx) => self.resolved.push_front((resolved_at, tx)),
+            Change::Locked => _ = self.locks.pop(),
+        }
+    }
+
+    fn record(&mut self, change: Change) {
+        if let Some(journal) = &mut self.journal {
+            journal.changes.push_back(change);
+        }
+    }
+
+    /// Records everything an operation on `client` and `tx` may change, as it is now.
+    fn record_before(&mut self, client: ClientId, tx: TransactionId) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+
+        let changes = &mut journal.changes;
+        changes.push_back(Change::Account(client, self.accounts.get(&client).copied()));
+        changes.push_back(Change::Deposit(tx, self.deposits.get(tx).copied()));
+
+        if !self.withdrawals.contains_key(tx) {
+            changes.push_back(Change::Withdrawal(tx));
+        }
+    }
+
     pub fn deposit(
         &mut self,
         client: ClientId,
         tx: TransactionId,
         amount: Decimal,
     ) -> Result<(), Error> {
+        self.step(|engine| {
+            let res = engine.apply_deposit(client, tx, amount);
+            engine
+                .totals
+                .add(TransactionType::Deposit, amount, res.is_ok());
+            res
+        })
+    }
+
+    pub fn withdraw(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+    ) -> Result<(), Error> {
+        self.step(|engine| {
+            let res = engine.apply_withdraw(client, tx, amount);
+            engine
+                .totals
+                .add(TransactionType::Withdrawal, amount, res.is_ok());
+            res
+        })
+    }
+
+    pub fn dispute(&mut self, client: ClientId, tx: TransactionId) -> Result<(), Error> {
+        self.with_deposit_totals(TransactionType::Dispute, tx, |engine| {
+            engine.apply_dispute(client, tx)
+        })
+    }
+
+    pub fn resolve(&mut self, client: ClientId, tx: TransactionId) -> Result<(), Error> {
+        self.with_deposit_totals(TransactionType::Resolve, tx, |engine| {
+            engine.apply_resolve(client, tx)
+        })
+    }
+
+    pub fn chargeback(&mut self, client: ClientId, tx: TransactionId) -> Result<(), Error> {
+        self.with_deposit_totals(TransactionType::Chargeback, tx, |engine| {
+            engine.apply_chargeback(client, tx)
+        })
+    }
+
+    /// Locks `client`'s account, creating it if need be. An account that is already locked
+    /// stays locked for its first reason, but the lock is still added to the history.
+    pub fn lock(
+        &mut self,
+        client: ClientId,
+        reason: LockReason,
+        tx: Option<TransactionId>,
+    ) -> Result<(), Error> {
+        self.step(|engine| {
+            engine.record(Change::Account(
+                client,
+                engine.accounts.get(&client).copied(),
+            ));
+            _ = engine.accounts.entry(client).or_default();
+            engine.add_lock(client, Lock { reason, tx });
+            Ok(())
+        })
+    }
+
+    fn add_lock(&mut self, client: ClientId, lock: Lock) {
+        if let Some(account) = self.accounts.get_mut(&client) {
+            _ = account.lock.get_or_insert(lock);
+        }
+
+        self.locks.push((client, lock));
+        self.record(Change::Locked);
+    }
+
+    /// Runs an operation on a deposit, adding the deposit's amount to the totals for `r#type`.
+    /// The amount is looked up first, since a chargeback may forget the deposit.
+    fn with_deposit_totals(
+        &mut self,
+        r#type: TransactionType,
+        tx: TransactionId,
+        operation: impl FnOnce(&mut Self) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.step(|engine| {
+            let amount = engine.deposits.get(tx).map(Deposit::amount);
+            let res = operation(engine);
+
+            if let Some(amount) = amount {
+                engine.totals.add(r#type, amount, res.is_ok());
+            }
+
+            res
+        })
+    }
+
+    fn apply_deposit(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+    ) -> Result<(), Error> {
+        self.tick();
+        self.record_before(client, tx);
+
+        let requested = amount;
+        let mut amount = self.check_amount(tx, amount)?;
+
+        let limit = self.balance_limits.limit(client);
         let account = self.accounts.entry(client).or_default();
 
-        if account.locked {
+        if account.locked() {
             return Err(Error::Locked(client));
         }
 
-        match self.deposits.entry(tx) {
-            Entry::Vacant(entry) => {
-                _ = entry.insert(Deposit {
-                    client,
-                    amount,
-                    state: DepositState::Ok,
-                });
+        let mut total = account
+            .total
+            .checked_add(amount)
+            .ok_or(Error::Overflow(client))?;
+
+        if let Some(limit) = limit.filter(|&limit| total.to_decimal() > limit) {
+            let exceeded = Error::BalanceLimitExceeded {
+                client,
+                limit,
+                requested,
+            };
+
+            let room = limit
+                .checked_sub(account.total.to_decimal())
+                .unwrap_or(Decimal::MAX);
+
+            if !self.partial_deposits || room <= Decimal::ZERO {
+                return Err(exceeded);
             }
-            Entry::Occupied(_) => return Err(Error::DuplicateTransactionId(tx)),
+
+            // The room may have more decimal places than an amount can hold.
+            amount = Amount::from_decimal(room).ok_or(exceeded)?;
+            total = account
+                .total
+                .checked_add(amount)
+                .ok_or(Error::Overflow(client))?;
+        }
+
+        let settlement = self
+            .settlement
+            .checked_sub(amount)
+            .ok_or(Error::SettlementOverflow)?;
+
+        let deposit = Deposit {
+            client,
+            amount,
+            state: DepositState::Ok,
+        };
+
+        if self.withdrawals.contains_key(tx) {
+            return Err(Error::ConflictingDuplicate(tx));
         }
 
-        account.total += amount;
+        if !self.deposits.insert_new(tx, deposit) {
+            let replayed = self
+                .deposits
+                .get(tx)
+                .is_some_and(|existing| existing.client == client && existing.amount == amount);
+
+            return Err(if replayed {
+                Error::DuplicateTransactionId(tx)
+            } else {
+                Error::ConflictingDuplicate(tx)
+            });
+        }
+
+        account.total = total;
+        self.settlement = settlement;
 
         Ok(())
     }
 
-    pub fn withdraw(
+    fn apply_withdraw(
         &mut self,
         client: ClientId,
-        _tx: TransactionId,
+        tx: TransactionId,
         amount: Decimal,
     ) -> Result<(), Error> {
+        self.tick();
+        self.record_before(client, tx);
+
+        let requested = amount;
+        let amount = self.check_amount(tx, amount)?;
+
         let account = self.accounts.entry(client).or_default();
 
-        if account.locked {
+        if account.locked() {
             return Err(Error::Locked(client));
         }
 
-        if account.available() < amount {
+        let available = account
+            .total
+            .checked_sub(account.held)
+            .ok_or(Error::Overflow(client))?;
+
+        if available < amount && !self.credit_clients.contains(&client) {
             return Err(Error::InsufficientFunds {
                 client,
-                available: account.available(),
-                requested: amount,
+                available: available.to_decimal(),
+                requested,
             });
         }
 
-        account.total -= amount;
+        let total = account
+            .total
+            .checked_sub(amount)
+            .ok_or(Error::Overflow(client))?;
+
+        let settlement = self
+            .settlement
+            .checked_add(amount)
+            .ok_or(Error::SettlementOverflow)?;
+
+        if self.deposits.contains_key(tx) {
+            return Err(Error::ConflictingDuplicate(tx));
+        }
+
+        if !self.withdrawals.insert_new(tx, ()) {
+            return Err(Error::DuplicateTransactionId(tx));
+        }
+
+        account.total = total;
+        self.settlement = settlement;
 
         Ok(())
     }
 
-    pub fn dispute(&mut self, client: ClientId, tx: TransactionId) -> Result<(), Error> {
+    /// Whether a deposit or withdrawal with this ID has been applied.
+    pub fn is_known_id(&self, tx: TransactionId) -> bool {
+        self.deposits.contains_key(tx) || self.withdrawals.contains_key(tx)
+    }
+
+    /// Converts an amount from the input, failing if it is not allowed or can't be held.
+    fn check_amount(&self, tx: TransactionId, amount: Decimal) -> Result<Amount, Error> {
+        if self.reject_non_positive_amounts && amount <= Decimal::ZERO {
+            return Err(Error::InvalidAmount { tx, amount });
+        }
+
+        Amount::from_decimal(amount).ok_or(Error::InvalidAmount { tx, amount })
+    }
+
+    fn apply_dispute(&mut self, client: ClientId, tx: TransactionId) -> Result<(), Error> {
+        self.tick();
+        self.record_before(client, tx);
+
         let account = self.accounts.entry(client).or_default();
 
         let deposit = self
             .deposits
-            .get_mut(&tx)
+            .get_mut(tx)
             .ok_or(Error::TransactionNotFound(tx))?;
 
         if deposit.client != client {
@@ -168,18 +1184,24 @@ impl Engine {
 
         // If `deposit.amount > account.total`? Should be fine, right?
 
+        account.held = account
+            .held
+            .checked_add(deposit.amount)
+            .ok_or(Error::Overflow(client))?;
         deposit.state = DepositState::Dispute;
-        account.held += deposit.amount;
 
         Ok(())
     }
 
-    pub fn resolve(&mut self, client: ClientId, tx: TransactionId) -> Result<(), Error> {
+    fn apply_resolve(&mut self, client: ClientId, tx: TransactionId) -> Result<(), Error> {
+        self.tick();
+        self.record_before(client, tx);
+
         let account = self.accounts.entry(client).or_default();
 
         let deposit = self
             .deposits
-            .get_mut(&tx)
+            .get_mut(tx)
             .ok_or(Error::TransactionNotFound(tx))?;
 
         if deposit.client != client {
@@ -194,18 +1216,29 @@ impl Engine {
             return Err(Error::NotDisputed(tx));
         }
 
+        account.held = account
+            .held
+            .checked_sub(deposit.amount)
+            .ok_or(Error::Overflow(client))?;
         deposit.state = DepositState::Ok;
-        account.held -= deposit.amount;
+
+        if self.retention.forget_resolved_after.is_some() {
+            self.resolved.push_back((self.operations, tx));
+            self.record(Change::Resolved);
+        }
 
         Ok(())
     }
 
-    pub fn chargeback(&mut self, client: ClientId, tx: TransactionId) -> Result<(), Error> {
+    fn apply_chargeback(&mut self, client: ClientId, tx: TransactionId) -> Result<(), Error> {
+        self.tick();
+        self.record_before(client, tx);
+
         let account = self.accounts.entry(client).or_default();
 
         let deposit = self
             .deposits
-            .get_mut(&tx)
+            .get_mut(tx)
             .ok_or(Error::TransactionNotFound(tx))?;
 
         if deposit.client != client {
@@ -220,11 +1253,342 @@ impl Engine {
             return Err(Error::NotDisputed(tx));
         }
 
+        let held = account.held.checked_sub(deposit.amount);
+        let total = account.total.checked_sub(deposit.amount);
+        let (Some(held), Some(total)) = (held, total) else {
+            return Err(Error::Overflow(client));
+        };
+
+        let settlement = self
+            .settlement
+            .checked_add(deposit.amount)
+            .ok_or(Error::SettlementOverflow)?;
+
         deposit.state = DepositState::Chargeback;
-        account.held -= deposit.amount;
-        account.total -= deposit.amount;
-        account.locked = true;
+        account.held = held;
+        account.total = total;
+        self.settlement = settlement;
+
+        self.add_lock(
+            client,
+            Lock {
+                reason: LockReason::Chargeback,
+                tx: Some(tx),
+            },
+        );
+
+        if self.retention.forget_chargebacks {
+            self.deposits.remove(tx);
+        }
 
         Ok(())
     }
+
+    /// Counts an operation, forgetting any resolved deposits that have been kept long enough.
+    fn tick(&mut self) {
+        self.operations += 1;
+
+        let Some(after) = self.retention.forget_resolved_after else {
+            return;
+        };
+
+        while let Some(&(resolved_at, tx)) = self.resolved.front() {
+            if self.operations - resolved_at <= after {
+                break;
+            }
+
+            _ = self.resolved.pop_front();
+            self.record(Change::Unqueued(resolved_at, tx));
+
+            if let Some(&deposit) = self.deposits.get(tx) {
+                if deposit.state == DepositState::Ok {
+                    self.record(Change::Deposit(tx, Some(deposit)));
+                    self.deposits.remove(tx);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: ClientId = ClientId(1);
+
+    fn tx(id: u16) -> TransactionId {
+        TransactionId(id.into())
+    }
+
+    fn dec(n: i64) -> Decimal {
+        Decimal::from(n)
+    }
+
+    #[test]
+    fn forgets_chargebacks() {
+        let mut engine = Engine::new().retention(Retention {
+            forget_chargebacks: true,
+            forget_resolved_after: None,
+        });
+
+        engine.deposit(CLIENT, tx(1), dec(10)).unwrap();
+        engine.dispute(CLIENT, tx(1)).unwrap();
+        engine.chargeback(CLIENT, tx(1)).unwrap();
+
+        assert!(engine.deposit_by_id(tx(1)).is_none());
+        assert!(matches!(
+            engine.dispute(CLIENT, tx(1)),
+            Err(Error::TransactionNotFound(_))
+        ));
+
+        let account = engine.account(CLIENT).unwrap();
+        assert_eq!(account.total(), dec(0));
+        assert!(account.locked());
+    }
+
+    #[test]
+    fn forgets_resolved_deposits_after_delay() {
+        let mut engine = Engine::new().retention(Retention {
+            forget_chargebacks: false,
+            forget_resolved_after: Some(2),
+        });
+
+        engine.deposit(CLIENT, tx(1), dec(10)).unwrap();
+        engine.dispute(CLIENT, tx(1)).unwrap();
+        engine.resolve(CLIENT, tx(1)).unwrap();
+
+        engine.deposit(CLIENT, tx(2), dec(1)).unwrap();
+        engine.deposit(CLIENT, tx(3), dec(1)).unwrap();
+        assert!(engine.deposit_by_id(tx(1)).is_some());
+
+        engine.deposit(CLIENT, tx(4), dec(1)).unwrap();
+        assert!(engine.deposit_by_id(tx(1)).is_none());
+        assert_eq!(engine.account(CLIENT).unwrap().total(), dec(13));
+    }
+
+    #[test]
+    fn keeps_resolved_deposits_disputed_again() {
+        let mut engine = Engine::new().retention(Retention {
+            forget_chargebacks: false,
+            forget_resolved_after: Some(1),
+        });
+
+        engine.deposit(CLIENT, tx(1), dec(10)).unwrap();
+        engine.dispute(CLIENT, tx(1)).unwrap();
+        engine.resolve(CLIENT, tx(1)).unwrap();
+        engine.dispute(CLIENT, tx(1)).unwrap();
+
+        engine.deposit(CLIENT, tx(2), dec(1)).unwrap();
+        engine.deposit(CLIENT, tx(3), dec(1)).unwrap();
+
+        assert!(engine.deposit_by_id(tx(1)).is_some());
+        engine.chargeback(CLIENT, tx(1)).unwrap();
+    }
+
+    #[test]
+    fn rolls_back_to_savepoint() {
+        let mut engine = Engine::new();
+        engine.deposit(CLIENT, tx(1), dec(10)).unwrap();
+
+        let savepoint = engine.savepoint();
+        engine.deposit(CLIENT, tx(2), dec(5)).unwrap();
+        engine.dispute(CLIENT, tx(1)).unwrap();
+        engine.withdraw(CLIENT, tx(3), dec(2)).unwrap();
+
+        engine.rollback_to(savepoint).unwrap();
+
+        let account = engine.account(CLIENT).unwrap();
+        assert_eq!(account.total(), dec(10));
+        assert_eq!(account.held(), dec(0));
+        assert_eq!(engine.settlement(), dec(-10));
+        assert!(engine.deposit_by_id(tx(2)).is_none());
+        assert_eq!(engine.deposit_by_id(tx(1)).unwrap().state, DepositState::Ok);
+
+        // The IDs can be used again, since those transactions never happened.
+        engine.deposit(CLIENT, tx(2), dec(1)).unwrap();
+        engine.withdraw(CLIENT, tx(3), dec(1)).unwrap();
+    }
+
+    #[test]
+    fn closes_later_savepoints_on_rollback() {
+        let mut engine = Engine::new();
+
+        let outer = engine.savepoint();
+        engine.deposit(CLIENT, tx(1), dec(10)).unwrap();
+        let inner = engine.savepoint();
+
+        engine.rollback_to(outer).unwrap();
+
+        assert!(matches!(
+            engine.rollback_to(inner),
+            Err(Error::StaleSavepoint)
+        ));
+        assert!(engine.account(CLIENT).is_none());
+    }
+
+    #[test]
+    fn keeps_changes_on_release() {
+        let mut engine = Engine::new();
+
+        let savepoint = engine.savepoint();
+        engine.deposit(CLIENT, tx(1), dec(10)).unwrap();
+        engine.release(savepoint).unwrap();
+
+        assert!(matches!(
+            engine.rollback_to(savepoint),
+            Err(Error::StaleSavepoint)
+        ));
+        assert_eq!(engine.account(CLIENT).unwrap().total(), dec(10));
+    }
+
+    #[test]
+    fn applies_batch_atomically() {
+        let mut engine = Engine::new();
+        engine.deposit(CLIENT, tx(1), dec(10)).unwrap();
+
+        let batch = [
+            TransactionRecord {
+                r#type: TransactionType::Withdrawal,
+                client: CLIENT,
+                tx: tx(2),
+                amount: Some(dec(4)),
+                seq: None,
+                wallet: None,
+            },
+            TransactionRecord {
+                r#type: TransactionType::Withdrawal,
+                client: CLIENT,
+                tx: tx(3),
+                amount: Some(dec(7)),
+                seq: None,
+                wallet: None,
+            },
+        ];
+
+        assert!(matches!(
+            engine.apply_atomic(&batch),
+            Err(Error::InsufficientFunds { .. })
+        ));
+        assert_eq!(engine.account(CLIENT).unwrap().total(), dec(10));
+    }
+
+    #[test]
+    fn undoes_applied_operations() {
+        let mut engine = Engine::new().undo_limit(10);
+        engine.deposit(CLIENT, tx(1), dec(10)).unwrap();
+        engine.deposit(CLIENT, tx(2), dec(5)).unwrap();
+        engine.withdraw(CLIENT, tx(3), dec(100)).unwrap_err();
+        engine.dispute(CLIENT, tx(1)).unwrap();
+
+        engine.undo(2).unwrap();
+
+        let account = engine.account(CLIENT).unwrap();
+        assert_eq!(account.total(), dec(10));
+        assert_eq!(account.held(), dec(0));
+        assert!(engine.deposit_by_id(tx(2)).is_none());
+
+        engine.undo(1).unwrap();
+        assert!(engine.account(CLIENT).is_none());
+        assert_eq!(engine.settlement(), dec(0));
+    }
+
+    #[test]
+    fn undoes_no_further_than_limit() {
+        let mut engine = Engine::new().undo_limit(2);
+
+        for id in 1..=4 {
+            engine.deposit(CLIENT, tx(id), dec(1)).unwrap();
+        }
+
+        assert!(matches!(
+            engine.undo(3),
+            Err(Error::UndoUnavailable { available: 2 })
+        ));
+
+        engine.undo(2).unwrap();
+        assert_eq!(engine.account(CLIENT).unwrap().total(), dec(2));
+    }
+
+    fn limits() -> BalanceLimits {
+        BalanceLimits {
+            default: Some(dec(100)),
+            clients: BTreeMap::from([(ClientId(2), dec(20))]),
+        }
+    }
+
+    #[test]
+    fn rejects_deposits_over_limit() {
+        let mut engine = Engine::new().balance_limits(limits());
+
+        engine.deposit(CLIENT, tx(1), dec(100)).unwrap();
+        assert!(matches!(
+            engine.deposit(CLIENT, tx(2), dec(1)),
+            Err(Error::BalanceLimitExceeded { limit, .. }) if limit == dec(100)
+        ));
+
+        assert!(matches!(
+            engine.deposit(ClientId(2), tx(3), dec(21)),
+            Err(Error::BalanceLimitExceeded { limit, .. }) if limit == dec(20)
+        ));
+
+        assert_eq!(engine.account(CLIENT).unwrap().total(), dec(100));
+        assert_eq!(engine.settlement(), dec(-100));
+    }
+
+    #[test]
+    fn accepts_part_of_deposits_over_limit() {
+        let mut engine = Engine::new()
+            .balance_limits(limits())
+            .partial_deposits(true);
+
+        engine.deposit(CLIENT, tx(1), dec(90)).unwrap();
+        engine.deposit(CLIENT, tx(2), dec(30)).unwrap();
+        assert_eq!(engine.deposit_by_id(tx(2)).unwrap().amount(), dec(10));
+
+        engine.dispute(CLIENT, tx(2)).unwrap();
+        assert_eq!(engine.account(CLIENT).unwrap().held(), dec(10));
+
+        // A full account takes nothing, rather than a deposit of zero.
+        assert!(matches!(
+            engine.deposit(CLIENT, tx(3), dec(1)),
+            Err(Error::BalanceLimitExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn keeps_first_lock_and_history() {
+        let mut engine = Engine::new();
+        engine.deposit(CLIENT, tx(1), dec(10)).unwrap();
+        engine.dispute(CLIENT, tx(1)).unwrap();
+        engine.chargeback(CLIENT, tx(1)).unwrap();
+        engine.lock(CLIENT, LockReason::Admin, None).unwrap();
+
+        let chargeback = Lock {
+            reason: LockReason::Chargeback,
+            tx: Some(tx(1)),
+        };
+        let admin = Lock {
+            reason: LockReason::Admin,
+            tx: None,
+        };
+        assert_eq!(engine.account(CLIENT).unwrap().lock(), Some(chargeback));
+        assert_eq!(engine.locks(), [(CLIENT, chargeback), (CLIENT, admin)]);
+    }
+
+    #[test]
+    fn rolls_back_locks() {
+        let mut engine = Engine::new();
+        engine.deposit(CLIENT, tx(1), dec(10)).unwrap();
+
+        let savepoint = engine.savepoint();
+        engine
+            .lock(CLIENT, LockReason::RiskRule, Some(tx(1)))
+            .unwrap();
+        engine.lock(ClientId(2), LockReason::Admin, None).unwrap();
+        engine.rollback_to(savepoint).unwrap();
+
+        assert!(!engine.account(CLIENT).unwrap().locked());
+        assert!(engine.account(ClientId(2)).is_none());
+        assert!(engine.locks().is_empty());
+    }
 }
@@ -0,0 +1,107 @@
+use std::{collections::VecDeque, io};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{engine::DisputeAges, output::Rounding, ClientId, TransactionId};
+
+#[derive(Clone, Debug, Serialize)]
+struct LapsedRecord {
+    client: ClientId,
+    tx: TransactionId,
+    amount: Decimal,
+}
+
+/// Lapses disputes that have been left open too long, resolving them as if a resolve had come
+/// in, the way unactioned disputes lapse under network rules.
+///
+/// Transactions carry no timestamps, so a dispute's age is the number of transactions that have
+/// come in since it was opened, as kept in [`DisputeAges`]. Disputes already open in a loaded
+/// state carry on aging from where the run that saved it left off.
+pub struct Expiry<'a> {
+    /// Transactions a dispute may stay open for.
+    after: u64,
+    /// Disputes in the order they were opened, with the count they were opened at.
+    opened: VecDeque<(u64, TransactionId)>,
+    lapsed: u64,
+    writer: Option<(csv::Writer<Box<dyn io::Write + 'a>>, Rounding)>,
+}
+
+impl<'a> Expiry<'a> {
+    pub fn new(after: u64) -> Self {
+        Self {
+            after,
+            opened: VecDeque::new(),
+            lapsed: 0,
+            writer: None,
+        }
+    }
+
+    /// Writes every dispute that lapses.
+    pub fn with_writer(mut self, writer: impl io::Write + 'a, rounding: Rounding) -> Self {
+        self.writer = Some((csv::Writer::from_writer(Box::new(writer)), rounding));
+        self
+    }
+
+    /// Starts watching a dispute that was opened when `opened_at` transactions had come in.
+    /// Disputes must be opened in order.
+    pub fn opened(&mut self, tx: TransactionId, opened_at: u64) {
+        self.opened.push_back((opened_at, tx));
+    }
+
+    /// Takes the disputes that have now been open too long by `ages`. Disputes that have been
+    /// closed since, or opened again later, are skipped.
+    pub fn due(&mut self, ages: &DisputeAges) -> Vec<TransactionId> {
+        let mut due = Vec::new();
+
+        while let Some(&(opened_at, tx)) = self.opened.front() {
+            if ages.seen() - opened_at <= self.after {
+                break;
+            }
+
+            _ = self.opened.pop_front();
+
+            if ages.opened_at(tx) == Some(opened_at) {
+                due.push(tx);
+            }
+        }
+
+        due
+    }
+
+    /// Reports a dispute that was open too long and has been resolved.
+    pub fn lapse(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+    ) -> Result<(), csv::Error> {
+        self.lapsed += 1;
+
+        let Some((writer, rounding)) = &mut self.writer else {
+            return Ok(());
+        };
+
+        writer.serialize(LapsedRecord {
+            client,
+            tx,
+            amount: rounding.round(amount),
+        })
+    }
+
+    /// Reports how many disputes lapsed, and flushes the writer.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.lapsed > 0 {
+            eprintln!("warning: {} disputes lapsed", self.lapsed);
+        }
+
+        self.flush()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some((writer, _)) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::io;
+
+use crate::{
+    cli::{ExplainArgs, ExplainSource},
+    diffs, input_paths, load_initial, process, replay_processor,
+};
+
+/// Replays transaction files and prints every transaction for one client, in the order it was
+/// applied, with the client's balances after each.
+///
+/// Rejected transactions are included along with the reason, since they often explain a balance
+/// as much as the applied ones do.
+///
+/// The input is replayed with the same options as processing it, starting from the same saved
+/// state if one is given.
+///
+/// Given a diff log instead, this writes the client's steps from the log, which also show how
+/// each transaction left the deposit it refers to.
+pub fn run(args: &ExplainArgs) -> Result<(), anyhow::Error> {
+    let replay = match &args.source {
+        ExplainSource::Replay(replay) => replay,
+        ExplainSource::Diffs(path) => {
+            return diffs::explain(path, args.client, io::stdout().lock());
+        }
+    };
+
+    let processor = replay_processor(replay, load_initial(replay)?)?
+        .with_trace(args.client, io::stdout().lock());
+
+    process(&input_paths(replay)?, &replay.read, processor)?;
+
+    Ok(())
+}
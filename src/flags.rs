@@ -0,0 +1,198 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{config::FlagConfig, ClientId, TransactionId, TransactionRecord, TransactionType};
+
+/// How many small deposits in a row make a large withdrawal after them suspicious, unless set.
+pub const DEFAULT_SMALL_DEPOSITS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagRule {
+    /// A withdrawal of at least the amount of the client's deposit just before it.
+    DepositWithdrawn,
+    /// A dispute soon after the deposit it disputes.
+    RapidDispute,
+    /// A large withdrawal after a run of small deposits.
+    Structuring,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct FlagRecord {
+    rule: FlagRule,
+    client: ClientId,
+    tx: TransactionId,
+    r#type: TransactionType,
+    amount: Option<Decimal>,
+}
+
+/// Which suspicious patterns to look for. Each is off unless set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heuristics {
+    pub deposit_withdrawn: bool,
+    /// Flag disputes within this many transactions of their deposit.
+    pub rapid_dispute: Option<u64>,
+    /// Deposits of no more than this count as small.
+    pub small_deposit: Option<Decimal>,
+    pub small_deposits: usize,
+    /// Flag withdrawals of at least this after enough small deposits in a row.
+    pub large_withdrawal: Option<Decimal>,
+    /// Lock the account of a client whose transaction is flagged.
+    pub lock: bool,
+}
+
+impl Heuristics {
+    pub fn enabled(&self) -> bool {
+        self.deposit_withdrawn
+            || self.rapid_dispute.is_some()
+            || (self.small_deposit.is_some() && self.large_withdrawal.is_some())
+    }
+}
+
+impl From<FlagConfig> for Heuristics {
+    fn from(config: FlagConfig) -> Self {
+        Self {
+            deposit_withdrawn: config.deposit_withdrawn.unwrap_or(false),
+            rapid_dispute: config.rapid_dispute,
+            small_deposit: config.small_deposit,
+            small_deposits: config.small_deposits.unwrap_or(DEFAULT_SMALL_DEPOSITS),
+            large_withdrawal: config.large_withdrawal,
+            lock: config.lock.unwrap_or(false),
+        }
+    }
+}
+
+impl Default for Heuristics {
+    fn default() -> Self {
+        Self {
+            deposit_withdrawn: false,
+            rapid_dispute: None,
+            small_deposit: None,
+            small_deposits: DEFAULT_SMALL_DEPOSITS,
+            large_withdrawal: None,
+            lock: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Recent {
+    /// The amount of the client's last transaction, if it was a deposit.
+    deposit: Option<Decimal>,
+    /// How many small deposits the client has made in a row.
+    small_deposits: usize,
+}
+
+/// Watches applied transactions for patterns that need a closer look.
+///
+/// Like alerts, flags are only reported; they never stop a transaction from being applied.
+pub struct Flagger<'a> {
+    heuristics: Heuristics,
+    recent: BTreeMap<ClientId, Recent>,
+    /// How many transactions have been applied.
+    applied: u64,
+    /// When each deposit still within the rapid dispute window was applied, oldest first.
+    deposits: VecDeque<(u64, TransactionId)>,
+    deposit_positions: BTreeMap<TransactionId, u64>,
+    writer: csv::Writer<Box<dyn io::Write + 'a>>,
+}
+
+impl<'a> Flagger<'a> {
+    pub fn new(heuristics: Heuristics, writer: impl io::Write + 'a) -> Self {
+        Self {
+            heuristics,
+            recent: BTreeMap::new(),
+            applied: 0,
+            deposits: VecDeque::new(),
+            deposit_positions: BTreeMap::new(),
+            writer: csv::Writer::from_writer(Box::new(writer)),
+        }
+    }
+
+    /// Checks an applied transaction, returning whether any heuristic flagged it.
+    pub fn check(&mut self, transaction: &TransactionRecord) -> Result<bool, csv::Error> {
+        self.applied += 1;
+
+        if let Some(window) = self.heuristics.rapid_dispute {
+            while let Some(&(applied, tx)) = self.deposits.front() {
+                if self.applied - applied <= window {
+                    break;
+                }
+
+                _ = self.deposits.pop_front();
+                _ = self.deposit_positions.remove(&tx);
+            }
+        }
+
+        let heuristics = self.heuristics;
+        let recent = self.recent.entry(transaction.client).or_default();
+        let previous = std::mem::take(recent);
+        let mut rules = Vec::new();
+
+        match (transaction.r#type, transaction.amount) {
+            (TransactionType::Deposit, Some(amount)) => {
+                recent.deposit = Some(amount);
+
+                if heuristics
+                    .small_deposit
+                    .is_some_and(|small| amount <= small)
+                {
+                    recent.small_deposits = previous.small_deposits + 1;
+                }
+
+                if heuristics.rapid_dispute.is_some() {
+                    self.deposits.push_back((self.applied, transaction.tx));
+                    _ = self.deposit_positions.insert(transaction.tx, self.applied);
+                }
+            }
+            (TransactionType::Withdrawal, Some(amount)) => {
+                if heuristics.deposit_withdrawn
+                    && previous.deposit.is_some_and(|deposit| amount >= deposit)
+                {
+                    rules.push(FlagRule::DepositWithdrawn);
+                }
+
+                if heuristics.small_deposit.is_some()
+                    && heuristics
+                        .large_withdrawal
+                        .is_some_and(|large| amount >= large)
+                    && previous.small_deposits >= heuristics.small_deposits
+                {
+                    rules.push(FlagRule::Structuring);
+                }
+            }
+            (TransactionType::Dispute, _)
+                if self.deposit_positions.contains_key(&transaction.tx) =>
+            {
+                rules.push(FlagRule::RapidDispute);
+            }
+            _ => (),
+        }
+
+        for &rule in &rules {
+            self.writer.serialize(FlagRecord {
+                rule,
+                client: transaction.client,
+                tx: transaction.tx,
+                r#type: transaction.r#type,
+                amount: transaction.amount,
+            })?;
+        }
+
+        Ok(!rules.is_empty())
+    }
+
+    /// Whether flagged clients' accounts should be locked.
+    pub fn locks(&self) -> bool {
+        self.heuristics.lock
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
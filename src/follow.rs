@@ -0,0 +1,123 @@
+use std::{
+    convert::Infallible,
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    clients::Clients,
+    input::{ReadOptions, RowParser},
+    output::{self, Rounding},
+    processor::Processor,
+};
+
+/// How long to wait before checking for more rows once the end of the file is reached.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Applies `path` like `tail -f`, rewriting the account report at `report` and flushing the other
+/// outputs after every batch of new rows. This only returns on error.
+///
+/// A row is only read once its line ending has been written, so rows that are still being
+/// appended are never parsed in halves. Rows are parsed as `options` says, as if the file were
+/// read in full.
+pub fn run(
+    path: &Path,
+    options: &ReadOptions,
+    mut processor: Processor,
+    report: &Path,
+    rounding: Rounding,
+    clients: Option<&Clients>,
+) -> Result<Infallible, anyhow::Error> {
+    let file = File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+
+    let mut parser = None;
+    // Lines read in earlier chunks, so that rows are numbered from the start of the file.
+    let mut lines = 0;
+    let mut line = String::new();
+    let mut chunk = String::new();
+
+    loop {
+        // `read_line` appends, so a partial line at the end of the file is kept in `line` and
+        // completed on a later pass.
+        while reader
+            .read_line(&mut line)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            > 0
+        {
+            if !line.ends_with('\n') {
+                break;
+            }
+
+            chunk.push_str(&line);
+            line.clear();
+        }
+
+        if chunk.is_empty() {
+            // Records can't wait forever on a gap that the rest of the file may never fill.
+            if processor.release_expired()? {
+                processor.flush()?;
+                write_report(&processor, report, rounding, clients)?;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .from_reader(chunk.as_bytes());
+
+        for record_res in csv_reader.byte_records() {
+            let mut record =
+                record_res.with_context(|| format!("failed to read {}", path.display()))?;
+
+            let Some(parser) = &mut parser else {
+                parser = Some(RowParser::new(record, options));
+                continue;
+            };
+
+            if let Some(mut position) = record.position().cloned() {
+                _ = position.set_line(position.line() + lines);
+                record.set_position(Some(position));
+            }
+
+            if let Some(transaction) = parser.parse_row(&record, processor.policy(), path)? {
+                processor.push(transaction)?;
+            }
+        }
+
+        lines += csv_reader.position().line() - 1;
+        chunk.clear();
+        processor.flush()?;
+        write_report(&processor, report, rounding, clients)?;
+    }
+}
+
+/// Replaces `report` in one step, so readers never see a half-written report.
+pub fn write_report(
+    processor: &Processor,
+    report: &Path,
+    rounding: Rounding,
+    clients: Option<&Clients>,
+) -> Result<(), anyhow::Error> {
+    let Some(name) = report.file_name() else {
+        bail!("invalid report path: {}", report.display());
+    };
+
+    let mut temp_name = name.to_owned();
+    temp_name.push(".tmp");
+    let temp = report.with_file_name(temp_name);
+
+    let file =
+        File::create(&temp).with_context(|| format!("failed to create {}", temp.display()))?;
+    output::write_accounts(file, processor.engine().accounts(), rounding, clients, None)?;
+
+    fs::rename(&temp, report).with_context(|| format!("failed to replace {}", report.display()))
+}
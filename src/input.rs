@@ -0,0 +1,915 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::{self, FromStr},
+    sync::mpsc::{self, SyncSender},
+    thread,
+};
+
+use anyhow::{bail, Context};
+use memmap2::Mmap;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    policy::{ControlPolicy, Policy, RowPolicy},
+    ClientId, RawClientId, RawTransactionId, TransactionId, TransactionRecord, TransactionType,
+};
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, thiserror::Error)]
+enum ReadError {
+    #[error(transparent)]
+    Csv(csv::Error),
+
+    #[error("unknown transaction type (line: {line}, type: {name})")]
+    UnknownType { line: u64, name: String },
+
+    #[error("invalid amount (line: {line}, amount: {amount})")]
+    InvalidAmount { line: u64, amount: String },
+
+    #[error(
+        "{column} out of range (line: {line}, {column}: {value}, max: {max}){}",
+        WIDE_IDS_HINT
+    )]
+    IdOutOfRange {
+        line: u64,
+        column: &'static str,
+        value: String,
+        max: u128,
+    },
+
+    #[error("missing control record")]
+    MissingControl,
+
+    #[error("invalid control record (line: {line})")]
+    InvalidControl { line: u64 },
+
+    #[error("rows after control record (line: {line})")]
+    AfterControl { line: u64 },
+
+    #[error(
+        "control record doesn't match \
+        (rows: {}, expected rows: {}, sum: {}, expected sum: {})",
+        actual.rows,
+        expected.rows,
+        actual.sum,
+        expected.sum
+    )]
+    ControlMismatch {
+        expected: ControlTotals,
+        actual: ControlTotals,
+    },
+}
+
+impl ReadError {
+    /// Whether the error only affects one row, so that reading can carry on past it.
+    fn is_row_error(&self) -> bool {
+        match self {
+            Self::Csv(err) => !err.is_io_error(),
+            Self::UnknownType { .. } | Self::InvalidAmount { .. } | Self::IdOutOfRange { .. } => {
+                true
+            }
+            Self::MissingControl
+            | Self::InvalidControl { .. }
+            | Self::AfterControl { .. }
+            | Self::ControlMismatch { .. } => false,
+        }
+    }
+}
+
+/// Points builds with narrow IDs at the feature that widens them.
+const WIDE_IDS_HINT: &str = if cfg!(feature = "wide-ids") {
+    ""
+} else {
+    "; build with the wide-ids feature to accept 64-bit IDs"
+};
+
+/// The figures a control record vouches for: how many rows come before it, and the sum of their
+/// amounts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct ControlTotals {
+    rows: u64,
+    sum: Decimal,
+}
+
+/// How input files are read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadOptions {
+    /// Memory-map files and parse them in place rather than reading them through a buffer.
+    pub mmap: bool,
+    /// Bytes to read from a file at a time, when it isn't memory-mapped.
+    pub buffer_capacity: usize,
+    /// Accept amounts with currency symbols, thousands separators, or parentheses for negatives.
+    pub lenient_amounts: bool,
+    pub decimal_separator: DecimalSeparator,
+    /// Other names for types, on top of the usual names in any case.
+    pub type_aliases: TypeAliases,
+    /// Expect each file to end with a row of type `control`, whose `tx` is the number of rows
+    /// before it and whose `amount` is the sum of their amounts.
+    pub control_trailer: bool,
+}
+
+impl ReadOptions {
+    /// Whether amounts need rewriting before they can be parsed.
+    fn rewrites_amounts(&self) -> bool {
+        self.lenient_amounts || self.decimal_separator != DecimalSeparator::Point
+    }
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            mmap: false,
+            buffer_capacity: 64 * 1024,
+            lenient_amounts: false,
+            decimal_separator: DecimalSeparator::Point,
+            type_aliases: TypeAliases::default(),
+            control_trailer: false,
+        }
+    }
+}
+
+/// The character between the whole and fractional parts of an amount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecimalSeparator {
+    /// `12.3456`
+    Point,
+    /// `12,3456`, where thousands are separated by points if at all.
+    Comma,
+}
+
+impl DecimalSeparator {
+    fn byte(self) -> u8 {
+        match self {
+            Self::Point => b'.',
+            Self::Comma => b',',
+        }
+    }
+}
+
+impl FromStr for DecimalSeparator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "point" => Ok(Self::Point),
+            "comma" => Ok(Self::Comma),
+            _ => bail!("expected one of: point, comma"),
+        }
+    }
+}
+
+/// Other names for transaction types, such as `credit` for deposits. Names match in any case.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "BTreeMap<String, TransactionType>")]
+pub struct TypeAliases(BTreeMap<String, TransactionType>);
+
+impl From<BTreeMap<String, TransactionType>> for TypeAliases {
+    fn from(aliases: BTreeMap<String, TransactionType>) -> Self {
+        Self(
+            aliases
+                .into_iter()
+                .map(|(alias, r#type)| (alias.to_ascii_lowercase(), r#type))
+                .collect(),
+        )
+    }
+}
+
+/// Parses a comma-separated list of `alias=type` pairs.
+impl FromStr for TypeAliases {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut aliases = BTreeMap::new();
+        for pair in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let Some((alias, r#type)) = pair.split_once('=') else {
+                bail!("expected alias=type: {pair}");
+            };
+            _ = aliases.insert(alias.trim().to_owned(), r#type.trim().parse()?);
+        }
+        Ok(Self::from(aliases))
+    }
+}
+
+/// How each type is spelled in the input.
+const TYPE_NAMES: [&[u8]; 5] = [
+    b"deposit",
+    b"withdrawal",
+    b"dispute",
+    b"resolve",
+    b"chargeback",
+];
+
+/// The columns that [`parse_fast`] can read, in order.
+const FAST_HEADERS: [&[u8]; 4] = [b"type", b"client", b"tx", b"amount"];
+
+/// Calls `f` with every record from `paths`, in file order.
+///
+/// Each file is parsed on its own thread, with as many files being parsed at once as there are
+/// CPUs, ahead of the file whose records are being handed to `f`. Records are always handed to
+/// `f` one file at a time in the order the files were given, so the result does not depend on
+/// thread scheduling.
+///
+/// Rows that fail to parse or have an unknown type are fatal, unless `policy` says to skip them.
+/// Skipped rows with unknown types are counted for each file.
+///
+/// A control record that doesn't match the rows that were read before it is fatal, unless
+/// `policy` says to warn. Rows that were skipped count as not read.
+pub fn for_each_record(
+    paths: &[PathBuf],
+    options: &ReadOptions,
+    policy: Policy,
+    mut f: impl FnMut(TransactionRecord) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    let workers = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+    thread::scope(|scope| {
+        let spawn = |index: usize| {
+            let path = &paths[index];
+            let (sender, receiver) = mpsc::sync_channel(CHANNEL_CAPACITY);
+            _ = scope.spawn(move || read_file(path, options, &sender));
+            receiver
+        };
+
+        let mut pending = 0..paths.len();
+        let mut receivers: VecDeque<_> = pending.by_ref().take(workers).map(spawn).collect();
+
+        for path in paths {
+            let receiver = receivers
+                .pop_front()
+                .expect("every file is spawned before it is reached");
+
+            let mut unknown_types = 0_u64;
+
+            for record_res in receiver {
+                let Some(record) = check(record_res, policy, path, &mut unknown_types)? else {
+                    continue;
+                };
+
+                f(record)?;
+            }
+
+            // The file's thread has finished with its channel, so another can take its place.
+            receivers.extend(pending.next().map(spawn));
+
+            if unknown_types > 0 {
+                eprintln!(
+                    "warning: skipped {unknown_types} rows with unknown types in {}",
+                    path.display()
+                );
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Passes on a record that parsed, or decides what to do with one that didn't as `policy` says:
+/// returns `None` for a row to skip, after warning about it, or fails.
+fn check(
+    record_res: Result<TransactionRecord, ReadError>,
+    policy: Policy,
+    path: &Path,
+    unknown_types: &mut u64,
+) -> Result<Option<TransactionRecord>, anyhow::Error> {
+    match record_res {
+        Ok(record) => Ok(Some(record)),
+        Err(ReadError::UnknownType { line, name }) if policy.unknown_type == RowPolicy::Skip => {
+            eprintln!(
+                "warning: skipping unknown type in {} (line: {line}, type: {name})",
+                path.display()
+            );
+            *unknown_types += 1;
+            Ok(None)
+        }
+        Err(err @ ReadError::ControlMismatch { .. })
+            if policy.control_mismatch == ControlPolicy::Warn =>
+        {
+            eprintln!("warning: {err} in {}", path.display());
+            Ok(None)
+        }
+        Err(err) if policy.malformed_row == RowPolicy::Skip && err.is_row_error() => {
+            eprintln!(
+                "warning: skipping malformed row in {}: {err}",
+                path.display()
+            );
+            Ok(None)
+        }
+        Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+fn read_file(
+    path: &Path,
+    options: &ReadOptions,
+    sender: &SyncSender<Result<TransactionRecord, ReadError>>,
+) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            _ = sender.send(Err(ReadError::Csv(err.into())));
+            return;
+        }
+    };
+
+    if !options.mmap {
+        read(file, options.buffer_capacity, options, sender);
+        return;
+    }
+
+    // SAFETY: The map is only valid while nothing else changes the file. Input files are
+    // expected to be left alone while they are processed, as with any other way of reading them.
+    match unsafe { Mmap::map(&file) } {
+        // The whole file is already in memory, so there is nothing to gain from a bigger buffer.
+        Ok(mapped) => read(
+            &mapped[..],
+            ReadOptions::default().buffer_capacity,
+            options,
+            sender,
+        ),
+        Err(err) => _ = sender.send(Err(ReadError::Csv(err.into()))),
+    }
+}
+
+/// Where the columns that may need rewriting are, if they need rewriting at all, and where the
+/// IDs are.
+#[derive(Clone, Copy, Debug)]
+struct Columns {
+    r#type: Option<usize>,
+    amount: Option<usize>,
+    client: Option<usize>,
+    tx: Option<usize>,
+}
+
+/// Reads a file a row of bytes at a time.
+///
+/// Types and amounts are rewritten into the forms serde reads as `options` says. Files with
+/// exactly the standard columns are parsed without going through serde for each row.
+///
+/// If the file should end with a control record, that record is checked against the rows that
+/// parsed once the file is finished, instead of being sent on.
+fn read(
+    reader: impl io::Read,
+    buffer_capacity: usize,
+    options: &ReadOptions,
+    sender: &SyncSender<Result<TransactionRecord, ReadError>>,
+) {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .buffer_capacity(buffer_capacity)
+        .from_reader(reader);
+
+    let headers = match csv_reader.byte_headers() {
+        Ok(headers) => headers.clone(),
+        Err(err) => {
+            _ = sender.send(Err(ReadError::Csv(err)));
+            return;
+        }
+    };
+
+    let mut parser = RowParser::new(headers, options);
+
+    let mut raw = csv::ByteRecord::new();
+    let mut control = None;
+    let mut actual = ControlTotals::default();
+
+    loop {
+        let record_res = match csv_reader.read_byte_record(&mut raw) {
+            Ok(false) if options.control_trailer => {
+                match control {
+                    None => _ = sender.send(Err(ReadError::MissingControl)),
+                    Some(expected) if expected != actual => {
+                        _ = sender.send(Err(ReadError::ControlMismatch { expected, actual }));
+                    }
+                    Some(_) => (),
+                }
+
+                return;
+            }
+            Ok(false) => return,
+            Ok(true) if control.is_some() => Err(ReadError::AfterControl { line: line(&raw) }),
+            Ok(true) if options.control_trailer && is_control(&raw, parser.columns) => {
+                match parse_control(&raw, &parser.headers, options) {
+                    Some(totals) => {
+                        control = Some(totals);
+                        continue;
+                    }
+                    None => Err(ReadError::InvalidControl { line: line(&raw) }),
+                }
+            }
+            Ok(true) => parser.parse(&raw),
+            Err(err) => Err(ReadError::Csv(err)),
+        };
+
+        if let Ok(record) = &record_res {
+            actual.rows += 1;
+            actual.sum = actual.sum.saturating_add(record.amount.unwrap_or_default());
+        }
+
+        if !send(sender, record_res) {
+            return;
+        }
+    }
+}
+
+/// Parses rows against a header row, the same way for rows read from a whole file and for rows
+/// read as they are appended.
+pub struct RowParser<'a> {
+    options: &'a ReadOptions,
+    headers: csv::ByteRecord,
+    columns: Columns,
+    /// Whether the headers are exactly the standard columns, so [`parse_fast`] can read rows.
+    fast: bool,
+    /// Where rows are rewritten, kept to reuse its allocation.
+    normalized: csv::ByteRecord,
+}
+
+impl<'a> RowParser<'a> {
+    pub fn new(headers: csv::ByteRecord, options: &'a ReadOptions) -> Self {
+        let columns = Columns {
+            r#type: headers.iter().position(|header| header == b"type"),
+            amount: headers
+                .iter()
+                .position(|header| header == b"amount")
+                .filter(|_| options.rewrites_amounts()),
+            client: headers.iter().position(|header| header == b"client"),
+            tx: headers.iter().position(|header| header == b"tx"),
+        };
+
+        Self {
+            options,
+            fast: headers.iter().eq(FAST_HEADERS),
+            headers,
+            columns,
+            normalized: csv::ByteRecord::new(),
+        }
+    }
+
+    /// Parses `raw`, or decides what to do with it as `policy` says if it doesn't parse:
+    /// returns `None` for a row to skip, after warning about it, or fails. `path` is only for
+    /// messages.
+    pub fn parse_row(
+        &mut self,
+        raw: &csv::ByteRecord,
+        policy: Policy,
+        path: &Path,
+    ) -> Result<Option<TransactionRecord>, anyhow::Error> {
+        check(self.parse(raw), policy, path, &mut 0)
+    }
+
+    fn parse(&mut self, raw: &csv::ByteRecord) -> Result<TransactionRecord, ReadError> {
+        let rewritten = normalize(raw, self.columns, self.options, &mut self.normalized)?;
+        let record = if rewritten { &self.normalized } else { raw };
+
+        // Anything unusual goes through serde after all, so it fails or succeeds the same way.
+        self.fast
+            .then(|| parse_fast(record))
+            .flatten()
+            .map_or_else(|| record.deserialize(Some(&self.headers)), Ok)
+            .map_err(|err| id_out_of_range(raw, self.columns).unwrap_or(ReadError::Csv(err)))
+    }
+}
+
+fn line(record: &csv::ByteRecord) -> u64 {
+    record.position().map_or(0, csv::Position::line)
+}
+
+/// Explains a row that failed to parse because one of its IDs is too large to hold, if that's
+/// why it failed, rather than leaving it to serde's message about the target type.
+fn id_out_of_range(record: &csv::ByteRecord, columns: Columns) -> Option<ReadError> {
+    let check = |column: &'static str, position: Option<usize>, max: u128| {
+        let value = str::from_utf8(record.get(position?)?).ok()?;
+        let too_large = !value.is_empty()
+            && value.bytes().all(|byte| byte.is_ascii_digit())
+            && value.parse::<u128>().map_or(true, |value| value > max);
+
+        too_large.then(|| ReadError::IdOutOfRange {
+            line: line(record),
+            column,
+            value: value.to_owned(),
+            max,
+        })
+    };
+
+    check("client", columns.client, u128::from(RawClientId::MAX))
+        .or_else(|| check("tx", columns.tx, u128::from(RawTransactionId::MAX)))
+}
+
+fn is_control(record: &csv::ByteRecord, columns: Columns) -> bool {
+    columns
+        .r#type
+        .and_then(|column| record.get(column))
+        .is_some_and(|field| field.eq_ignore_ascii_case(b"control"))
+}
+
+/// Reads the row count from the `tx` column of a control record and the amount sum from its
+/// `amount` column, which is written like any other amount.
+fn parse_control(
+    record: &csv::ByteRecord,
+    headers: &csv::ByteRecord,
+    options: &ReadOptions,
+) -> Option<ControlTotals> {
+    let field = |name: &[u8]| record.get(headers.iter().position(|header| header == name)?);
+
+    let amount = field(b"amount")?;
+    let amount = if options.lenient_amounts {
+        lenient_amount(amount, options.decimal_separator)?
+    } else {
+        with_decimal_point(amount, options.decimal_separator)
+    };
+
+    Some(ControlTotals {
+        rows: parse_field(field(b"tx")?)?,
+        sum: parse_field(&amount)?,
+    })
+}
+
+/// Parses a row of the standard columns, or returns `None` if it isn't plainly valid.
+fn parse_fast(record: &csv::ByteRecord) -> Option<TransactionRecord> {
+    let r#type = match record.get(0)? {
+        b"deposit" => TransactionType::Deposit,
+        b"withdrawal" => TransactionType::Withdrawal,
+        b"dispute" => TransactionType::Dispute,
+        b"resolve" => TransactionType::Resolve,
+        b"chargeback" => TransactionType::Chargeback,
+        _ => return None,
+    };
+
+    let amount = match record.get(3)? {
+        b"" => None,
+        amount => Some(parse_amount(amount)?),
+    };
+
+    Some(TransactionRecord {
+        r#type,
+        client: ClientId(parse_field(record.get(1)?)?),
+        tx: TransactionId(parse_field(record.get(2)?)?),
+        amount,
+        seq: None,
+        wallet: None,
+    })
+}
+
+/// Copies `record` into `normalized` with its type and amount rewritten, returning `false` and
+/// leaving `normalized` alone if there is nothing to rewrite.
+///
+/// Fails if the type isn't one, or if a lenient amount isn't an amount.
+fn normalize(
+    record: &csv::ByteRecord,
+    columns: Columns,
+    options: &ReadOptions,
+    normalized: &mut csv::ByteRecord,
+) -> Result<bool, ReadError> {
+    let r#type = match columns.r#type.and_then(|column| record.get(column)) {
+        Some(field) => {
+            normalize_type(field, &options.type_aliases).map_err(|name| ReadError::UnknownType {
+                line: line(record),
+                name,
+            })?
+        }
+        None => None,
+    };
+
+    if r#type.is_none() && columns.amount.is_none() {
+        return Ok(false);
+    }
+
+    normalized.clear();
+
+    for (index, field) in record.iter().enumerate() {
+        let rewritten = if Some(index) == columns.amount {
+            if options.lenient_amounts {
+                let amount = lenient_amount(field, options.decimal_separator).ok_or_else(|| {
+                    ReadError::InvalidAmount {
+                        line: line(record),
+                        amount: String::from_utf8_lossy(field).into_owned(),
+                    }
+                })?;
+                Some(amount)
+            } else {
+                Some(with_decimal_point(field, options.decimal_separator))
+            }
+        } else if Some(index) == columns.r#type {
+            r#type.map(|r#type| r#type.to_string().into_bytes())
+        } else {
+            None
+        };
+
+        match rewritten {
+            Some(rewritten) => normalized.push_field(&rewritten),
+            None => normalized.push_field(field),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Reads a type spelled in any case or as one of `aliases`, or returns `None` if it is already
+/// spelled as serde expects. Fails with the name if it isn't a type at all.
+fn normalize_type(field: &[u8], aliases: &TypeAliases) -> Result<Option<TransactionType>, String> {
+    if TYPE_NAMES.contains(&field) {
+        return Ok(None);
+    }
+
+    let name = String::from_utf8_lossy(field).to_ascii_lowercase();
+
+    match aliases.0.get(&name) {
+        Some(&r#type) => Ok(Some(r#type)),
+        None => name.parse().map(Some).map_err(|_| name),
+    }
+}
+
+/// Currency symbols that may come before a lenient amount.
+const CURRENCY_SYMBOLS: [&str; 4] = ["$", "£", "€", "¥"];
+
+/// Rewrites an amount like `$1,234.56` or `(12.50)` as a plain decimal, by reading parentheses as
+/// a minus sign, dropping a leading currency symbol, and dropping thousands separators between
+/// groups of three digits. An empty field is left empty.
+///
+/// Returns `None` if anything else is in the way, so that a mistyped amount is rejected rather
+/// than read as some other number.
+fn lenient_amount(field: &[u8], separator: DecimalSeparator) -> Option<Vec<u8>> {
+    let field = field.trim_ascii();
+
+    if field.is_empty() {
+        return Some(Vec::new());
+    }
+
+    let (negative, field) = match field.strip_prefix(b"(").and_then(|f| f.strip_suffix(b")")) {
+        Some(inner) => (true, inner),
+        None => (false, field),
+    };
+
+    let (minus, field) = match field.strip_prefix(b"-") {
+        Some(rest) => (true, rest),
+        None => (false, field),
+    };
+
+    let field = CURRENCY_SYMBOLS
+        .iter()
+        .find_map(|symbol| field.strip_prefix(symbol.as_bytes()))
+        .unwrap_or(field);
+
+    let (whole, fraction) = match field.iter().position(|&b| b == separator.byte()) {
+        Some(at) => (&field[..at], Some(&field[at + 1..])),
+        None => (field, None),
+    };
+
+    let thousands = match separator {
+        DecimalSeparator::Point => b',',
+        DecimalSeparator::Comma => b'.',
+    };
+
+    let mut groups = whole.split(|&b| b == thousands);
+    let first = groups.next().unwrap_or_default();
+    let grouped = whole.contains(&thousands);
+
+    let digits = |part: &[u8]| part.iter().all(u8::is_ascii_digit);
+
+    if negative && minus
+        || !digits(first)
+        || grouped && !(1..=3).contains(&first.len())
+        || !groups.all(|group| group.len() == 3 && digits(group))
+        || fraction.is_some_and(|fraction| fraction.is_empty() || !digits(fraction))
+        || whole.is_empty() && fraction.is_none()
+    {
+        return None;
+    }
+
+    let mut amount = Vec::with_capacity(field.len() + 1);
+
+    if negative || minus {
+        amount.push(b'-');
+    }
+
+    amount.extend(whole.iter().filter(|&&b| b != thousands));
+
+    if let Some(fraction) = fraction {
+        amount.push(b'.');
+        amount.extend_from_slice(fraction);
+    }
+
+    Some(amount)
+}
+
+fn with_decimal_point(field: &[u8], separator: DecimalSeparator) -> Vec<u8> {
+    field
+        .iter()
+        .map(|&b| if b == separator.byte() { b'.' } else { b })
+        .collect()
+}
+
+fn parse_field<T: FromStr>(field: &[u8]) -> Option<T> {
+    str::from_utf8(field).ok()?.parse().ok()
+}
+
+/// Parses an amount the same way serde does from CSV, which reads a field that looks like a
+/// number as an integer or `f64` before converting it to a [`Decimal`].
+fn parse_amount(field: &[u8]) -> Option<Decimal> {
+    if let Some(n) = parse_field::<u64>(field) {
+        return Some(Decimal::from(n));
+    }
+
+    if let Some(n) = parse_field::<i64>(field) {
+        return Some(Decimal::from(n));
+    }
+
+    // Leave anything else serde might read differently, such as exponents, to serde.
+    if !field.contains(&b'.')
+        || !field
+            .iter()
+            .all(|&b| b.is_ascii_digit() || b"-.".contains(&b))
+    {
+        return None;
+    }
+
+    parse_field::<f64>(field)?.to_string().parse().ok()
+}
+
+/// Sends a parsed row, returning whether reading should go on.
+fn send(
+    sender: &SyncSender<Result<TransactionRecord, ReadError>>,
+    record_res: Result<TransactionRecord, ReadError>,
+) -> bool {
+    // The reader can carry on past a row that fails to parse, but not past an I/O error.
+    let stops = record_res.as_ref().is_err_and(|err| !err.is_row_error());
+
+    // A failed send means the consumer has stopped early.
+    sender.send(record_res).is_ok() && !stops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_str(input: &str, options: &ReadOptions) -> Vec<Result<TransactionRecord, ReadError>> {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        read(input.as_bytes(), options.buffer_capacity, options, &sender);
+        drop(sender);
+        receiver.into_iter().collect()
+    }
+
+    fn read_tx(tx: &str) -> Result<TransactionRecord, ReadError> {
+        let input = format!("type,client,tx,amount\ndeposit,1,{tx},1.0\n");
+        read_str(&input, &ReadOptions::default()).remove(0)
+    }
+
+    #[test]
+    fn reads_largest_narrow_id() {
+        let record = read_tx(&u32::MAX.to_string()).unwrap();
+        assert_eq!(u128::from(record.tx.0), u128::from(u32::MAX));
+    }
+
+    #[cfg(not(feature = "wide-ids"))]
+    #[test]
+    fn rejects_id_past_narrow_range() {
+        let value = (u64::from(u32::MAX) + 1).to_string();
+
+        match read_tx(&value) {
+            Err(ReadError::IdOutOfRange {
+                line,
+                column,
+                value: found,
+                max,
+            }) => {
+                assert_eq!(line, 2);
+                assert_eq!(column, "tx");
+                assert_eq!(found, value);
+                assert_eq!(max, u128::from(u32::MAX));
+            }
+            other => panic!("expected IdOutOfRange, got {other:?}"),
+        }
+    }
+
+    #[cfg(not(feature = "wide-ids"))]
+    #[test]
+    fn rejects_client_past_narrow_range() {
+        let input = "type,client,tx,amount\ndeposit,65536,1,1.0\n";
+
+        assert!(matches!(
+            read_str(input, &ReadOptions::default()).remove(0),
+            Err(ReadError::IdOutOfRange {
+                column: "client",
+                ..
+            })
+        ));
+    }
+
+    #[cfg(feature = "wide-ids")]
+    #[test]
+    fn reads_id_past_narrow_range() {
+        let record = read_tx(&(u64::from(u32::MAX) + 1).to_string()).unwrap();
+        assert_eq!(record.tx.0, u64::from(u32::MAX) + 1);
+    }
+
+    #[cfg(feature = "wide-ids")]
+    #[test]
+    fn rejects_id_past_wide_range() {
+        let value = (u128::from(u64::MAX) + 1).to_string();
+
+        assert!(matches!(
+            read_tx(&value),
+            Err(ReadError::IdOutOfRange { column: "tx", max, .. }) if max == u128::from(u64::MAX)
+        ));
+    }
+
+    #[test]
+    fn negative_id_is_not_out_of_range() {
+        assert!(matches!(read_tx("-1"), Err(ReadError::Csv(_))));
+    }
+
+    fn lenient(amount: &str, separator: DecimalSeparator) -> Option<String> {
+        lenient_amount(amount.as_bytes(), separator)
+            .map(|amount| String::from_utf8(amount).unwrap())
+    }
+
+    #[test]
+    fn reads_lenient_amounts() {
+        let point = |amount| lenient(amount, DecimalSeparator::Point);
+
+        assert_eq!(point("$1,234.56").as_deref(), Some("1234.56"));
+        assert_eq!(point("(12.50)").as_deref(), Some("-12.50"));
+        assert_eq!(point("-€1,000").as_deref(), Some("-1000"));
+        assert_eq!(point(" 1234567.8 ").as_deref(), Some("1234567.8"));
+        assert_eq!(point("").as_deref(), Some(""));
+        assert_eq!(
+            lenient("1.234,5", DecimalSeparator::Comma).as_deref(),
+            Some("1234.5")
+        );
+    }
+
+    #[test]
+    fn rejects_misplaced_thousands_separators() {
+        for amount in ["1,23.4", "1234,567", "12,34,567", ",123", "1,", "1,2345"] {
+            assert_eq!(lenient(amount, DecimalSeparator::Point), None, "{amount}");
+        }
+    }
+
+    #[test]
+    fn rejects_stray_characters_in_amounts() {
+        for amount in [
+            "12x.5", "1.2.3", "1 000", "USD 5", "-(5)", "$", "5.", "1.5-",
+        ] {
+            assert_eq!(lenient(amount, DecimalSeparator::Point), None, "{amount}");
+        }
+    }
+
+    #[test]
+    fn reports_invalid_lenient_amounts() {
+        let options = ReadOptions {
+            lenient_amounts: true,
+            ..ReadOptions::default()
+        };
+        let input = "type,client,tx,amount\ndeposit,1,1,12o.5\ndeposit,1,2,\"$1,000\"\n";
+        let mut records = read_str(input, &options).into_iter();
+
+        assert!(matches!(
+            records.next(),
+            Some(Err(ReadError::InvalidAmount { line: 2, ref amount })) if amount == "12o.5"
+        ));
+        assert_eq!(
+            records.next().unwrap().unwrap().amount,
+            Some(Decimal::from(1000))
+        );
+    }
+
+    #[test]
+    fn reads_more_files_than_workers_in_order() {
+        let dir = std::env::temp_dir().join(format!("spork-input-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let workers = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let paths: Vec<_> = (1..=u16::try_from(workers * 2 + 1).unwrap())
+            .map(|id| {
+                let path = dir.join(format!("{id}.csv"));
+                let input = format!("type,client,tx,amount\ndeposit,1,{id},1.0\n");
+                std::fs::write(&path, input).unwrap();
+                path
+            })
+            .collect();
+
+        let mut ids = Vec::new();
+        let res = for_each_record(
+            &paths,
+            &ReadOptions::default(),
+            Policy::from(crate::policy::Preset::default()),
+            |record| {
+                ids.push(record.tx);
+                Ok(())
+            },
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        res.unwrap();
+
+        let expected: Vec<_> = (1..=u16::try_from(paths.len()).unwrap())
+            .map(|id| TransactionId(id.into()))
+            .collect();
+        assert_eq!(ids, expected);
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::Context;
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{engine::Engine, TransactionId, TransactionRecord, TransactionType};
+
+/// Decimal places interest is rounded to, matching the precision of the input.
+const INTEREST_SCALE: u32 = 4;
+
+/// Credits every unlocked account with interest at `rate` on its available balance.
+pub fn accrue(engine: &mut Engine, rate: Decimal) -> Result<(), anyhow::Error> {
+    for credit in credits(engine, rate)? {
+        engine.deposit(credit.client, credit.tx, credit.amount.unwrap_or_default())?;
+    }
+
+    Ok(())
+}
+
+/// The deposits that credit every unlocked account with interest at `rate` on its available
+/// balance, for applying like any other transaction.
+///
+/// Credits are cut down to fit under each account's balance limit, and accounts already at their
+/// limit get nothing.
+///
+/// Each credit is an ordinary deposit, so it can be looked up and disputed like any other. They
+/// are numbered on from the highest deposit or withdrawal ID in the engine, skipping any that are
+/// taken.
+pub fn credits(engine: &Engine, rate: Decimal) -> Result<Vec<TransactionRecord>, anyhow::Error> {
+    let credits: Vec<_> = engine
+        .accounts()
+        .filter(|(_, account)| !account.locked())
+        .map(|(&client, account)| {
+            let mut amount = (account.available() * rate).round_dp(INTEREST_SCALE);
+
+            if let Some(limit) = engine.balance_limit(client) {
+                let room = limit
+                    .checked_sub(account.total())
+                    .unwrap_or(Decimal::MAX)
+                    .round_dp_with_strategy(INTEREST_SCALE, RoundingStrategy::ToZero);
+                amount = amount.min(room);
+            }
+
+            (client, amount)
+        })
+        .filter(|&(_, amount)| amount > Decimal::ZERO)
+        .collect();
+
+    let mut next = engine.last_id().map_or(Some(1), |tx| tx.0.checked_add(1));
+
+    credits
+        .into_iter()
+        .map(|(client, amount)| {
+            let tx = loop {
+                let tx = next.context("ran out of transaction IDs for interest")?;
+                next = tx.checked_add(1);
+
+                if !engine.is_known_id(TransactionId(tx)) {
+                    break TransactionId(tx);
+                }
+            };
+
+            Ok(TransactionRecord {
+                r#type: TransactionType::Deposit,
+                client,
+                tx,
+                amount: Some(amount),
+                seq: None,
+                wallet: None,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{engine::BalanceLimits, ClientId};
+
+    #[test]
+    fn numbers_credits_after_withdrawals() {
+        let mut engine = Engine::new();
+        engine
+            .deposit(ClientId(1), TransactionId(1), Decimal::from(100))
+            .unwrap();
+        engine
+            .withdraw(ClientId(1), TransactionId(2), Decimal::from(50))
+            .unwrap();
+
+        accrue(&mut engine, Decimal::new(1, 1)).unwrap();
+
+        let credit = engine.deposit_by_id(TransactionId(3)).unwrap();
+        assert_eq!(credit.amount(), Decimal::from(5));
+        assert_eq!(
+            engine.account(ClientId(1)).unwrap().total(),
+            Decimal::from(55)
+        );
+    }
+
+    #[test]
+    fn keeps_credits_under_balance_limit() {
+        let mut engine = Engine::new().balance_limits(BalanceLimits {
+            default: Some(Decimal::from(104)),
+            clients: BTreeMap::from([(ClientId(2), Decimal::from(100))]),
+        });
+
+        for (client, tx) in [(1, 1), (2, 2)] {
+            engine
+                .deposit(ClientId(client), TransactionId(tx), Decimal::from(100))
+                .unwrap();
+        }
+
+        accrue(&mut engine, Decimal::new(1, 1)).unwrap();
+
+        let total = |client| engine.account(ClientId(client)).unwrap().total();
+        assert_eq!(total(1), Decimal::from(104));
+        assert_eq!(total(2), Decimal::from(100));
+    }
+}
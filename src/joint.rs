@@ -0,0 +1,105 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::{engine, ClientId, TransactionId, TransactionRecord, TransactionType};
+
+#[derive(Clone, Debug, Deserialize)]
+struct JointRecord {
+    client: ClientId,
+    account: ClientId,
+}
+
+/// Lets several clients share one account, as listed in a mapping file.
+///
+/// Every transaction from a listed client is applied to its account instead, but a deposit on a
+/// shared account can only be disputed, resolved, or charged back by the client who made it.
+#[derive(Debug)]
+pub struct JointAccounts {
+    accounts: BTreeMap<ClientId, ClientId>,
+    shared: BTreeSet<ClientId>,
+    /// Who made each deposit on a shared account.
+    origins: BTreeMap<TransactionId, ClientId>,
+}
+
+impl JointAccounts {
+    /// Reads a CSV file with `client` and `account` columns.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let load = || -> Result<Self, anyhow::Error> {
+            let mut accounts = BTreeMap::new();
+
+            for record_res in csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_path(path)?
+                .deserialize()
+            {
+                let record: JointRecord = record_res?;
+                if accounts.insert(record.client, record.account).is_some() {
+                    bail!("client listed more than once: {}", record.client);
+                }
+            }
+
+            Ok(Self {
+                shared: accounts.values().copied().collect(),
+                accounts,
+                origins: BTreeMap::new(),
+            })
+        };
+
+        load().with_context(|| format!("failed to load joint accounts from {}", path.display()))
+    }
+
+    /// Returns `transaction` as it applies to the client's account.
+    ///
+    /// Fails with [`engine::Error::ClientMismatch`] if a client acts on another client's deposit
+    /// on a shared account.
+    pub fn map(&self, transaction: &TransactionRecord) -> Result<TransactionRecord, engine::Error> {
+        if !matches!(
+            transaction.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            if let Some(&origin) = self.origins.get(&transaction.tx) {
+                if origin != transaction.client {
+                    return Err(engine::Error::ClientMismatch {
+                        tx: transaction.tx,
+                        expected: origin,
+                        found: transaction.client,
+                    });
+                }
+            }
+        }
+
+        Ok(TransactionRecord {
+            client: self.account(transaction.client),
+            ..transaction.clone()
+        })
+    }
+
+    /// Who made each deposit on a shared account so far.
+    pub fn origins(&self) -> &BTreeMap<TransactionId, ClientId> {
+        &self.origins
+    }
+
+    /// Carries on from the deposits made on shared accounts in an earlier run, or restores them
+    /// after rolling back.
+    pub fn resume(&mut self, origins: BTreeMap<TransactionId, ClientId>) {
+        self.origins = origins;
+    }
+
+    /// Notes who made a deposit once it has been applied.
+    pub fn applied(&mut self, transaction: &TransactionRecord) {
+        if transaction.r#type == TransactionType::Deposit
+            && self.shared.contains(&self.account(transaction.client))
+        {
+            _ = self.origins.insert(transaction.tx, transaction.client);
+        }
+    }
+
+    fn account(&self, client: ClientId) -> ClientId {
+        self.accounts.get(&client).copied().unwrap_or(client)
+    }
+}
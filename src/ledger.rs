@@ -0,0 +1,83 @@
+use std::io;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{output::Rounding, TransactionId, TransactionRecord, TransactionType};
+
+/// The account that stands for the funds actually held on behalf of every client.
+const OMNIBUS: &str = "omnibus";
+
+/// One side of a transaction. Debits are positive and credits negative, so each transaction's
+/// postings sum to zero.
+#[derive(Clone, Debug, Serialize)]
+struct PostingRecord {
+    tx: TransactionId,
+    r#type: TransactionType,
+    account: String,
+    amount: Decimal,
+}
+
+/// Writes every applied transaction as a pair of balanced postings, for accounting to import.
+///
+/// Deposits and withdrawals move funds between the omnibus account and the client's available
+/// funds, disputes and resolutions between the client's available and held funds, and
+/// chargebacks from the client's held funds back out of the omnibus account.
+pub struct Ledger<'a> {
+    rounding: Rounding,
+    writer: csv::Writer<Box<dyn io::Write + 'a>>,
+}
+
+impl<'a> Ledger<'a> {
+    pub fn new(writer: impl io::Write + 'a, rounding: Rounding) -> Self {
+        Self {
+            rounding,
+            writer: csv::Writer::from_writer(Box::new(writer)),
+        }
+    }
+
+    /// Writes the postings for a transaction that has just been applied. `disputed` is the amount
+    /// of the deposit it disputes, resolves, or charges back.
+    pub fn record(
+        &mut self,
+        transaction: &TransactionRecord,
+        disputed: Option<Decimal>,
+    ) -> Result<(), csv::Error> {
+        let client = transaction.client;
+        let available = format!("client:{client}:available");
+        let held = format!("client:{client}:held");
+
+        let (debit, credit, amount) = match transaction.r#type {
+            TransactionType::Deposit => (
+                OMNIBUS.to_owned(),
+                available,
+                transaction.amount.unwrap_or_default(),
+            ),
+            TransactionType::Withdrawal => (
+                available,
+                OMNIBUS.to_owned(),
+                transaction.amount.unwrap_or_default(),
+            ),
+            TransactionType::Dispute => (available, held, disputed.unwrap_or_default()),
+            TransactionType::Resolve => (held, available, disputed.unwrap_or_default()),
+            TransactionType::Chargeback => (held, OMNIBUS.to_owned(), disputed.unwrap_or_default()),
+        };
+
+        let amount = self.rounding.round(amount);
+
+        for (account, amount) in [(debit, amount), (credit, -amount)] {
+            self.writer.serialize(PostingRecord {
+                tx: transaction.tx,
+                r#type: transaction.r#type,
+                account,
+                amount,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,53 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, PoisonError, RwLock,
+    },
+    time::Duration,
+};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    engine::{Account, Engine},
+    ClientId,
+};
+
+/// A copy of the engine's accounts that other threads can read while processing continues.
+///
+/// Publishing replaces the whole map, so readers always see a consistent view and never block
+/// the engine for longer than it takes to swap a pointer.
+#[derive(Debug, Default)]
+pub struct LiveAccounts {
+    current: RwLock<Arc<BTreeMap<ClientId, Account>>>,
+}
+
+impl LiveAccounts {
+    pub fn load(&self) -> Arc<BTreeMap<ClientId, Account>> {
+        Arc::clone(&self.current.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn publish(&self, engine: &Engine) {
+        let accounts = engine
+            .accounts()
+            .map(|(&client, &account)| (client, account))
+            .collect();
+
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(accounts);
+    }
+}
+
+/// Reports a summary of `live` to stderr every `interval`, until `stop` is disconnected.
+pub fn monitor(live: &LiveAccounts, interval: Duration, stop: &Receiver<()>) {
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+        let accounts = live.load();
+        let locked = accounts.values().filter(|account| account.locked()).count();
+        let total: Decimal = accounts.values().map(Account::total).sum();
+
+        eprintln!(
+            "progress: {} accounts, {locked} locked, total {total}",
+            accounts.len()
+        );
+    }
+}
@@ -1,36 +1,148 @@
-use std::{env, io};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fs::File,
+    io::{self, BufWriter},
+    panic,
+    path::PathBuf,
+    slice,
+    str::FromStr,
+    sync::mpsc,
+    thread,
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use derive_more::Display;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
-use crate::engine::Engine;
+use crate::{
+    activity::Activity,
+    aging::AgingReport,
+    alert::{Alerter, Thresholds},
+    cli::{Command, Emit, ProcessArgs},
+    clients::Clients,
+    diffs::DiffRecorder,
+    engine::{Account, Engine},
+    expiry::Expiry,
+    flags::Flagger,
+    input::ReadOptions,
+    joint::JointAccounts,
+    ledger::Ledger,
+    live::LiveAccounts,
+    processor::Processor,
+    quarantine::Quarantine,
+    risk::RiskReport,
+    settlement::Settlement,
+    top::TopAccounts,
+    wallet::Wallets,
+};
 
+mod activity;
+mod aging;
+mod alert;
+mod amount;
+#[cfg(feature = "amqp")]
+mod amqp;
+mod anonymize;
+mod balance;
+mod cli;
+mod clients;
+mod config;
+mod diffs;
 mod engine;
+mod expiry;
+mod explain;
+mod flags;
+mod follow;
+mod input;
+mod interest;
+mod joint;
+mod ledger;
+mod live;
+mod manifest;
+mod merge;
+mod metadata;
+#[cfg(feature = "nats")]
+mod nats;
+mod output;
+mod policy;
+mod processor;
+mod quarantine;
+mod query;
+mod reconcile;
+mod reorder;
+mod repl;
+mod risk;
+mod settlement;
+mod source;
+mod split;
+mod state;
+mod stats;
+mod store;
+mod top;
+mod validate;
+mod wallet;
+mod watch;
+
+/// The integers that IDs are held in, which the `wide-ids` feature widens for ID spaces that don't
+/// fit the specification's.
+#[cfg(not(feature = "wide-ids"))]
+type RawClientId = u16;
+#[cfg(feature = "wide-ids")]
+type RawClientId = u64;
+#[cfg(not(feature = "wide-ids"))]
+type RawTransactionId = u32;
+#[cfg(feature = "wide-ids")]
+type RawTransactionId = u64;
 
 #[derive(Clone, Copy, Debug, Display, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
-struct ClientId(u16);
+struct ClientId(RawClientId);
 
 #[derive(Clone, Copy, Debug, Display, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
-struct TransactionId(u32);
+struct TransactionId(RawTransactionId);
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 enum TransactionType {
+    #[display("deposit")]
     Deposit,
+    #[display("withdrawal")]
     Withdrawal,
+    #[display("dispute")]
     Dispute,
+    #[display("resolve")]
     Resolve,
+    #[display("chargeback")]
     Chargeback,
 }
 
+impl FromStr for TransactionType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(Self::Deposit),
+            "withdrawal" => Ok(Self::Withdrawal),
+            "dispute" => Ok(Self::Dispute),
+            "resolve" => Ok(Self::Resolve),
+            "chargeback" => Ok(Self::Chargeback),
+            _ => bail!("expected one of: deposit, withdrawal, dispute, resolve, chargeback"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct TransactionRecord {
     r#type: TransactionType,
     client: ClientId,
     tx: TransactionId,
     amount: Option<Decimal>,
+    #[serde(default)]
+    seq: Option<u64>,
+    /// Which of the client's wallets the transaction is for, if not their main account.
+    #[serde(default)]
+    wallet: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -40,66 +152,434 @@ struct AccountRecord {
     held: Decimal,
     total: Decimal,
     locked: bool,
+    /// Client details, only written when a clients file is given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tier: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    /// What the account has been through, only written with `--extended-output`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    open_disputes: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deposited: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    withdrawn: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chargebacks: Option<u64>,
+    /// Written empty for accounts with no transactions applied this run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_tx: Option<String>,
+    /// Why the account was first locked, and the transaction that did it, written empty for
+    /// unlocked accounts and locks that no transaction set off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lock_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    locked_by: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct DeltaRecord {
+    client: ClientId,
+    tx: TransactionId,
+    r#type: TransactionType,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+/// One transaction's effect on an account, as explained by `explain`.
+#[derive(Clone, Debug, Serialize)]
+struct TraceRecord<'a> {
+    tx: TransactionId,
+    r#type: TransactionType,
+    amount: Option<Decimal>,
+    /// `applied`, or why the transaction was not.
+    result: &'a str,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
 }
 
 fn main() -> Result<(), anyhow::Error> {
-    let mut args = env::args_os();
-    _ = args.next();
-    let path = args
-        .next()
-        .context("missing argument: path to transactions")?;
-
-    let mut engine = Engine::new();
-
-    let mut csv_reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_path(path)?;
-
-    for transaction_res in csv_reader.deserialize::<TransactionRecord>() {
-        let transaction = transaction_res?;
-        println!("{transaction:?}");
-
-        let res = match transaction.r#type {
-            TransactionType::Deposit => engine.deposit(
-                transaction.client,
-                transaction.tx,
-                transaction.amount.context("missing amount")?,
-            ),
-            TransactionType::Withdrawal => engine.withdraw(
-                transaction.client,
-                transaction.tx,
-                transaction.amount.context("missing amount")?,
-            ),
-            TransactionType::Dispute => engine.dispute(transaction.client, transaction.tx),
-            TransactionType::Resolve => engine.resolve(transaction.client, transaction.tx),
-            TransactionType::Chargeback => engine.chargeback(transaction.client, transaction.tx),
+    match Command::parse(env::args_os())? {
+        Command::Process(args) => run(*args),
+        Command::Anonymize(args) => anonymize::run(&args),
+        Command::Accounts(args) => query::accounts(&args),
+        Command::Tx(args) => query::tx(&args),
+        Command::Locks(args) => query::locks(&args),
+        Command::Merge(args) => merge::run(&args),
+        Command::Stats(args) => stats::run(&args),
+        Command::Split(args) => split::run(&args),
+        Command::Repl(args) => repl::run(&args),
+        Command::Explain(args) => explain::run(&args),
+        Command::BalanceAt(args) => balance::run(&args),
+        Command::Reconcile(args) => reconcile::run(&args),
+        Command::Validate(args) => validate::run(&args),
+    }
+}
+
+fn run(mut args: ProcessArgs) -> Result<(), anyhow::Error> {
+    args.paths = input_paths(&args)?;
+
+    let clients = match &args.clients_file {
+        Some(path) => Some(Clients::load(path)?),
+        None => None,
+    };
+
+    let initial = load_initial(&args)?;
+
+    let before: Option<BTreeMap<ClientId, Account>> = initial
+        .as_ref()
+        .filter(|_| args.report.only_changed)
+        .map(|engine| {
+            engine
+                .accounts()
+                .map(|(&client, &account)| (client, account))
+                .collect()
+        });
+
+    let live = LiveAccounts::default();
+    let activity = args.report.extended.then(Activity::default);
+
+    let mut engine = thread::scope(|scope| {
+        let (stop_sender, stop_receiver) = mpsc::channel();
+
+        let live = args.monitor_interval.map(|interval| {
+            let live = &live;
+            _ = scope.spawn(move || live::monitor(live, interval, &stop_receiver));
+            live
+        });
+
+        let res = if args.sharded {
+            process_sharded(&args, live)
+        } else {
+            let processor =
+                build_processor(&args, initial, live, activity.as_ref(), clients.as_ref())?;
+
+            match (&args.follow, &args.watch) {
+                (Some(report), _) => follow::run(
+                    &args.paths[0],
+                    &args.read,
+                    processor,
+                    report,
+                    args.rounding,
+                    clients.as_ref(),
+                )
+                .map(|never| match never {}),
+                (None, Some(dir)) => watch::run(
+                    dir,
+                    &args.read,
+                    processor,
+                    args.save_state.as_deref(),
+                    args.rounding,
+                    clients.as_ref(),
+                )
+                .map(|never| match never {}),
+                (None, None) => match args.source.kind {
+                    #[cfg(any(feature = "nats", feature = "amqp"))]
+                    Some(kind) => source::run(
+                        kind,
+                        &args.source,
+                        &args.read,
+                        processor,
+                        args.save_state.as_deref(),
+                        args.rounding,
+                        clients.as_ref(),
+                    )
+                    .map(|never| match never {}),
+                    _ => process(&args.paths, &args.read, processor),
+                },
+            }
         };
 
-        match res {
-            Ok(()) => (),
+        drop(stop_sender);
+        res
+    })?;
+
+    report_credit_exposure(&engine, &args.credit_clients.0);
+
+    // Other runs credit interest as their processor finishes, so that it is reported.
+    if let (true, Some(rate)) = (args.sharded, args.accrue_interest) {
+        interest::accrue(&mut engine, rate)?;
+    }
+
+    if let Some(path) = args
+        .save_state
+        .as_ref()
+        .or(args.state.as_ref().filter(|_| args.append))
+    {
+        state::save(&engine, path)?;
+    }
+
+    if let Some(path) = &args.trial_balance {
+        output::write_trial_balance(File::create(path)?, &engine, args.rounding)?;
+    }
+
+    let activity = activity.map(|activity| activity.finish(&engine));
+
+    if args.emit == Emit::Final && args.top.is_none() {
+        let accounts = engine.accounts().filter(|&(client, account)| {
+            before
+                .as_ref()
+                .is_none_or(|before| before.get(client) != Some(account))
+        });
+
+        output::write_accounts(
+            BufWriter::with_capacity(args.write_buffer, io::stdout().lock()),
+            accounts,
+            args.rounding,
+            clients.as_ref(),
+            activity.as_ref(),
+        )?;
+    }
+
+    if let Some(path) = &args.run_metadata {
+        metadata::write(path, &args)?;
+    }
+
+    Ok(())
+}
+
+/// Sets up a processor with everything `args` asks to be reported along the way.
+fn build_processor<'a>(
+    args: &'a ProcessArgs,
+    initial: Option<Engine>,
+    live: Option<&'a LiveAccounts>,
+    activity: Option<&'a Activity>,
+    clients: Option<&'a Clients>,
+) -> Result<Processor<'a>, anyhow::Error> {
+    let mut processor = replay_processor(args, initial)?;
+
+    if let Some(live) = live {
+        processor = processor.with_live(live);
+    }
+
+    if let Some(activity) = activity {
+        processor = processor.with_activity(activity);
+    }
+
+    if args.emit == Emit::Deltas {
+        let stdout = BufWriter::with_capacity(args.write_buffer, io::stdout().lock());
+        processor = processor.with_deltas(stdout, args.rounding);
+    }
+
+    if let Some(rate) = args.accrue_interest {
+        processor = processor.with_interest(rate);
+    }
+
+    if args.alerts_enabled() {
+        let thresholds = Thresholds {
+            amount: args.alert_amount,
+            cumulative: args.alert_cumulative_amount,
+        };
 
-            Err(
-                fatal @ (engine::Error::ClientMismatch { .. }
-                | engine::Error::DuplicateTransactionId(_)),
-            ) => return Err(fatal.into()),
+        let mut alerter = match &args.alerts_path {
+            Some(path) => Alerter::new(thresholds, File::create(path)?),
+            None => Alerter::new(thresholds, io::stderr()),
+        };
 
-            Err(nonfatal) => eprintln!("warning: {nonfatal}"),
+        if let Some(clients) = clients {
+            alerter = alerter.with_clients(clients);
         }
+
+        processor = processor.with_alerter(alerter);
     }
 
-    let mut csv_writer = csv::Writer::from_writer(io::stdout().lock());
+    if args.heuristics.enabled() {
+        let flagger = match &args.flags_path {
+            Some(path) => Flagger::new(args.heuristics, File::create(path)?),
+            None => Flagger::new(args.heuristics, io::stderr()),
+        };
+
+        processor = processor.with_flagger(flagger);
+    }
+
+    if let Some(path) = &args.wallets {
+        let wallets = Wallets::new(File::create(path)?, args.rounding);
+        processor = processor.with_wallets(wallets);
+    }
+
+    if let Some(path) = &args.settlement {
+        let settlement = Settlement::new(File::create(path)?, args.rounding);
+        processor = processor.with_settlement(settlement);
+    }
+
+    if let Some(path) = &args.risk_report {
+        let risk = RiskReport::new(File::create(path)?, args.rounding);
+        processor = processor.with_risk_report(risk);
+    }
+
+    if let Some(path) = &args.ledger {
+        let ledger = Ledger::new(File::create(path)?, args.rounding);
+        processor = processor.with_ledger(ledger);
+    }
+
+    if let Some(path) = &args.diffs {
+        processor = processor.with_diffs(DiffRecorder::new(File::create(path)?));
+    }
+
+    if args.quarantine.is_some() || args.retry_unmatched {
+        let mut quarantine = Quarantine::new();
+
+        if let Some(path) = &args.quarantine {
+            quarantine = quarantine.with_writer(File::create(path)?);
+        }
 
-    for (&client, account) in engine.accounts() {
-        let account_record = AccountRecord {
-            client,
-            available: account.available(),
-            held: account.held,
-            total: account.total,
-            locked: account.locked,
+        if args.retry_unmatched {
+            quarantine = quarantine.retrying();
+        }
+
+        processor = processor.with_quarantine(quarantine);
+    }
+
+    if let (Some(after), Some(path)) = (args.dispute_expiry, &args.lapsed_disputes) {
+        let expiry = Expiry::new(after).with_writer(File::create(path)?, args.rounding);
+        processor = processor.with_expiry(expiry);
+    }
+
+    if let (Some(buckets), Some(path)) = (args.aging_buckets, &args.held_aging) {
+        let aging = AgingReport::new(buckets, File::create(path)?, args.rounding);
+        processor = processor.with_aging_report(aging);
+    }
+
+    if let Some(limit) = args.top {
+        let stdout = BufWriter::with_capacity(args.write_buffer, io::stdout().lock());
+        let mut top = TopAccounts::new(args.top_by, limit, stdout, args.rounding);
+
+        if let Some(clients) = clients {
+            top = top.with_clients(clients);
+        }
+
+        processor = processor.with_top(top);
+    }
+
+    Ok(processor)
+}
+
+/// The input files `args` names, whether given directly or through a manifest.
+fn input_paths(args: &ProcessArgs) -> Result<Vec<PathBuf>, anyhow::Error> {
+    match &args.manifest {
+        Some(manifest) => manifest::verify(manifest),
+        None => Ok(args.paths.clone()),
+    }
+}
+
+/// The saved state `args` says to start from, if any.
+fn load_initial(args: &ProcessArgs) -> Result<Option<Engine>, anyhow::Error> {
+    args.state.as_deref().map(state::load).transpose()
+}
+
+/// Sets up a processor that applies transactions the way `args` says, starting from `initial`,
+/// without reporting on them. Disputes lapse without being written anywhere.
+fn replay_processor<'a>(
+    args: &ProcessArgs,
+    initial: Option<Engine>,
+) -> Result<Processor<'a>, anyhow::Error> {
+    let mut processor = Processor::new(args.reorder_window, args.policy);
+
+    if let Some(engine) = initial {
+        processor = processor.with_engine(engine);
+    }
+
+    if let Some(timeout) = args.reorder_timeout {
+        processor = processor.with_reorder_timeout(timeout);
+    }
+
+    processor = processor
+        .with_credit_clients(args.credit_clients.0.clone())
+        .with_balance_limits(args.balance_limits.clone())
+        .with_retention(args.retention);
+
+    if let Some(path) = &args.joint_accounts {
+        processor = processor.with_joint_accounts(JointAccounts::load(path)?);
+    }
+
+    if let Some(after) = args.dispute_expiry {
+        processor = processor.with_expiry(Expiry::new(after));
+    }
+
+    Ok(processor)
+}
+
+fn process(
+    paths: &[PathBuf],
+    options: &ReadOptions,
+    mut processor: Processor,
+) -> Result<Engine, anyhow::Error> {
+    input::for_each_record(paths, options, processor.policy(), |transaction| {
+        processor.push(transaction)
+    })?;
+    processor.finish()
+}
+
+/// Warns about every credit client that has gone below zero available, and their total.
+fn report_credit_exposure(engine: &Engine, clients: &BTreeSet<ClientId>) {
+    let mut total = Decimal::ZERO;
+
+    for &client in clients {
+        let Some(account) = engine.account(client) else {
+            continue;
         };
 
-        csv_writer.serialize(account_record)?;
+        if account.available() < Decimal::ZERO {
+            let exposure = -account.available();
+            eprintln!("credit exposure (client: {client}, exposure: {exposure})");
+            total += exposure;
+        }
+    }
+
+    if total > Decimal::ZERO {
+        eprintln!("credit exposure (total: {total})");
     }
+}
 
-    Ok(())
+/// Processes each file with its own engine, then merges the results.
+///
+/// Every file must hold a disjoint set of clients.
+///
+/// Shards are not published to `live` until they have all been merged.
+fn process_sharded(
+    args: &ProcessArgs,
+    live: Option<&LiveAccounts>,
+) -> Result<Engine, anyhow::Error> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = args
+            .paths
+            .iter()
+            .map(|path| {
+                scope.spawn(|| {
+                    process(
+                        slice::from_ref(path),
+                        &args.read,
+                        Processor::new(args.reorder_window, args.policy)
+                            .with_credit_clients(args.credit_clients.0.clone())
+                            .with_balance_limits(args.balance_limits.clone())
+                            .with_retention(args.retention),
+                    )
+                })
+            })
+            .collect();
+
+        let mut merged = Engine::new();
+
+        for (path, handle) in args.paths.iter().zip(handles) {
+            let shard = handle
+                .join()
+                .unwrap_or_else(|payload| panic::resume_unwind(payload))?;
+
+            merged = merged
+                .merge(shard)
+                .with_context(|| format!("failed to merge shard {}", path.display()))?;
+        }
+
+        if let Some(live) = live {
+            live.publish(&merged);
+        }
+
+        Ok(merged)
+    })
 }
@@ -0,0 +1,121 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("checksum mismatch (file: {file}, expected: {expected}, found: {found})")]
+    ChecksumMismatch {
+        file: String,
+        expected: String,
+        found: String,
+    },
+
+    #[error("row count mismatch (file: {file}, expected: {expected}, found: {found})")]
+    RowCountMismatch {
+        file: String,
+        expected: u64,
+        found: u64,
+    },
+}
+
+/// One expected input file. `file` is relative to the manifest's own directory.
+#[derive(Clone, Debug, Deserialize)]
+struct ManifestRecord {
+    file: PathBuf,
+    sha256: String,
+    rows: u64,
+}
+
+/// Checks every file listed in the manifest at `path` against its SHA-256 checksum and data row
+/// count, returning the files' paths in manifest order.
+pub fn verify(path: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .with_context(|| format!("failed to read manifest {}", path.display()))?;
+
+    let mut paths = Vec::new();
+
+    for record_res in csv_reader.deserialize::<ManifestRecord>() {
+        let record = record_res?;
+        let file = base.join(&record.file);
+
+        let (sha256, rows) =
+            digest(&file).with_context(|| format!("failed to read {}", file.display()))?;
+
+        if !sha256.eq_ignore_ascii_case(&record.sha256) {
+            return Err(Error::ChecksumMismatch {
+                file: file.display().to_string(),
+                expected: record.sha256,
+                found: sha256,
+            }
+            .into());
+        }
+
+        if rows != record.rows {
+            return Err(Error::RowCountMismatch {
+                file: file.display().to_string(),
+                expected: record.rows,
+                found: rows,
+            }
+            .into());
+        }
+
+        paths.push(file);
+    }
+
+    Ok(paths)
+}
+
+/// Returns the hex SHA-256 of the file at `path` and the number of CSV rows after the header,
+/// in a single pass.
+pub fn digest(path: &Path) -> Result<(String, u64), csv::Error> {
+    let mut reader = HashingReader {
+        inner: File::open(path)?,
+        hasher: Sha256::new(),
+    };
+
+    let mut rows = 0;
+    for record_res in csv::Reader::from_reader(&mut reader).records() {
+        _ = record_res?;
+        rows += 1;
+    }
+
+    // The CSV reader stops at the last record, so hash anything after it too.
+    _ = io::copy(&mut reader, &mut io::sink())?;
+
+    Ok((hex(reader.hasher.finalize()), rows))
+}
+
+/// Writes a hash in lowercase hex.
+pub fn hex(hash: impl IntoIterator<Item = u8>) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in hash {
+        _ = write!(hex, "{byte:02x}");
+    }
+
+    hex
+}
+
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
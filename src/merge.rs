@@ -0,0 +1,159 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+use serde::Serialize;
+
+use crate::{
+    cli::MergeArgs,
+    engine::{Engine, MergeError},
+    state,
+};
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ConflictKind {
+    Client,
+    Transaction,
+    Settlement,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ConflictRecord {
+    state: String,
+    /// The earlier state it conflicts with, or empty if it conflicts with all of them together.
+    with: String,
+    kind: ConflictKind,
+    id: String,
+}
+
+impl ConflictRecord {
+    fn new(state: &Path, with: Option<&Path>, conflict: &MergeError) -> Self {
+        let (kind, id) = match *conflict {
+            MergeError::ClientConflict(client) => (ConflictKind::Client, client.to_string()),
+            MergeError::DuplicateTransactionId(tx) => (ConflictKind::Transaction, tx.to_string()),
+            MergeError::SettlementOverflow => (ConflictKind::Settlement, String::new()),
+        };
+
+        Self {
+            state: state.display().to_string(),
+            with: with.map_or_else(String::new, |with| with.display().to_string()),
+            kind,
+            id,
+        }
+    }
+}
+
+/// Combines saved states from sharded runs into one.
+///
+/// Every state is checked against every other, so a state that conflicts with more than one is
+/// reported once for each. If any conflict is found, every conflict is reported on stdout and
+/// nothing is written.
+pub fn run(args: &MergeArgs) -> Result<(), anyhow::Error> {
+    let engines = args
+        .states
+        .iter()
+        .map(|path| state::load(path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let conflicts = match merge(&args.states, engines) {
+        Ok(merged) => return state::save(&merged, &args.output),
+        Err(conflicts) => conflicts,
+    };
+
+    let mut csv_writer = csv::Writer::from_writer(io::stdout().lock());
+    for conflict in &conflicts {
+        csv_writer.serialize(conflict)?;
+    }
+    csv_writer.flush()?;
+
+    bail!(
+        "found {} conflicts, not writing {}",
+        conflicts.len(),
+        args.output.display()
+    );
+}
+
+/// Merges `engines`, loaded from `paths`, or lists every conflict between them.
+fn merge(paths: &[PathBuf], engines: Vec<Engine>) -> Result<Engine, Vec<ConflictRecord>> {
+    let mut conflicts = Vec::new();
+
+    for (i, (path, engine)) in paths.iter().zip(&engines).enumerate() {
+        for (earlier_path, earlier) in paths.iter().zip(&engines).take(i) {
+            conflicts.extend(
+                earlier
+                    .merge_conflicts(engine)
+                    .into_iter()
+                    // The settlement can only overflow once they are all added up.
+                    .filter(|conflict| !matches!(conflict, MergeError::SettlementOverflow))
+                    .map(|conflict| {
+                        ConflictRecord::new(path, Some(earlier_path.as_path()), &conflict)
+                    }),
+            );
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+
+    let mut merged = Engine::new();
+
+    for (path, engine) in paths.iter().zip(engines) {
+        merged = merged
+            .merge(engine)
+            .map_err(|conflict| vec![ConflictRecord::new(path, None, &conflict)])?;
+    }
+
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::{ClientId, TransactionId};
+
+    fn deposits(deposits: &[(ClientId, u16)]) -> Engine {
+        let mut engine = Engine::new();
+        for &(client, tx) in deposits {
+            engine
+                .deposit(client, TransactionId(tx.into()), Decimal::ONE)
+                .unwrap();
+        }
+        engine
+    }
+
+    #[test]
+    fn checks_every_pair_of_states() {
+        let paths = ["a", "b", "c"].map(PathBuf::from);
+        // b conflicts with a, and c only with b.
+        let engines = vec![
+            deposits(&[(ClientId(1), 1)]),
+            deposits(&[(ClientId(1), 2), (ClientId(2), 3)]),
+            deposits(&[(ClientId(3), 3)]),
+        ];
+
+        let conflicts = merge(&paths, engines).unwrap_err();
+
+        let found: Vec<_> = conflicts
+            .iter()
+            .map(|conflict| (&*conflict.state, &*conflict.with, &*conflict.id))
+            .collect();
+        assert_eq!(found, [("b", "a", "1"), ("c", "b", "3")]);
+    }
+
+    #[test]
+    fn merges_states_without_conflicts() {
+        let paths = ["a", "b"].map(PathBuf::from);
+        let engines = vec![deposits(&[(ClientId(1), 1)]), deposits(&[(ClientId(2), 2)])];
+
+        let merged = merge(&paths, engines).unwrap();
+
+        assert_eq!(merged.account(ClientId(2)).unwrap().total(), Decimal::ONE);
+        assert_eq!(merged.settlement(), Decimal::from(-2));
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{cli::ProcessArgs, manifest};
+
+/// Where a run's reports came from, so that each can be traced back to the run that wrote it.
+#[derive(Clone, Debug, Serialize)]
+struct RunMetadata {
+    version: &'static str,
+    /// Seconds since the Unix epoch when the run finished.
+    finished_at: u64,
+    /// The SHA-256 of every setting the run used, once config, environment, and flags were
+    /// combined. Runs with the same digest were set up the same way.
+    settings_sha256: String,
+    /// Every file the run wrote, apart from standard output.
+    outputs: Vec<PathBuf>,
+    inputs: Vec<InputMetadata>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct InputMetadata {
+    file: PathBuf,
+    sha256: String,
+    rows: u64,
+}
+
+/// Writes the metadata for a finished run to `path` as TOML.
+///
+/// Inputs are hashed again here rather than as they are processed, so this reads every input a
+/// second time.
+pub fn write(path: &Path, args: &ProcessArgs) -> Result<(), anyhow::Error> {
+    let inputs = args
+        .paths
+        .iter()
+        .map(|file| {
+            let (sha256, rows) = manifest::digest(file)
+                .with_context(|| format!("failed to read {}", file.display()))?;
+
+            Ok(InputMetadata {
+                file: file.clone(),
+                sha256,
+                rows,
+            })
+        })
+        .collect::<Result<_, anyhow::Error>>()?;
+
+    let outputs = [
+        args.save_state
+            .as_ref()
+            .or(args.state.as_ref().filter(|_| args.append)),
+        args.trial_balance.as_ref(),
+        args.settlement.as_ref(),
+        args.risk_report.as_ref(),
+        args.ledger.as_ref(),
+        args.diffs.as_ref(),
+        args.wallets.as_ref(),
+        args.quarantine.as_ref(),
+        args.lapsed_disputes.as_ref(),
+        args.alerts_path.as_ref(),
+        args.flags_path.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .collect();
+
+    let metadata = RunMetadata {
+        version: env!("CARGO_PKG_VERSION"),
+        finished_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+        settings_sha256: manifest::hex(Sha256::digest(format!("{args:?}"))),
+        outputs,
+        inputs,
+    };
+
+    fs::write(path, toml::to_string(&metadata)?)
+        .with_context(|| format!("failed to write {}", path.display()))
+}
@@ -0,0 +1,439 @@
+use std::{
+    convert::Infallible,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    process, str,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+
+use crate::source::{Consumer, SourceOptions};
+
+/// How long each pull request waits for messages before the server ends it and another is sent.
+const PULL_EXPIRES: Duration = Duration::from_secs(30);
+
+/// The subscription that every reply comes in on.
+const SID: &str = "1";
+
+/// Consumes transaction files from a `JetStream` stream through a durable pull consumer, acking
+/// each message once it has been applied and terminating it if it can't be.
+///
+/// The consumer is created if it doesn't exist, with explicit acks and delivery of the whole
+/// stream, so a new consumer starts from the beginning and an existing one from the first
+/// message it hasn't had acked.
+///
+/// The connection is plain TCP, so a server that requires TLS is refused.
+pub fn run(options: &SourceOptions, mut consumer: Consumer) -> Result<Infallible, anyhow::Error> {
+    let broker = options
+        .broker
+        .as_deref()
+        .context("--source nats requires --broker")?;
+    let stream = options
+        .stream
+        .as_deref()
+        .context("--source nats requires --stream")?;
+    let durable = options
+        .durable
+        .as_deref()
+        .context("--source nats requires --durable")?;
+
+    for (name, flag) in [(stream, "--stream"), (durable, "--durable")] {
+        if !valid_name(name) {
+            bail!("invalid {flag}: {name}");
+        }
+    }
+
+    let mut connection =
+        Connection::open(broker, options.user.as_deref(), options.password.as_deref())?;
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let inbox = format!("_INBOX.spork.{}.{nanos}", process::id());
+    connection.send(format!("SUB {inbox}.* {SID}\r\n").as_bytes())?;
+
+    let create = format!(
+        "{{\"stream_name\":{},\"config\":{{\"durable_name\":{},\"ack_policy\":\"explicit\",\
+        \"deliver_policy\":\"all\"}}}}",
+        json_string(stream),
+        json_string(durable),
+    );
+    connection.publish(
+        &format!("$JS.API.CONSUMER.DURABLE.CREATE.{stream}.{durable}"),
+        Some(&format!("{inbox}.create")),
+        create.as_bytes(),
+    )?;
+
+    let response = connection.next_message()?;
+    if let Some(status) = response.status {
+        bail!("failed to create consumer {durable} (status: {status}); is JetStream enabled?");
+    }
+    let response = String::from_utf8_lossy(&response.payload);
+    if response.contains("\"error\"") {
+        bail!("failed to create consumer {durable}: {response}");
+    }
+
+    let pull_subject = format!("$JS.API.CONSUMER.MSG.NEXT.{stream}.{durable}");
+    let pull_reply = format!("{inbox}.pull");
+    let pull = format!(
+        "{{\"batch\":{},\"expires\":{}}}",
+        options.prefetch,
+        PULL_EXPIRES.as_nanos()
+    );
+
+    loop {
+        connection.publish(&pull_subject, Some(&pull_reply), pull.as_bytes())?;
+
+        let mut remaining = options.prefetch;
+
+        while remaining > 0 {
+            let message = connection.next_message()?;
+
+            // The pull request ended without filling its batch, because it expired or because of
+            // something on the server's side. Either way, another one is needed.
+            if message.status.is_some() {
+                break;
+            }
+
+            // Only messages from the stream can be acked.
+            let Some(reply) = message.reply.filter(|reply| reply.starts_with("$JS.ACK.")) else {
+                continue;
+            };
+
+            remaining -= 1;
+
+            let name = format!("{stream} message on {}", message.subject);
+            let ack: &[u8] = if consumer.apply(&message.payload, &name)? {
+                b""
+            } else {
+                b"+TERM"
+            };
+
+            connection.publish(&reply, None, ack)?;
+        }
+    }
+}
+
+/// Whether `name` can be used as a stream or consumer name, which are also subject tokens.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => _ = write!(quoted, "\\u{:04x}", u32::from(c)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// A message delivered on a subscription.
+#[derive(Debug)]
+struct Message {
+    subject: String,
+    reply: Option<String>,
+    /// The status code from the headers, which the server sends about requests rather than
+    /// stream messages.
+    status: Option<u16>,
+    payload: Vec<u8>,
+}
+
+/// A connection speaking the NATS client protocol, without TLS.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(
+        broker: &str,
+        user: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<Self, anyhow::Error> {
+        let stream =
+            TcpStream::connect(broker).with_context(|| format!("failed to connect to {broker}"))?;
+        let mut connection = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        let info = connection.read_line()?;
+        let Some(info) = info.strip_prefix("INFO ") else {
+            bail!("unexpected greeting from {broker}: {info}");
+        };
+        if info.contains("\"tls_required\":true") {
+            bail!("{broker} requires TLS, which isn't supported");
+        }
+
+        let mut connect = String::from(
+            "{\"verbose\":false,\"pedantic\":false,\"lang\":\"rust\",\"protocol\":1,\
+            \"headers\":true,\"no_responders\":true",
+        );
+        for (key, value) in [("user", user), ("pass", password)] {
+            if let Some(value) = value {
+                _ = write!(connect, ",\"{key}\":{}", json_string(value));
+            }
+        }
+        connect.push('}');
+
+        connection.send(format!("CONNECT {connect}\r\nPING\r\n").as_bytes())?;
+
+        // The server answers a ping once it has accepted the connection, or says why it hasn't.
+        loop {
+            match connection.read_line()?.as_str() {
+                "PONG" => return Ok(connection),
+                line if line.starts_with("-ERR") => bail!("{broker} refused to connect: {line}"),
+                _ => (),
+            }
+        }
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> Result<(), anyhow::Error> {
+        self.writer
+            .write_all(bytes)
+            .context("failed to write to NATS")
+    }
+
+    fn publish(
+        &mut self,
+        subject: &str,
+        reply: Option<&str>,
+        payload: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        let reply = reply.map_or_else(String::new, |reply| format!(" {reply}"));
+        let mut bytes = format!("PUB {subject}{reply} {}\r\n", payload.len()).into_bytes();
+        bytes.extend_from_slice(payload);
+        bytes.extend_from_slice(b"\r\n");
+        self.send(&bytes)
+    }
+
+    /// Reads up to the next message, answering pings on the way.
+    fn next_message(&mut self) -> Result<Message, anyhow::Error> {
+        loop {
+            let line = self.read_line()?;
+            let mut fields = line.split_ascii_whitespace();
+
+            match fields.next() {
+                Some("PING") => self.send(b"PONG\r\n")?,
+                Some("-ERR") => bail!("NATS error: {line}"),
+                Some(op @ ("MSG" | "HMSG")) => {
+                    let fields: Vec<_> = fields.collect();
+                    return self
+                        .read_message(op == "HMSG", &fields)
+                        .with_context(|| format!("invalid message from NATS: {line}"));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Reads the rest of a message, given the fields of its `MSG` or `HMSG` line after the
+    /// operation: the subject, the subscription ID, the reply subject if there is one, the size
+    /// of the headers if there are any, and the total size.
+    fn read_message(
+        &mut self,
+        has_headers: bool,
+        fields: &[&str],
+    ) -> Result<Message, anyhow::Error> {
+        let (subject, reply, header_len, total) = match (has_headers, fields) {
+            (false, &[subject, _, total]) => (subject, None, "0", total),
+            (false, &[subject, _, reply, total]) => (subject, Some(reply), "0", total),
+            (true, &[subject, _, header_len, total]) => (subject, None, header_len, total),
+            (true, &[subject, _, reply, header_len, total]) => {
+                (subject, Some(reply), header_len, total)
+            }
+            _ => bail!("wrong number of fields"),
+        };
+
+        let header_len: usize = header_len.parse()?;
+        let total: usize = total.parse()?;
+        if header_len > total {
+            bail!("headers longer than the message");
+        }
+
+        let mut bytes = vec![0; total + 2];
+        self.reader
+            .read_exact(&mut bytes)
+            .context("failed to read from NATS")?;
+        bytes.truncate(total);
+        let payload = bytes.split_off(header_len);
+
+        Ok(Message {
+            subject: subject.to_owned(),
+            reply: reply.map(str::to_owned),
+            status: status(&bytes),
+            payload,
+        })
+    }
+
+    /// Reads a line without its line ending, failing if the connection has closed.
+    fn read_line(&mut self) -> Result<String, anyhow::Error> {
+        let mut line = String::new();
+
+        if self
+            .reader
+            .read_line(&mut line)
+            .context("failed to read from NATS")?
+            == 0
+        {
+            bail!("NATS closed the connection");
+        }
+
+        line.truncate(line.trim_end_matches(['\r', '\n']).len());
+        Ok(line)
+    }
+}
+
+/// Reads the status code from the first line of a message's headers, like `NATS/1.0 408`.
+fn status(headers: &[u8]) -> Option<u16> {
+    let line = headers.split(|&byte| byte == b'\r').next()?;
+    let mut fields = str::from_utf8(line).ok()?.split_ascii_whitespace();
+    _ = fields.next()?;
+    fields.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, net::TcpListener, thread};
+
+    use super::*;
+    use crate::{
+        input::ReadOptions,
+        output::Rounding,
+        policy::{Policy, Preset},
+        processor::Processor,
+        source::{self, SourceKind},
+    };
+
+    /// Reads a line from a client, without its line ending.
+    fn read_line(reader: &mut impl BufRead) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_owned()
+    }
+
+    /// Reads a `PUB` from a client, returning its reply subject, if any, and payload.
+    fn read_pub(reader: &mut impl BufRead) -> (Vec<String>, String) {
+        let line = read_line(reader);
+        let fields: Vec<_> = line.split(' ').map(str::to_owned).collect();
+        assert_eq!(fields[0], "PUB");
+        let payload = read_line(reader);
+        (fields, payload)
+    }
+
+    #[test]
+    fn acks_applied_messages_and_terminates_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+
+            writer
+                .write_all(b"INFO {\"server_id\":\"test\"}\r\n")
+                .unwrap();
+            assert!(read_line(&mut reader).starts_with("CONNECT "));
+            assert_eq!(read_line(&mut reader), "PING");
+            writer.write_all(b"PONG\r\n").unwrap();
+
+            let sub = read_line(&mut reader);
+            let inbox = sub
+                .split(' ')
+                .nth(1)
+                .unwrap()
+                .trim_end_matches(".*")
+                .to_owned();
+
+            let (create, _) = read_pub(&mut reader);
+            assert_eq!(create[1], "$JS.API.CONSUMER.DURABLE.CREATE.payments.spork");
+            let response = "{\"type\":\"io.nats.jetstream.api.v1.consumer_create_response\"}";
+            write!(
+                writer,
+                "MSG {inbox}.create 1 {}\r\n{response}\r\n",
+                response.len()
+            )
+            .unwrap();
+
+            let (pull, _) = read_pub(&mut reader);
+            assert_eq!(pull[1], "$JS.API.CONSUMER.MSG.NEXT.payments.spork");
+
+            let mut acks = Vec::new();
+            for (seq, body) in [
+                (1, "type,client,tx,amount\ndeposit,1,1,10\n"),
+                (2, "type,client,tx,amount\nnot a row\n"),
+            ] {
+                let reply = format!("$JS.ACK.payments.spork.1.{seq}.{seq}.0.0");
+                write!(
+                    writer,
+                    "MSG payments.new 1 {reply} {}\r\n{body}\r\n",
+                    body.len()
+                )
+                .unwrap();
+
+                let (ack, payload) = read_pub(&mut reader);
+                assert_eq!(ack[1], reply);
+                acks.push(payload);
+            }
+
+            acks
+        });
+
+        let report = env::temp_dir().join(format!("spork-nats-report-{}", process::id()));
+        let options = SourceOptions {
+            broker: Some(broker),
+            stream: Some("payments".to_owned()),
+            durable: Some("spork".to_owned()),
+            prefetch: 10,
+            report: Some(report.clone()),
+            ..SourceOptions::default()
+        };
+        let read = ReadOptions::default();
+        let processor = Processor::new(0, Policy::from(Preset::SpecCompat));
+
+        // The server hangs up once it has had both acks.
+        let err = source::run(
+            SourceKind::Nats,
+            &options,
+            &read,
+            processor,
+            None,
+            Rounding::default(),
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "NATS closed the connection");
+
+        let acks = server.join().unwrap();
+        let accounts = fs::read_to_string(&report);
+        fs::remove_file(&report).unwrap();
+
+        assert_eq!(acks, ["", "+TERM"]);
+        assert_eq!(
+            accounts.unwrap(),
+            "client,available,held,total,locked\n1,10,0,10,false\n"
+        );
+    }
+
+    #[test]
+    fn reads_status_from_headers() {
+        assert_eq!(status(b"NATS/1.0 408 Request Timeout\r\n\r\n"), Some(408));
+        assert_eq!(status(b"NATS/1.0\r\nNats-Msg-Id: 1\r\n\r\n"), None);
+    }
+}
@@ -0,0 +1,275 @@
+use std::{collections::BTreeMap, io, num::NonZeroUsize, panic, str::FromStr, thread};
+
+use anyhow::bail;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    activity::AccountActivity,
+    clients::Clients,
+    engine::{Account, DepositState, Engine},
+    AccountRecord, ClientId, TransactionType,
+};
+
+/// Which accounts the account report includes, and what it says about them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AccountReport {
+    /// Only report the accounts whose balances or lock changed since the loaded state.
+    pub only_changed: bool,
+    /// Add each account's open disputes, deposit and withdrawal totals, chargebacks, and last
+    /// transaction.
+    pub extended: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoundingMode {
+    /// Round midpoints to the nearest even digit.
+    #[default]
+    Bankers,
+    /// Round midpoints away from zero.
+    HalfUp,
+}
+
+impl FromStr for RoundingMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bankers" => Ok(Self::Bankers),
+            "half-up" => Ok(Self::HalfUp),
+            _ => bail!("expected one of: bankers, half-up"),
+        }
+    }
+}
+
+/// How amounts are rounded when written out. This never affects the engine's own arithmetic.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rounding {
+    /// Decimal places to round to, or `None` to write amounts as they are.
+    pub scale: Option<u32>,
+    pub mode: RoundingMode,
+    /// Write every amount with the same number of decimal places, however it was calculated:
+    /// exactly `scale` places if there is a scale, or else no trailing zeros.
+    pub normalize: bool,
+}
+
+impl Rounding {
+    pub fn round(self, amount: Decimal) -> Decimal {
+        let Some(scale) = self.scale else {
+            return if self.normalize {
+                amount.normalize()
+            } else {
+                amount
+            };
+        };
+
+        let strategy = match self.mode {
+            RoundingMode::Bankers => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+        };
+
+        let mut rounded = amount.round_dp_with_strategy(scale, strategy);
+
+        // Rounding only ever removes places, so pad the rest with zeros. This can't round again,
+        // since the amount has no more than `scale` places already.
+        if self.normalize {
+            rounded.rescale(scale);
+        }
+
+        rounded
+    }
+}
+
+/// Accounts serialized by one thread at a time when writing a large report.
+const PARALLEL_CHUNK: usize = 100_000;
+
+/// Writes the standard account report, with each client's details appended if `clients` is given.
+///
+/// Large reports are serialized a chunk at a time on several threads, and written in order.
+pub fn write_accounts<'a>(
+    mut writer: impl io::Write,
+    accounts: impl IntoIterator<Item = (&'a ClientId, &'a Account)>,
+    rounding: Rounding,
+    clients: Option<&Clients>,
+    activity: Option<&BTreeMap<ClientId, AccountActivity>>,
+) -> Result<(), csv::Error> {
+    let accounts: Vec<_> = accounts.into_iter().collect();
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+    if threads == 1 || accounts.len() <= PARALLEL_CHUNK {
+        return write_chunk(writer, &accounts, true, rounding, clients, activity);
+    }
+
+    // Only a few chunks are held in memory at once, however large the report.
+    for (round_index, round) in accounts.chunks(PARALLEL_CHUNK * threads).enumerate() {
+        let buffers = thread::scope(|scope| {
+            let handles: Vec<_> = round
+                .chunks(PARALLEL_CHUNK)
+                .enumerate()
+                .map(|(chunk_index, chunk)| {
+                    let headers = round_index == 0 && chunk_index == 0;
+                    scope.spawn(move || {
+                        let mut buffer = Vec::new();
+                        write_chunk(&mut buffer, chunk, headers, rounding, clients, activity)?;
+                        Ok(buffer)
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|payload| panic::resume_unwind(payload))
+                })
+                .collect::<Result<Vec<_>, csv::Error>>()
+        })?;
+
+        for buffer in buffers {
+            writer.write_all(&buffer)?;
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_chunk(
+    writer: impl io::Write,
+    accounts: &[(&ClientId, &Account)],
+    headers: bool,
+    rounding: Rounding,
+    clients: Option<&Clients>,
+    activity: Option<&BTreeMap<ClientId, AccountActivity>>,
+) -> Result<(), csv::Error> {
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(headers)
+        .from_writer(writer);
+
+    for &(&client, account) in accounts {
+        let info = clients.map(|clients| clients.get(client));
+        let activity = activity.map(|activity| activity.get(&client).copied().unwrap_or_default());
+
+        csv_writer.serialize(AccountRecord {
+            client,
+            available: rounding.round(account.available()),
+            held: rounding.round(account.held()),
+            total: rounding.round(account.total()),
+            locked: account.locked(),
+            name: info.map(|info| info.name.clone()),
+            tier: info.map(|info| info.tier.clone()),
+            email: info.map(|info| info.email.clone()),
+            open_disputes: activity.map(|activity| activity.open_disputes),
+            deposited: activity.map(|activity| rounding.round(activity.deposited)),
+            withdrawn: activity.map(|activity| rounding.round(activity.withdrawn)),
+            chargebacks: activity.map(|activity| activity.chargebacks),
+            last_tx: activity.map(|activity| {
+                activity
+                    .last_tx
+                    .map_or_else(String::new, |tx| tx.to_string())
+            }),
+            lock_reason: activity.map(|_| {
+                account
+                    .lock()
+                    .map_or_else(String::new, |lock| lock.reason.to_string())
+            }),
+            locked_by: activity.map(|_| {
+                account
+                    .lock()
+                    .and_then(|lock| lock.tx)
+                    .map_or_else(String::new, |tx| tx.to_string())
+            }),
+        })?;
+    }
+
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+struct TrialBalanceRecord {
+    accounts: usize,
+    locked: usize,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    /// The sum of the deposits under dispute, which should always equal `held`.
+    disputed: Decimal,
+    /// The sum of the deposits charged back, apart from any that have been forgotten.
+    charged_back: Decimal,
+    /// The settlement account's balance, and how far it is from balancing every account's total,
+    /// which should always be zero.
+    settlement: Decimal,
+    imbalance: Decimal,
+    /// The amounts of this run's operations, which can be checked against upstream control
+    /// totals.
+    deposits_applied: Decimal,
+    deposits_rejected: Decimal,
+    withdrawals_applied: Decimal,
+    withdrawals_rejected: Decimal,
+    disputes_applied: Decimal,
+    disputes_rejected: Decimal,
+    resolves_applied: Decimal,
+    resolves_rejected: Decimal,
+    chargebacks_applied: Decimal,
+    chargebacks_rejected: Decimal,
+}
+
+/// Writes the sums of every account's balances, and of the deposits that are disputed or were
+/// charged back, so that funds can be checked to have been conserved.
+pub fn write_trial_balance(
+    writer: impl io::Write,
+    engine: &Engine,
+    rounding: Rounding,
+) -> Result<(), csv::Error> {
+    let mut sums = TrialBalanceRecord::default();
+
+    for (_, account) in engine.accounts() {
+        sums.accounts += 1;
+        sums.locked += usize::from(account.locked());
+        sums.available += account.available();
+        sums.held += account.held();
+        sums.total += account.total();
+    }
+
+    for (_, deposit) in engine.deposits() {
+        match deposit.state {
+            DepositState::Ok => (),
+            DepositState::Dispute => sums.disputed += deposit.amount(),
+            DepositState::Chargeback => sums.charged_back += deposit.amount(),
+        }
+    }
+
+    let totals = engine.totals();
+    let applied = |r#type| rounding.round(totals.applied(r#type));
+    let rejected = |r#type| rounding.round(totals.rejected(r#type));
+
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    csv_writer.serialize(TrialBalanceRecord {
+        available: rounding.round(sums.available),
+        held: rounding.round(sums.held),
+        total: rounding.round(sums.total),
+        disputed: rounding.round(sums.disputed),
+        charged_back: rounding.round(sums.charged_back),
+        settlement: rounding.round(engine.settlement()),
+        imbalance: sums.total + engine.settlement(),
+        deposits_applied: applied(TransactionType::Deposit),
+        deposits_rejected: rejected(TransactionType::Deposit),
+        withdrawals_applied: applied(TransactionType::Withdrawal),
+        withdrawals_rejected: rejected(TransactionType::Withdrawal),
+        disputes_applied: applied(TransactionType::Dispute),
+        disputes_rejected: rejected(TransactionType::Dispute),
+        resolves_applied: applied(TransactionType::Resolve),
+        resolves_rejected: rejected(TransactionType::Resolve),
+        chargebacks_applied: applied(TransactionType::Chargeback),
+        chargebacks_rejected: rejected(TransactionType::Chargeback),
+        ..sums
+    })?;
+    csv_writer.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,302 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{ClientId, RawClientId, TransactionType};
+
+/// A named combination of policies, so that users get sensible behaviour without having to set
+/// each policy individually.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// Behaves exactly as the original specification describes.
+    #[default]
+    SpecCompat,
+    /// Stops at the first questionable input.
+    Strict,
+    /// Skips questionable input with a warning and carries on.
+    Lenient,
+}
+
+impl FromStr for Preset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spec-compat" => Ok(Self::SpecCompat),
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            _ => bail!("expected one of: spec-compat, strict, lenient"),
+        }
+    }
+}
+
+/// What to do with a deposit or withdrawal whose amount is zero or negative.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AmountPolicy {
+    Apply,
+    Reject,
+    Fail,
+}
+
+impl FromStr for AmountPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "apply" => Ok(Self::Apply),
+            "reject" => Ok(Self::Reject),
+            "fail" => Ok(Self::Fail),
+            _ => bail!("expected one of: apply, reject, fail"),
+        }
+    }
+}
+
+/// What to do with an input row that cannot be parsed, or that is missing a required amount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RowPolicy {
+    Fail,
+    Skip,
+}
+
+impl FromStr for RowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            _ => bail!("expected one of: fail, skip"),
+        }
+    }
+}
+
+/// What to do when a file's control record doesn't match the rows before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlPolicy {
+    Fail,
+    Warn,
+}
+
+impl FromStr for ControlPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "warn" => Ok(Self::Warn),
+            _ => bail!("expected one of: fail, warn"),
+        }
+    }
+}
+
+/// What to do with a deposit or withdrawal whose ID has already been used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    Fail,
+    /// Skip exact replays with a warning, but fail on IDs reused with different details.
+    Skip,
+    /// Reject every duplicate like any other transaction that can't be applied.
+    Reject,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            "reject" => Ok(Self::Reject),
+            _ => bail!("expected one of: fail, skip, reject"),
+        }
+    }
+}
+
+/// What to do with a deposit that would take an account over its balance limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitPolicy {
+    Reject,
+    /// Accept as much of the deposit as fits, with a warning.
+    Partial,
+}
+
+impl FromStr for LimitPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "partial" => Ok(Self::Partial),
+            _ => bail!("expected one of: reject, partial"),
+        }
+    }
+}
+
+/// A set of transaction types.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "Vec<TransactionType>")]
+pub struct TypeSet(u8);
+
+impl TypeSet {
+    pub fn insert(&mut self, r#type: TransactionType) {
+        self.0 |= 1 << r#type as u8;
+    }
+
+    pub fn contains(self, r#type: TransactionType) -> bool {
+        self.0 & (1 << r#type as u8) != 0
+    }
+}
+
+impl From<Vec<TransactionType>> for TypeSet {
+    fn from(types: Vec<TransactionType>) -> Self {
+        let mut set = Self::default();
+        for r#type in types {
+            set.insert(r#type);
+        }
+        set
+    }
+}
+
+/// Parses a comma-separated list of types.
+impl FromStr for TypeSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut set = Self::default();
+        for r#type in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            set.insert(r#type.parse()?);
+        }
+        Ok(set)
+    }
+}
+
+/// Clients whose accounts are allowed to go below zero available.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "Vec<ClientId>")]
+pub struct CreditClients(pub BTreeSet<ClientId>);
+
+impl From<Vec<ClientId>> for CreditClients {
+    fn from(clients: Vec<ClientId>) -> Self {
+        Self(clients.into_iter().collect())
+    }
+}
+
+/// Parses a comma-separated list of client IDs.
+impl FromStr for CreditClients {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut clients = BTreeSet::new();
+        for client in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let id = client
+                .parse::<RawClientId>()
+                .with_context(|| format!("invalid client ID: {client}"))?;
+            _ = clients.insert(ClientId(id));
+        }
+        Ok(Self(clients))
+    }
+}
+
+/// Balance limits for particular clients, overriding the limit for everyone else.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ClientLimits(pub BTreeMap<ClientId, Decimal>);
+
+impl TryFrom<String> for ClientLimits {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Parses a comma-separated list of `client=limit` pairs.
+impl FromStr for ClientLimits {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = BTreeMap::new();
+        for pair in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let Some((client, limit)) = pair.split_once('=') else {
+                bail!("expected client=limit: {pair}");
+            };
+
+            let id = client
+                .trim()
+                .parse::<RawClientId>()
+                .with_context(|| format!("invalid client ID: {client}"))?;
+            let limit = limit
+                .trim()
+                .parse::<Decimal>()
+                .map_err(|err| anyhow!("{err}"))
+                .with_context(|| format!("invalid limit: {limit}"))?;
+
+            if limit < Decimal::ZERO {
+                bail!("negative limit: {limit}");
+            }
+
+            if limits.insert(ClientId(id), limit).is_some() {
+                bail!("client listed more than once: {id}");
+            }
+        }
+        Ok(Self(limits))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Policy {
+    pub invalid_amount: AmountPolicy,
+    pub malformed_row: RowPolicy,
+    /// What to do with a row whose type isn't one of the known transaction types.
+    pub unknown_type: RowPolicy,
+    pub control_mismatch: ControlPolicy,
+    pub duplicate_id: DuplicatePolicy,
+    pub over_limit: LimitPolicy,
+    /// Transaction types that are rejected instead of applied.
+    pub disabled_types: TypeSet,
+}
+
+impl From<Preset> for Policy {
+    fn from(preset: Preset) -> Self {
+        match preset {
+            Preset::SpecCompat => Self {
+                invalid_amount: AmountPolicy::Apply,
+                malformed_row: RowPolicy::Fail,
+                unknown_type: RowPolicy::Fail,
+                control_mismatch: ControlPolicy::Fail,
+                duplicate_id: DuplicatePolicy::Fail,
+                over_limit: LimitPolicy::Reject,
+                disabled_types: TypeSet::default(),
+            },
+            Preset::Strict => Self {
+                invalid_amount: AmountPolicy::Fail,
+                malformed_row: RowPolicy::Fail,
+                unknown_type: RowPolicy::Fail,
+                control_mismatch: ControlPolicy::Fail,
+                duplicate_id: DuplicatePolicy::Fail,
+                over_limit: LimitPolicy::Reject,
+                disabled_types: TypeSet::default(),
+            },
+            Preset::Lenient => Self {
+                invalid_amount: AmountPolicy::Reject,
+                malformed_row: RowPolicy::Skip,
+                unknown_type: RowPolicy::Skip,
+                control_mismatch: ControlPolicy::Warn,
+                duplicate_id: DuplicatePolicy::Reject,
+                over_limit: LimitPolicy::Reject,
+                disabled_types: TypeSet::default(),
+            },
+        }
+    }
+}
@@ -0,0 +1,1104 @@
+use std::{
+    collections::BTreeSet,
+    io,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use rust_decimal::Decimal;
+
+use crate::{
+    activity::Activity,
+    aging::AgingReport,
+    alert::Alerter,
+    diffs::{Before, DiffRecorder},
+    engine::{
+        self, Account, BalanceLimits, DepositState, DisputeAges, Engine, LockReason, Retention,
+    },
+    expiry::Expiry,
+    flags::Flagger,
+    interest,
+    joint::JointAccounts,
+    ledger::Ledger,
+    live::LiveAccounts,
+    output::Rounding,
+    policy::{AmountPolicy, DuplicatePolicy, LimitPolicy, Policy, RowPolicy},
+    quarantine::Quarantine,
+    reorder::Reorderer,
+    risk::RiskReport,
+    settlement::Settlement,
+    top::TopAccounts,
+    wallet::Wallets,
+    ClientId, DeltaRecord, TraceRecord, TransactionRecord, TransactionType,
+};
+
+/// How many applied transactions to wait between publishing live account snapshots.
+const LIVE_PUBLISH_INTERVAL: usize = 10_000;
+
+/// Feeds input records through reordering into an engine, reporting on the way.
+pub struct Processor<'a> {
+    policy: Policy,
+    engine: Engine,
+    reorderer: Reorderer,
+    ready: Vec<TransactionRecord>,
+    live: Option<&'a LiveAccounts>,
+    until_publish: usize,
+    activity: Option<&'a Activity>,
+    deltas: Option<(csv::Writer<Box<dyn io::Write + 'a>>, Rounding)>,
+    alerter: Option<Alerter<'a>>,
+    flagger: Option<Flagger<'a>>,
+    trace: Option<(ClientId, csv::Writer<Box<dyn io::Write + 'a>>)>,
+    diffs: Option<DiffRecorder<'a>>,
+    quarantine: Option<Quarantine<'a>>,
+    expiry: Option<Expiry<'a>>,
+    settlement: Option<Settlement<'a>>,
+    risk: Option<RiskReport<'a>>,
+    aging: Option<AgingReport<'a>>,
+    ledger: Option<Ledger<'a>>,
+    top: Option<TopAccounts<'a>>,
+    joint: Option<JointAccounts>,
+    wallets: Option<Wallets<'a>>,
+    /// The rate to credit interest at once the input ends.
+    interest: Option<Decimal>,
+    /// How long each open dispute has been open, kept in the engine alongside the sequence
+    /// numbers.
+    ages: DisputeAges,
+}
+
+impl<'a> Processor<'a> {
+    pub fn new(reorder_window: usize, policy: Policy) -> Self {
+        Self {
+            policy,
+            engine: Engine::new()
+                .reject_non_positive_amounts(policy.invalid_amount != AmountPolicy::Apply)
+                .partial_deposits(policy.over_limit == LimitPolicy::Partial),
+            reorderer: Reorderer::new(reorder_window),
+            ready: Vec::new(),
+            live: None,
+            until_publish: LIVE_PUBLISH_INTERVAL,
+            activity: None,
+            deltas: None,
+            alerter: None,
+            flagger: None,
+            trace: None,
+            diffs: None,
+            quarantine: None,
+            expiry: None,
+            settlement: None,
+            risk: None,
+            aging: None,
+            ledger: None,
+            top: None,
+            joint: None,
+            wallets: None,
+            interest: None,
+            ages: DisputeAges::default(),
+        }
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// The engine as of the last applied transaction. Records held back for reordering are not
+    /// reflected until they are released.
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    /// Carries on from `engine` instead of starting with no accounts, keeping this processor's
+    /// policy. Any other engine settings must be set after this.
+    pub fn with_engine(mut self, engine: Engine) -> Self {
+        self.reorderer.resume(engine.sequences());
+        self.ages = engine.dispute_ages().clone();
+        self.engine = engine
+            .reject_non_positive_amounts(self.policy.invalid_amount != AmountPolicy::Apply)
+            .partial_deposits(self.policy.over_limit == LimitPolicy::Partial);
+        self
+    }
+
+    /// Skips gaps in clients' sequence numbers once records have waited on them for `timeout`.
+    pub fn with_reorder_timeout(mut self, timeout: Duration) -> Self {
+        self.reorderer = self.reorderer.with_timeout(timeout);
+        self
+    }
+
+    /// Lets the engine forget deposits that are unlikely to be needed again.
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.engine = self.engine.retention(retention);
+        self
+    }
+
+    /// Lets these clients' accounts go below zero available.
+    pub fn with_credit_clients(mut self, clients: BTreeSet<ClientId>) -> Self {
+        self.engine = self.engine.credit_clients(clients);
+        self
+    }
+
+    /// Rejects deposits that would take an account over its limit, or accepts part of them,
+    /// depending on the policy.
+    pub fn with_balance_limits(mut self, limits: BalanceLimits) -> Self {
+        self.engine = self.engine.balance_limits(limits);
+        self
+    }
+
+    /// Periodically publishes the engine's accounts to `live`.
+    pub fn with_live(mut self, live: &'a LiveAccounts) -> Self {
+        self.live = Some(live);
+        self
+    }
+
+    /// Adds every applied transaction to `activity`, for the extended account report.
+    pub fn with_activity(mut self, activity: &'a Activity) -> Self {
+        self.activity = Some(activity);
+        self
+    }
+
+    /// Writes a [`DeltaRecord`] to `writer` every time an account's balances change.
+    pub fn with_deltas(mut self, writer: impl io::Write + 'a, rounding: Rounding) -> Self {
+        self.deltas = Some((csv::Writer::from_writer(Box::new(writer)), rounding));
+        self
+    }
+
+    /// Checks every applied transaction against `alerter`'s thresholds.
+    pub fn with_alerter(mut self, alerter: Alerter<'a>) -> Self {
+        self.alerter = Some(alerter);
+        self
+    }
+
+    /// Checks every applied transaction against `flagger`'s heuristics.
+    pub fn with_flagger(mut self, flagger: Flagger<'a>) -> Self {
+        self.flagger = Some(flagger);
+        self
+    }
+
+    /// Applies transactions from clients that share an account to that account. Deposits made on
+    /// shared accounts in the engine's earlier runs are kept, so this must be set after
+    /// [`Self::with_engine`].
+    pub fn with_joint_accounts(mut self, mut joint: JointAccounts) -> Self {
+        joint.resume(self.engine.joint_origins().clone());
+        self.joint = Some(joint);
+        self
+    }
+
+    /// Applies transactions that name a wallet to that wallet instead of the main account.
+    ///
+    /// Wallet transactions are reported by `wallets` alone, not as deltas, traces, alerts, or
+    /// settlement.
+    pub fn with_wallets(mut self, wallets: Wallets<'a>) -> Self {
+        self.wallets = Some(wallets);
+        self
+    }
+
+    /// Adds every applied transaction to `settlement`, which is written out by [`Self::finish`].
+    pub fn with_settlement(mut self, settlement: Settlement<'a>) -> Self {
+        self.settlement = Some(settlement);
+        self
+    }
+
+    /// Adds every applied transaction to `risk`, which is written out by [`Self::finish`].
+    pub fn with_risk_report(mut self, risk: RiskReport<'a>) -> Self {
+        self.risk = Some(risk);
+        self
+    }
+
+    /// Has `aging` write out held funds by how long they have been disputed when [`Self::finish`]
+    /// is called.
+    pub fn with_aging_report(mut self, aging: AgingReport<'a>) -> Self {
+        self.aging = Some(aging);
+        self
+    }
+
+    /// Writes every applied transaction to `ledger` as postings.
+    pub fn with_ledger(mut self, ledger: Ledger<'a>) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    /// Has `top` write the largest accounts when [`Self::finish`] is called.
+    pub fn with_top(mut self, top: TopAccounts<'a>) -> Self {
+        self.top = Some(top);
+        self
+    }
+
+    /// Writes a [`TraceRecord`] to `writer` for every one of `client`'s transactions, whether or
+    /// not it was applied.
+    pub fn with_trace(mut self, client: ClientId, writer: impl io::Write + 'a) -> Self {
+        self.trace = Some((client, csv::Writer::from_writer(Box::new(writer))));
+        self
+    }
+
+    /// Records how every transaction that reaches the engine changes it, for debugging.
+    pub fn with_diffs(mut self, diffs: DiffRecorder<'a>) -> Self {
+        self.diffs = Some(diffs);
+        self
+    }
+
+    /// Keeps the transactions that refer to deposits that can't be found in `quarantine`, which
+    /// may retry them once the input ends.
+    pub fn with_quarantine(mut self, quarantine: Quarantine<'a>) -> Self {
+        self.quarantine = Some(quarantine);
+        self
+    }
+
+    /// Lapses disputes that `expiry` finds have been open too long. Disputes already open in the
+    /// engine carry on aging from when they were opened, so this must be set after
+    /// [`Self::with_engine`].
+    pub fn with_expiry(mut self, mut expiry: Expiry<'a>) -> Self {
+        let mut opened: Vec<_> = self.ages.iter().collect();
+        opened.sort_by_key(|&(tx, opened_at)| (opened_at, tx));
+
+        for (tx, opened_at) in opened {
+            expiry.opened(tx, opened_at);
+        }
+
+        self.expiry = Some(expiry);
+        self
+    }
+
+    /// Credits every unlocked account with interest at `rate` once the input ends, reporting the
+    /// credits like any other deposit.
+    pub fn with_interest(mut self, rate: Decimal) -> Self {
+        self.interest = Some(rate);
+        self
+    }
+
+    pub fn push(&mut self, transaction: TransactionRecord) -> Result<(), anyhow::Error> {
+        let mut ready = std::mem::take(&mut self.ready);
+        self.reorderer.push(transaction, &mut ready);
+
+        for transaction in ready.drain(..) {
+            self.apply(&transaction)?;
+        }
+
+        self.ready = ready;
+        Ok(())
+    }
+
+    /// Applies every transaction in `batch`, along with any held back for reordering, or none of
+    /// them. If one fails, the engine and the reordering are left as they were before the batch,
+    /// and the error is returned. Whatever was already reported about the batch stays reported.
+    pub fn push_batch(
+        &mut self,
+        batch: impl IntoIterator<Item = TransactionRecord>,
+    ) -> Result<(), anyhow::Error> {
+        let savepoint = self.engine.savepoint();
+        let reorderer = self.reorderer.clone();
+        let ages = self.ages.clone();
+
+        match self.push_all(batch) {
+            Ok(()) => {
+                self.engine.release(savepoint)?;
+                self.save_progress();
+                Ok(())
+            }
+            Err(err) => {
+                self.engine.rollback_to(savepoint)?;
+                self.reorderer = reorderer;
+                self.ages = ages;
+
+                if let Some(joint) = &mut self.joint {
+                    joint.resume(self.engine.joint_origins().clone());
+                }
+
+                self.ready.clear();
+                Err(err)
+            }
+        }
+    }
+
+    fn push_all(
+        &mut self,
+        batch: impl IntoIterator<Item = TransactionRecord>,
+    ) -> Result<(), anyhow::Error> {
+        for transaction in batch {
+            self.push(transaction)?;
+        }
+
+        self.release_held()
+    }
+
+    /// Applies every transaction that has waited longer than the reorder timeout on a gap in its
+    /// client's sequence, skipping the gap. Returns whether any were applied.
+    pub fn release_expired(&mut self) -> Result<bool, anyhow::Error> {
+        let mut ready = std::mem::take(&mut self.ready);
+        self.reorderer.release_expired(Instant::now(), &mut ready);
+        let released = !ready.is_empty();
+
+        for transaction in ready.drain(..) {
+            self.apply(&transaction)?;
+        }
+
+        self.ready = ready;
+        Ok(released)
+    }
+
+    /// Applies every transaction still held back for reordering, in sequence order.
+    fn release_held(&mut self) -> Result<(), anyhow::Error> {
+        let mut ready = std::mem::take(&mut self.ready);
+        self.reorderer.finish(&mut ready);
+
+        for transaction in ready.drain(..) {
+            self.apply(&transaction)?;
+        }
+
+        self.ready = ready;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Engine, anyhow::Error> {
+        self.release_held()?;
+
+        let retries = self
+            .quarantine
+            .as_mut()
+            .map(Quarantine::take_retries)
+            .unwrap_or_default();
+
+        for transaction in retries {
+            self.apply(&transaction)?;
+        }
+
+        // Credits skip joint account mapping and the policy's disabled types, like lapsed
+        // disputes, since they don't come from the input.
+        if let Some(rate) = self.interest {
+            for credit in interest::credits(&self.engine, rate)? {
+                self.apply_to_engine(&credit, &credit)?;
+            }
+        }
+
+        if let Some(live) = self.live {
+            live.publish(&self.engine);
+        }
+
+        self.flush()?;
+
+        if let Some(quarantine) = &mut self.quarantine {
+            quarantine.finish()?;
+        }
+
+        if let Some(expiry) = &mut self.expiry {
+            expiry.finish()?;
+        }
+
+        if let Some(settlement) = &mut self.settlement {
+            settlement.finish()?;
+        }
+
+        if let Some(risk) = &mut self.risk {
+            risk.finish(&self.engine)?;
+        }
+
+        if let Some(aging) = &mut self.aging {
+            aging.finish(&self.engine, &self.ages)?;
+        }
+
+        if let Some(top) = &mut self.top {
+            top.finish(&self.engine)?;
+        }
+
+        if let Some(wallets) = &mut self.wallets {
+            wallets.finish()?;
+        }
+
+        self.save_progress();
+
+        Ok(self.engine)
+    }
+
+    /// Keeps what the processor tracks alongside the engine in it, so that it is saved with the
+    /// state.
+    fn save_progress(&mut self) {
+        self.engine.set_sequences(self.reorderer.sequences());
+        self.engine.set_dispute_ages(self.ages.clone());
+
+        if let Some(joint) = &self.joint {
+            self.engine.set_joint_origins(joint.origins().clone());
+        }
+    }
+
+    /// Flushes everything that is written as transactions are applied, so that readers see it
+    /// without waiting for the input to end.
+    pub fn flush(&mut self) -> Result<(), anyhow::Error> {
+        if let Some((deltas, _)) = &mut self.deltas {
+            deltas.flush()?;
+        }
+
+        if let Some(alerter) = &mut self.alerter {
+            alerter.flush()?;
+        }
+
+        if let Some(flagger) = &mut self.flagger {
+            flagger.flush()?;
+        }
+
+        if let Some((_, trace)) = &mut self.trace {
+            trace.flush()?;
+        }
+
+        if let Some(diffs) = &mut self.diffs {
+            diffs.flush()?;
+        }
+
+        if let Some(ledger) = &mut self.ledger {
+            ledger.flush()?;
+        }
+
+        if let Some(quarantine) = &mut self.quarantine {
+            quarantine.flush()?;
+        }
+
+        if let Some(expiry) = &mut self.expiry {
+            expiry.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Passes a transaction that has just been applied to everything that reports on them, along
+    /// with the amount of the deposit it disputes, resolves, or charges back.
+    fn report(
+        &mut self,
+        transaction: &TransactionRecord,
+        disputed: Option<Decimal>,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(activity) = self.activity {
+            activity.record(transaction);
+        }
+
+        if let Some(alerter) = &mut self.alerter {
+            alerter.check(transaction)?;
+        }
+
+        if let Some(flagger) = &mut self.flagger {
+            if flagger.check(transaction)? && flagger.locks() {
+                self.engine.lock(
+                    transaction.client,
+                    LockReason::RiskRule,
+                    Some(transaction.tx),
+                )?;
+            }
+        }
+
+        if let Some(settlement) = &mut self.settlement {
+            settlement.record(transaction, disputed);
+        }
+
+        if let Some(risk) = &mut self.risk {
+            risk.record(transaction, disputed);
+        }
+
+        if let Some(ledger) = &mut self.ledger {
+            ledger.record(transaction, disputed)?;
+        }
+
+        if let Some(top) = &mut self.top {
+            top.record(transaction);
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, original: &TransactionRecord) -> Result<(), anyhow::Error> {
+        self.ages.tick();
+        self.lapse_disputes()?;
+
+        let mapped;
+        let transaction = match &self.joint {
+            Some(joint) => {
+                mapped = joint.map(original)?;
+                &mapped
+            }
+            None => original,
+        };
+
+        if self.policy.disabled_types.contains(transaction.r#type) {
+            eprintln!(
+                "warning: transaction type disabled (tx: {}, type: {})",
+                transaction.tx, transaction.r#type
+            );
+            self.trace(transaction, "disabled")?;
+            return Ok(());
+        }
+
+        let needs_amount = matches!(
+            transaction.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+
+        if needs_amount && transaction.amount.is_none() {
+            match self.policy.malformed_row {
+                RowPolicy::Fail => bail!("missing amount (tx: {})", transaction.tx),
+                RowPolicy::Skip => {
+                    eprintln!(
+                        "warning: skipping row with missing amount (tx: {})",
+                        transaction.tx
+                    );
+                    self.trace(transaction, "missing amount")?;
+                    return Ok(());
+                }
+            }
+        }
+
+        // It can't be the same transaction replayed, since it went to a different engine.
+        if self.id_taken_elsewhere(transaction) {
+            let err = engine::Error::ConflictingDuplicate(transaction.tx);
+
+            if self.policy.duplicate_id != DuplicatePolicy::Reject {
+                return Err(err.into());
+            }
+
+            eprintln!("warning: {err}");
+            self.trace(transaction, &err.to_string())?;
+            return Ok(());
+        }
+
+        if let (Some(wallets), Some(wallet)) = (&mut self.wallets, &transaction.wallet) {
+            let engine = wallets.engine(wallet, || self.engine.new_like());
+
+            if let Outcome::Rejected(err) = apply(engine, transaction, self.policy)? {
+                eprintln!("warning: {err} (wallet: {wallet})");
+            }
+
+            return Ok(());
+        }
+
+        self.apply_to_engine(original, transaction)
+    }
+
+    /// Whether `transaction` is a deposit or withdrawal whose ID is already taken in the main
+    /// engine or a wallet other than its own, since they share one ID space. Each engine checks
+    /// its own IDs.
+    fn id_taken_elsewhere(&self, transaction: &TransactionRecord) -> bool {
+        let Some(wallets) = &self.wallets else {
+            return false;
+        };
+
+        if !matches!(
+            transaction.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            return false;
+        }
+
+        let wallet = transaction.wallet.as_deref();
+        (wallet.is_some() && self.engine.is_known_id(transaction.tx))
+            || wallets.is_known_id(transaction.tx, wallet)
+    }
+
+    /// Applies a transaction to the engine, once it has been mapped to its account and checked
+    /// against the policy.
+    fn apply_to_engine(
+        &mut self,
+        original: &TransactionRecord,
+        transaction: &TransactionRecord,
+    ) -> Result<(), anyhow::Error> {
+        let before = self
+            .engine
+            .account(transaction.client)
+            .copied()
+            .unwrap_or_default();
+
+        let diff_before = self
+            .diffs
+            .is_some()
+            .then(|| Before::new(&self.engine, transaction));
+
+        // Taken before applying, since a chargeback may make the engine forget the deposit. This
+        // also finds disputes that were opened before a saved state was loaded.
+        let disputed = match transaction.r#type {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                self.engine
+                    .deposit_by_id(transaction.tx)
+                    .map(engine::Deposit::amount)
+            }
+            TransactionType::Deposit | TransactionType::Withdrawal => None,
+        };
+
+        let outcome = apply(&mut self.engine, transaction, self.policy)?;
+
+        if let (Some(diffs), Some(before)) = (&mut self.diffs, diff_before) {
+            let result = match &outcome {
+                Outcome::Applied => "applied".to_owned(),
+                Outcome::Rejected(err) => err.to_string(),
+            };
+
+            diffs.record(transaction, &result, before, &self.engine)?;
+        }
+
+        let partial = match outcome {
+            Outcome::Applied => self.partially_accepted(transaction),
+            Outcome::Rejected(_) => None,
+        };
+
+        let accepted;
+        let transaction = match partial {
+            Some(partial) => {
+                accepted = partial;
+                &accepted
+            }
+            None => transaction,
+        };
+
+        match outcome {
+            Outcome::Applied => {
+                if let Some(joint) = &mut self.joint {
+                    joint.applied(original);
+                }
+
+                match transaction.r#type {
+                    TransactionType::Dispute => {
+                        self.ages.open(transaction.tx);
+
+                        if let Some(expiry) = &mut self.expiry {
+                            expiry.opened(transaction.tx, self.ages.seen());
+                        }
+                    }
+                    TransactionType::Resolve | TransactionType::Chargeback => {
+                        self.ages.close(transaction.tx);
+                    }
+                    TransactionType::Deposit | TransactionType::Withdrawal => (),
+                }
+
+                self.trace(transaction, "applied")?;
+            }
+            Outcome::Rejected(err) => {
+                let deferred = match (&err, &mut self.quarantine) {
+                    (engine::Error::TransactionNotFound(_), Some(quarantine)) => {
+                        quarantine.add(original)?
+                    }
+                    _ => false,
+                };
+
+                // Deferred transactions are only warned about if they fail again.
+                if !deferred {
+                    eprintln!("warning: {err}");
+                }
+
+                self.trace(transaction, &err.to_string())?;
+                return Ok(());
+            }
+        }
+
+        self.report(transaction, disputed)?;
+
+        self.delta(transaction, before)?;
+
+        self.until_publish -= 1;
+        if self.until_publish == 0 {
+            self.until_publish = LIVE_PUBLISH_INTERVAL;
+
+            if let Some(live) = self.live {
+                live.publish(&self.engine);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an applied `transaction` with the amount the engine accepted, if it was a deposit
+    /// that only partly fit under its account's limit.
+    fn partially_accepted(&self, transaction: &TransactionRecord) -> Option<TransactionRecord> {
+        if transaction.r#type != TransactionType::Deposit {
+            return None;
+        }
+
+        let requested = transaction.amount?;
+        let deposit = self.engine.deposit_by_id(transaction.tx)?;
+
+        if deposit.amount() == requested {
+            return None;
+        }
+
+        eprintln!(
+            "warning: deposit partially accepted over balance limit (tx: {}, accepted: {}, \
+            requested: {requested})",
+            transaction.tx,
+            deposit.amount(),
+        );
+
+        Some(TransactionRecord {
+            amount: Some(deposit.amount()),
+            ..transaction.clone()
+        })
+    }
+
+    /// Resolves every dispute that has now been open too long.
+    ///
+    /// These resolves are reported like any other, but skip joint account mapping and the
+    /// policy's disabled types, since they don't come from the input.
+    fn lapse_disputes(&mut self) -> Result<(), anyhow::Error> {
+        let Some(expiry) = &mut self.expiry else {
+            return Ok(());
+        };
+
+        for tx in expiry.due(&self.ages) {
+            let Some(&deposit) = self.engine.deposit_by_id(tx) else {
+                continue;
+            };
+
+            if deposit.state != DepositState::Dispute {
+                continue;
+            }
+
+            let resolve = TransactionRecord {
+                r#type: TransactionType::Resolve,
+                client: deposit.client,
+                tx,
+                amount: None,
+                seq: None,
+                wallet: None,
+            };
+
+            self.apply_to_engine(&resolve, &resolve)?;
+
+            let resolved = self
+                .engine
+                .deposit_by_id(tx)
+                .is_some_and(|deposit| deposit.state == DepositState::Ok);
+
+            if let (true, Some(expiry)) = (resolved, &mut self.expiry) {
+                expiry.lapse(deposit.client, tx, deposit.amount())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a delta if `transaction` changed its account from `before`.
+    fn delta(
+        &mut self,
+        transaction: &TransactionRecord,
+        before: Account,
+    ) -> Result<(), csv::Error> {
+        let Some((deltas, rounding)) = &mut self.deltas else {
+            return Ok(());
+        };
+
+        let after = self
+            .engine
+            .account(transaction.client)
+            .copied()
+            .unwrap_or_default();
+
+        if after == before {
+            return Ok(());
+        }
+
+        deltas.serialize(DeltaRecord {
+            client: transaction.client,
+            tx: transaction.tx,
+            r#type: transaction.r#type,
+            available: rounding.round(after.available()),
+            held: rounding.round(after.held()),
+            total: rounding.round(after.total()),
+            locked: after.locked(),
+        })
+    }
+
+    fn trace(&mut self, transaction: &TransactionRecord, result: &str) -> Result<(), csv::Error> {
+        let Some((client, trace)) = &mut self.trace else {
+            return Ok(());
+        };
+
+        if transaction.client != *client {
+            return Ok(());
+        }
+
+        let account = self.engine.account(*client).copied().unwrap_or_default();
+
+        trace.serialize(TraceRecord {
+            tx: transaction.tx,
+            r#type: transaction.r#type,
+            amount: transaction.amount,
+            result,
+            available: account.available(),
+            held: account.held(),
+            total: account.total(),
+            locked: account.locked(),
+        })
+    }
+}
+
+/// What happened to a transaction that did not stop processing.
+enum Outcome {
+    Applied,
+    Rejected(engine::Error),
+}
+
+fn apply(
+    engine: &mut Engine,
+    transaction: &TransactionRecord,
+    policy: Policy,
+) -> Result<Outcome, anyhow::Error> {
+    match engine.apply(transaction) {
+        Ok(()) => Ok(Outcome::Applied),
+
+        Err(fatal @ (engine::Error::ClientMismatch { .. } | engine::Error::MissingAmount(_))) => {
+            Err(fatal.into())
+        }
+
+        Err(duplicate @ engine::Error::DuplicateTransactionId(_))
+            if policy.duplicate_id == DuplicatePolicy::Fail =>
+        {
+            Err(duplicate.into())
+        }
+
+        Err(conflicting @ engine::Error::ConflictingDuplicate(_))
+            if policy.duplicate_id != DuplicatePolicy::Reject =>
+        {
+            Err(conflicting.into())
+        }
+
+        Err(invalid @ engine::Error::InvalidAmount { .. })
+            if policy.invalid_amount == AmountPolicy::Fail =>
+        {
+            Err(invalid.into())
+        }
+
+        Err(nonfatal) => Ok(Outcome::Rejected(nonfatal)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::{policy::Preset, TransactionId};
+
+    fn record(r#type: TransactionType, id: u16, seq: Option<u64>) -> TransactionRecord {
+        TransactionRecord {
+            r#type,
+            client: ClientId(1),
+            tx: TransactionId(id.into()),
+            amount: Some(Decimal::from(10)),
+            seq,
+            wallet: None,
+        }
+    }
+
+    #[test]
+    fn rolls_back_failed_batch() {
+        let mut processor = Processor::new(16, Policy::from(Preset::SpecCompat));
+        processor
+            .push_batch([record(TransactionType::Deposit, 1, None)])
+            .unwrap();
+
+        let batch = [
+            record(TransactionType::Deposit, 2, None),
+            record(TransactionType::Deposit, 2, None),
+        ];
+        assert!(processor.push_batch(batch).is_err());
+
+        let account = processor.engine().account(ClientId(1)).unwrap();
+        assert_eq!(account.total(), Decimal::from(10));
+        assert!(processor.engine().deposit_by_id(TransactionId(2)).is_none());
+    }
+
+    #[test]
+    fn applies_held_records_with_batch() {
+        let mut processor = Processor::new(16, Policy::from(Preset::SpecCompat));
+
+        let batch = [
+            record(TransactionType::Deposit, 1, Some(1)),
+            record(TransactionType::Deposit, 2, Some(3)),
+        ];
+        processor.push_batch(batch).unwrap();
+
+        let account = processor.engine().account(ClientId(1)).unwrap();
+        assert_eq!(account.total(), Decimal::from(20));
+    }
+
+    #[test]
+    fn reports_interest_credits() {
+        let mut deltas = Vec::new();
+        let rounding = Rounding {
+            normalize: true,
+            ..Rounding::default()
+        };
+        let mut processor = Processor::new(16, Policy::from(Preset::SpecCompat))
+            .with_deltas(&mut deltas, rounding)
+            .with_interest(Decimal::new(1, 1));
+
+        processor
+            .push(record(TransactionType::Deposit, 1, None))
+            .unwrap();
+        let engine = processor.finish().unwrap();
+
+        assert_eq!(
+            engine.account(ClientId(1)).unwrap().total(),
+            Decimal::from(11)
+        );
+        assert_eq!(
+            String::from_utf8(deltas).unwrap(),
+            "client,tx,type,available,held,total,locked\n\
+            1,1,deposit,10,0,10,false\n\
+            1,2,deposit,11,0,11,false\n"
+        );
+    }
+
+    #[test]
+    fn shares_ids_and_settings_with_wallets() {
+        let mut output = Vec::new();
+        let wallets = Wallets::new(&mut output, Rounding::default());
+        let policy = Policy {
+            duplicate_id: DuplicatePolicy::Reject,
+            ..Policy::from(Preset::SpecCompat)
+        };
+        let mut processor = Processor::new(16, policy)
+            .with_credit_clients(BTreeSet::from([ClientId(1)]))
+            .with_wallets(wallets);
+
+        let in_wallet = |r#type, id| TransactionRecord {
+            wallet: Some("bonus".to_owned()),
+            ..record(r#type, id, None)
+        };
+
+        processor
+            .push(record(TransactionType::Deposit, 1, None))
+            .unwrap();
+        // Reuses the main account's ID, so it is rejected.
+        processor
+            .push(in_wallet(TransactionType::Deposit, 1))
+            .unwrap();
+        // Goes below zero, as a credit client may.
+        processor
+            .push(in_wallet(TransactionType::Withdrawal, 2))
+            .unwrap();
+        // Reuses the wallet's ID, so it is rejected.
+        processor
+            .push(record(TransactionType::Withdrawal, 2, None))
+            .unwrap();
+        let engine = processor.finish().unwrap();
+
+        assert_eq!(
+            engine.account(ClientId(1)).unwrap().total(),
+            Decimal::from(10)
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,wallet,available,held,total,locked\n1,bonus,-10,0,-10,false\n"
+        );
+    }
+
+    #[test]
+    fn reports_disputes_of_loaded_and_forgotten_deposits() {
+        let mut engine = Engine::new();
+        for tx in [1, 2] {
+            engine
+                .deposit(ClientId(1), TransactionId(tx), Decimal::from(10))
+                .unwrap();
+        }
+        engine.dispute(ClientId(1), TransactionId(1)).unwrap();
+
+        let mut ledger = Vec::new();
+        let mut settlement = Vec::new();
+        let rounding = Rounding {
+            normalize: true,
+            ..Rounding::default()
+        };
+        let mut processor = Processor::new(16, Policy::from(Preset::SpecCompat))
+            .with_engine(engine)
+            .with_retention(Retention {
+                forget_chargebacks: true,
+                forget_resolved_after: None,
+            })
+            .with_ledger(Ledger::new(&mut ledger, rounding))
+            .with_settlement(Settlement::new(&mut settlement, rounding));
+
+        // The first was disputed before the state was loaded, and both are forgotten once
+        // charged back.
+        for (r#type, id) in [
+            (TransactionType::Chargeback, 1),
+            (TransactionType::Dispute, 2),
+            (TransactionType::Chargeback, 2),
+        ] {
+            processor.push(record(r#type, id, None)).unwrap();
+        }
+        processor.finish().unwrap();
+
+        assert_eq!(
+            String::from_utf8(ledger).unwrap(),
+            "tx,type,account,amount\n\
+            1,chargeback,client:1:held,10\n\
+            1,chargeback,omnibus,-10\n\
+            2,dispute,client:1:available,10\n\
+            2,dispute,client:1:held,-10\n\
+            2,chargeback,client:1:held,10\n\
+            2,chargeback,omnibus,-10\n"
+        );
+        assert_eq!(
+            String::from_utf8(settlement).unwrap(),
+            "client,deposits,withdrawals,chargebacks,net\n1,0,0,20,-20\n"
+        );
+    }
+
+    #[test]
+    fn ages_disputes_across_loads() {
+        let mut processor =
+            Processor::new(16, Policy::from(Preset::SpecCompat)).with_expiry(Expiry::new(2));
+        for (r#type, id) in [
+            (TransactionType::Deposit, 1),
+            (TransactionType::Dispute, 1),
+            (TransactionType::Deposit, 2),
+        ] {
+            processor.push(record(r#type, id, None)).unwrap();
+        }
+        let engine = processor.finish().unwrap();
+
+        // The dispute has been open for one transaction, so it lapses two transactions into the
+        // next run rather than three.
+        let mut processor = Processor::new(16, Policy::from(Preset::SpecCompat))
+            .with_engine(engine)
+            .with_expiry(Expiry::new(2));
+        processor
+            .push(record(TransactionType::Deposit, 3, None))
+            .unwrap();
+        assert_eq!(
+            processor
+                .engine()
+                .deposit_by_id(TransactionId(1))
+                .unwrap()
+                .state,
+            DepositState::Dispute
+        );
+        processor
+            .push(record(TransactionType::Deposit, 4, None))
+            .unwrap();
+        assert_eq!(
+            processor
+                .engine()
+                .deposit_by_id(TransactionId(1))
+                .unwrap()
+                .state,
+            DepositState::Ok
+        );
+    }
+
+    #[test]
+    fn keeps_joint_deposit_origins_across_loads() {
+        let path = env::temp_dir().join(format!("spork-joint-{}", process::id()));
+        fs::write(&path, "client,account\n1,1\n2,1\n").unwrap();
+        let joint = || JointAccounts::load(&path).unwrap();
+
+        let mut processor =
+            Processor::new(16, Policy::from(Preset::SpecCompat)).with_joint_accounts(joint());
+        processor
+            .push(TransactionRecord {
+                client: ClientId(2),
+                ..record(TransactionType::Deposit, 1, None)
+            })
+            .unwrap();
+        let engine = processor.finish().unwrap();
+
+        // The deposit was made by the other client on the shared account in the last run.
+        let mut processor = Processor::new(16, Policy::from(Preset::SpecCompat))
+            .with_engine(engine)
+            .with_joint_accounts(joint());
+        fs::remove_file(&path).unwrap();
+        assert!(processor
+            .push(record(TransactionType::Dispute, 1, None))
+            .is_err());
+    }
+}
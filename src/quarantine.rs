@@ -0,0 +1,83 @@
+use std::io;
+
+use crate::TransactionRecord;
+
+/// Keeps the disputes, resolves, and chargebacks whose deposits couldn't be found, rather than
+/// only warning about them.
+///
+/// They may be retried once the input ends, and any kept for good are written in the input
+/// format, so the file can be processed again once the missing deposits turn up.
+#[derive(Default)]
+pub struct Quarantine<'a> {
+    writer: Option<csv::Writer<Box<dyn io::Write + 'a>>>,
+    /// Transactions held back to be retried once the input ends, if they are being retried.
+    retries: Option<Vec<TransactionRecord>>,
+    /// Whether the held transactions have been taken for retrying.
+    retried: bool,
+    /// Transactions that still failed once retried.
+    unmatched: u64,
+}
+
+impl<'a> Quarantine<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes every transaction that isn't held back, or that fails again when retried.
+    pub fn with_writer(mut self, writer: impl io::Write + 'a) -> Self {
+        self.writer = Some(csv::Writer::from_writer(Box::new(writer)));
+        self
+    }
+
+    /// Holds transactions back to be retried once the input ends, in case their deposits come
+    /// later, and only keeps those that still fail.
+    pub fn retrying(mut self) -> Self {
+        self.retries = Some(Vec::new());
+        self
+    }
+
+    /// Keeps a transaction whose deposit wasn't found, and returns whether it was held back to
+    /// be retried.
+    pub fn add(&mut self, transaction: &TransactionRecord) -> Result<bool, csv::Error> {
+        if let Some(retries) = &mut self.retries {
+            retries.push(transaction.clone());
+            return Ok(true);
+        }
+
+        if self.retried {
+            self.unmatched += 1;
+        }
+
+        if let Some(writer) = &mut self.writer {
+            writer.serialize(transaction)?;
+        }
+
+        Ok(false)
+    }
+
+    /// Takes the transactions held back for retrying. Any added after this are kept for good.
+    pub fn take_retries(&mut self) -> Vec<TransactionRecord> {
+        self.retried = self.retries.is_some();
+        self.retries.take().unwrap_or_default()
+    }
+
+    /// Reports how many transactions were still unmatched after retrying, and flushes the
+    /// writer.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.unmatched > 0 {
+            eprintln!(
+                "warning: {} transactions still unmatched after retrying",
+                self.unmatched
+            );
+        }
+
+        self.flush()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
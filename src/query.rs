@@ -0,0 +1,118 @@
+use std::io;
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    cli::{AccountsArgs, TxArgs},
+    engine::{Deposit, DepositState, Lock, LockReason},
+    output::{self, Rounding},
+    state, ClientId, TransactionId,
+};
+
+#[derive(Clone, Debug, Serialize)]
+struct DepositRecord {
+    tx: TransactionId,
+    client: ClientId,
+    amount: Decimal,
+    state: DepositState,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct LockRecord {
+    client: ClientId,
+    reason: LockReason,
+    tx: Option<TransactionId>,
+}
+
+/// Prints the accounts in a saved state, or just one client's account.
+pub fn accounts(args: &AccountsArgs) -> Result<(), anyhow::Error> {
+    let engine = state::load(&args.state)?;
+
+    let accounts: Vec<_> = match &args.client {
+        Some(client) => {
+            let account = engine
+                .account(*client)
+                .with_context(|| format!("client not found: {client}"))?;
+            vec![(client, account)]
+        }
+        None => engine.accounts().collect(),
+    };
+
+    output::write_accounts(
+        io::stdout().lock(),
+        accounts,
+        Rounding::default(),
+        None,
+        None,
+    )?;
+
+    Ok(())
+}
+
+/// Prints everything that locked an account in a saved state, in order, or just what locked one
+/// client's account.
+pub fn locks(args: &AccountsArgs) -> Result<(), anyhow::Error> {
+    let engine = state::load(&args.state)?;
+
+    let locks = engine
+        .locks()
+        .iter()
+        .filter(|(client, _)| args.client.is_none_or(|only| *client == only));
+
+    write_locks(io::stdout().lock(), locks)?;
+
+    Ok(())
+}
+
+/// Prints a deposit from a saved state, including whether it is disputed or charged back.
+///
+/// Only deposits are kept by the engine, so other transaction IDs are never found.
+pub fn tx(args: &TxArgs) -> Result<(), anyhow::Error> {
+    let engine = state::load(&args.state)?;
+
+    let deposit = engine
+        .deposit_by_id(args.tx)
+        .with_context(|| format!("deposit not found: {}", args.tx))?;
+
+    write_deposit(io::stdout().lock(), args.tx, deposit)?;
+
+    Ok(())
+}
+
+pub fn write_deposit(
+    writer: impl io::Write,
+    tx: TransactionId,
+    deposit: &Deposit,
+) -> Result<(), csv::Error> {
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    csv_writer.serialize(DepositRecord {
+        tx,
+        client: deposit.client,
+        amount: deposit.amount(),
+        state: deposit.state,
+    })?;
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+pub fn write_locks<'a>(
+    writer: impl io::Write,
+    locks: impl IntoIterator<Item = &'a (ClientId, Lock)>,
+) -> Result<(), csv::Error> {
+    let mut csv_writer = csv::Writer::from_writer(writer);
+
+    for &(client, lock) in locks {
+        csv_writer.serialize(LockRecord {
+            client,
+            reason: lock.reason,
+            tx: lock.tx,
+        })?;
+    }
+
+    csv_writer.flush()?;
+
+    Ok(())
+}
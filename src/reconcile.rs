@@ -0,0 +1,135 @@
+use std::{collections::BTreeMap, io, path::Path};
+
+use anyhow::{bail, Context};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{cli::ReconcileArgs, state, ClientId};
+
+/// A row of the expected balances file. `locked` is only compared if the file has it.
+#[derive(Clone, Copy, Debug, Deserialize)]
+struct ExpectedRecord {
+    client: ClientId,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    #[serde(default)]
+    locked: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Field {
+    /// The account is in only one of the engine and the expected balances.
+    Account,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct DiscrepancyRecord {
+    client: ClientId,
+    field: Field,
+    expected: String,
+    actual: String,
+}
+
+/// Compares the accounts in a saved state with balances produced elsewhere, and reports every
+/// difference on stdout.
+///
+/// Amounts that differ by no more than the tolerance are taken to match.
+pub fn run(args: &ReconcileArgs) -> Result<(), anyhow::Error> {
+    let engine = state::load(&args.state)?;
+    let expected = load_expected(&args.expected)?;
+
+    let mut discrepancies = Vec::new();
+
+    for (&client, expected) in &expected {
+        let Some(account) = engine.account(client) else {
+            discrepancies.push(DiscrepancyRecord {
+                client,
+                field: Field::Account,
+                expected: "present".to_owned(),
+                actual: "missing".to_owned(),
+            });
+            continue;
+        };
+
+        let amounts = [
+            (Field::Available, expected.available, account.available()),
+            (Field::Held, expected.held, account.held()),
+            (Field::Total, expected.total, account.total()),
+        ];
+
+        for (field, expected, actual) in amounts {
+            if (expected - actual).abs() > args.tolerance {
+                discrepancies.push(DiscrepancyRecord {
+                    client,
+                    field,
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                });
+            }
+        }
+
+        if let Some(locked) = expected.locked.filter(|&locked| locked != account.locked()) {
+            discrepancies.push(DiscrepancyRecord {
+                client,
+                field: Field::Locked,
+                expected: locked.to_string(),
+                actual: account.locked().to_string(),
+            });
+        }
+    }
+
+    for (&client, _) in engine.accounts() {
+        if !expected.contains_key(&client) {
+            discrepancies.push(DiscrepancyRecord {
+                client,
+                field: Field::Account,
+                expected: "missing".to_owned(),
+                actual: "present".to_owned(),
+            });
+        }
+    }
+
+    if discrepancies.is_empty() {
+        return Ok(());
+    }
+
+    discrepancies.sort_by_key(|discrepancy| discrepancy.client);
+
+    let mut csv_writer = csv::Writer::from_writer(io::stdout().lock());
+    for discrepancy in &discrepancies {
+        csv_writer.serialize(discrepancy)?;
+    }
+    csv_writer.flush()?;
+
+    bail!("found {} discrepancies", discrepancies.len());
+}
+
+/// Reads a CSV file with `client`, `available`, `held`, `total`, and optionally `locked` columns.
+fn load_expected(path: &Path) -> Result<BTreeMap<ClientId, ExpectedRecord>, anyhow::Error> {
+    let load = || -> Result<_, anyhow::Error> {
+        let mut expected = BTreeMap::new();
+
+        for record_res in csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)?
+            .deserialize()
+        {
+            let record: ExpectedRecord = record_res?;
+            let client = record.client;
+
+            if expected.insert(client, record).is_some() {
+                bail!("duplicate client: {client}");
+            }
+        }
+
+        Ok(expected)
+    };
+
+    load().with_context(|| format!("failed to load expected balances from {}", path.display()))
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    time::{Duration, Instant},
+};
+
+use crate::{ClientId, TransactionRecord};
+
+/// Restores per-client ordering of records that carry a sequence number.
+///
+/// Each client's sequence numbers are expected to start at 1, or where an earlier run left off,
+/// and increase by one. Records that arrive ahead of a gap are held back until the gap fills,
+/// until more than `window` records are waiting for that client, or until they have waited
+/// longer than the timeout, if there is one. Then the gap is skipped.
+#[derive(Clone, Debug)]
+pub struct Reorderer {
+    window: usize,
+    timeout: Option<Duration>,
+    clients: BTreeMap<ClientId, ClientQueue>,
+}
+
+#[derive(Clone, Debug)]
+struct ClientQueue {
+    next: u64,
+    pending: BTreeMap<u64, TransactionRecord>,
+    /// When the records in `pending` started waiting on the gap before them.
+    waiting_since: Option<Instant>,
+}
+
+impl ClientQueue {
+    fn new(next: u64) -> Self {
+        Self {
+            next,
+            pending: BTreeMap::new(),
+            waiting_since: None,
+        }
+    }
+
+    fn release(&mut self, ready: &mut Vec<TransactionRecord>) {
+        while let Some(record) = self.pending.remove(&self.next) {
+            ready.push(record);
+            self.next += 1;
+        }
+    }
+
+    fn skip_gap(&mut self, client: ClientId, ready: &mut Vec<TransactionRecord>) {
+        if let Some(&seq) = self.pending.keys().next() {
+            eprintln!(
+                "warning: gap in sequence (client: {client}, missing: {}..{seq})",
+                self.next
+            );
+            self.next = seq;
+            self.release(ready);
+            self.waiting_since = None;
+        }
+    }
+
+    /// Starts the clock on records left waiting on a new gap, or stops it if there are none.
+    fn wait(&mut self, now: impl FnOnce() -> Instant) {
+        self.waiting_since = if self.pending.is_empty() {
+            None
+        } else {
+            Some(self.waiting_since.unwrap_or_else(now))
+        };
+    }
+
+    fn expired(&self, timeout: Option<Duration>, now: Instant) -> bool {
+        timeout.is_some_and(|timeout| {
+            self.waiting_since
+                .is_some_and(|since| now.saturating_duration_since(since) >= timeout)
+        })
+    }
+}
+
+impl Reorderer {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            timeout: None,
+            clients: BTreeMap::new(),
+        }
+    }
+
+    /// Skips gaps that records have waited on for at least `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Expects each client's sequence to carry on from the number given for it in `sequences`.
+    pub fn resume(&mut self, sequences: &BTreeMap<ClientId, u64>) {
+        for (&client, &next) in sequences {
+            self.clients
+                .entry(client)
+                .and_modify(|queue| queue.next = queue.next.max(next))
+                .or_insert_with(|| ClientQueue::new(next));
+        }
+    }
+
+    /// The next sequence number expected from each client that has sent one.
+    pub fn sequences(&self) -> BTreeMap<ClientId, u64> {
+        self.clients
+            .iter()
+            .map(|(&client, queue)| (client, queue.next))
+            .collect()
+    }
+
+    /// Accepts a record, appending any records that are now in order to `ready`.
+    pub fn push(&mut self, record: TransactionRecord, ready: &mut Vec<TransactionRecord>) {
+        let Some(seq) = record.seq else {
+            ready.push(record);
+            return;
+        };
+
+        let client = record.client;
+        let queue = self
+            .clients
+            .entry(client)
+            .or_insert_with(|| ClientQueue::new(1));
+
+        if seq < queue.next {
+            eprintln!("warning: late sequence number (client: {client}, seq: {seq})");
+            ready.push(record);
+            return;
+        }
+
+        match queue.pending.entry(seq) {
+            Entry::Vacant(entry) => {
+                _ = entry.insert(record);
+            }
+            Entry::Occupied(_) => {
+                eprintln!("warning: duplicate sequence number (client: {client}, seq: {seq})");
+                ready.push(record);
+                return;
+            }
+        }
+
+        queue.release(ready);
+
+        while queue.pending.len() > self.window {
+            queue.skip_gap(client, ready);
+        }
+
+        queue.wait(Instant::now);
+
+        // Only this client's clock is checked here, so that every push doesn't visit every
+        // client. `release_expired` checks the rest.
+        if self.timeout.is_some() && queue.expired(self.timeout, Instant::now()) {
+            queue.skip_gap(client, ready);
+            queue.wait(Instant::now);
+        }
+    }
+
+    /// Skips every gap that has been waited on for at least the timeout as of `now`, appending
+    /// the records that were waiting to `ready`.
+    pub fn release_expired(&mut self, now: Instant, ready: &mut Vec<TransactionRecord>) {
+        if self.timeout.is_none() {
+            return;
+        }
+
+        for (&client, queue) in &mut self.clients {
+            if queue.expired(self.timeout, now) {
+                queue.skip_gap(client, ready);
+                queue.wait(|| now);
+            }
+        }
+    }
+
+    /// Releases every record still waiting on a gap, in sequence order. Each client's next
+    /// expected sequence number is kept, so records pushed afterwards carry on from it.
+    pub fn finish(&mut self, ready: &mut Vec<TransactionRecord>) {
+        for (&client, queue) in &mut self.clients {
+            while !queue.pending.is_empty() {
+                queue.skip_gap(client, ready);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TransactionId, TransactionType};
+
+    const CLIENT: ClientId = ClientId(1);
+
+    fn record(tx: u16, seq: u64) -> TransactionRecord {
+        TransactionRecord {
+            r#type: TransactionType::Deposit,
+            client: CLIENT,
+            tx: TransactionId(tx.into()),
+            amount: None,
+            seq: Some(seq),
+            wallet: None,
+        }
+    }
+
+    fn released(ready: &mut Vec<TransactionRecord>) -> Vec<u64> {
+        ready.drain(..).filter_map(|record| record.seq).collect()
+    }
+
+    #[test]
+    fn holds_records_until_gap_fills() {
+        let mut reorderer = Reorderer::new(10);
+        let mut ready = Vec::new();
+
+        reorderer.push(record(3, 3), &mut ready);
+        reorderer.push(record(2, 2), &mut ready);
+        assert!(ready.is_empty());
+
+        reorderer.push(record(1, 1), &mut ready);
+        assert_eq!(released(&mut ready), [1, 2, 3]);
+    }
+
+    #[test]
+    fn skips_gap_past_window() {
+        let mut reorderer = Reorderer::new(2);
+        let mut ready = Vec::new();
+
+        reorderer.push(record(2, 2), &mut ready);
+        reorderer.push(record(4, 4), &mut ready);
+        assert!(ready.is_empty());
+
+        reorderer.push(record(5, 5), &mut ready);
+        assert_eq!(released(&mut ready), [2]);
+
+        // The gap has been skipped, so the missing record is late when it comes.
+        reorderer.push(record(1, 1), &mut ready);
+        assert_eq!(released(&mut ready), [1]);
+    }
+
+    #[test]
+    fn skips_gap_after_timeout() {
+        let timeout = Duration::from_secs(30);
+        let mut reorderer = Reorderer::new(10).with_timeout(timeout);
+        let mut ready = Vec::new();
+
+        reorderer.push(record(2, 2), &mut ready);
+        reorderer.push(record(3, 3), &mut ready);
+
+        reorderer.release_expired(Instant::now(), &mut ready);
+        assert!(ready.is_empty());
+
+        reorderer.release_expired(Instant::now() + timeout, &mut ready);
+        assert_eq!(released(&mut ready), [2, 3]);
+    }
+
+    #[test]
+    fn keeps_next_sequence_after_finish() {
+        let mut reorderer = Reorderer::new(10);
+        let mut ready = Vec::new();
+
+        reorderer.push(record(1, 1), &mut ready);
+        reorderer.push(record(3, 3), &mut ready);
+        reorderer.finish(&mut ready);
+        assert_eq!(released(&mut ready), [1, 3]);
+        assert_eq!(reorderer.sequences(), BTreeMap::from([(CLIENT, 4)]));
+
+        reorderer.push(record(5, 5), &mut ready);
+        assert!(ready.is_empty());
+    }
+
+    #[test]
+    fn resumes_from_saved_sequences() {
+        let mut reorderer = Reorderer::new(10);
+        reorderer.resume(&BTreeMap::from([(CLIENT, 7)]));
+        let mut ready = Vec::new();
+
+        reorderer.push(record(8, 8), &mut ready);
+        assert!(ready.is_empty());
+
+        reorderer.push(record(7, 7), &mut ready);
+        assert_eq!(released(&mut ready), [7, 8]);
+    }
+}
@@ -0,0 +1,215 @@
+use std::io::{self, BufRead, Write};
+
+use anyhow::{anyhow, bail, Context};
+use rust_decimal::Decimal;
+
+use crate::{
+    cli::ReplArgs,
+    engine::{Engine, LockReason, Savepoint},
+    output::{self, Rounding},
+    query, state, ClientId, RawClientId, RawTransactionId, TransactionId, TransactionRecord,
+    TransactionType,
+};
+
+/// How many applied transactions can be undone.
+const UNDO_LIMIT: usize = 1000;
+
+const HELP: &str = "\
+commands:
+  deposit <client> <tx> <amount>
+  withdrawal <client> <tx> <amount>
+  dispute <client> <tx>
+  resolve <client> <tx>
+  chargeback <client> <tx>
+  lock <client>
+  batch <transaction>; <transaction>; ...
+  savepoint
+  rollback [<savepoint>]
+  undo [<count>]
+  accounts
+  account <client>
+  tx <tx>
+  locks
+  help
+  quit";
+
+/// Applies transactions typed one per line, for trying out dispute scenarios by hand.
+///
+/// Rejected transactions and bad commands are reported without ending the session.
+pub fn run(args: &ReplArgs) -> Result<(), anyhow::Error> {
+    let mut session = Session {
+        engine: match &args.state {
+            Some(path) => state::load(path)?,
+            None => Engine::new(),
+        }
+        .undo_limit(UNDO_LIMIT),
+        savepoints: Vec::new(),
+    };
+
+    let stdin = io::stdin().lock();
+    let mut stdout = io::stdout().lock();
+
+    write!(stdout, "> ")?;
+    stdout.flush()?;
+
+    for line in stdin.lines() {
+        let line = line?;
+        let words: Vec<_> = line.split_whitespace().collect();
+
+        match words.as_slice() {
+            [] => (),
+            ["quit" | "exit"] => break,
+            ["help"] => writeln!(stdout, "{HELP}")?,
+            words => {
+                if let Err(err) = session.execute(words, &mut stdout) {
+                    writeln!(stdout, "error: {err:#}")?;
+                }
+            }
+        }
+
+        write!(stdout, "> ")?;
+        stdout.flush()?;
+    }
+
+    Ok(())
+}
+
+struct Session {
+    engine: Engine,
+    /// Savepoints that can still be rolled back to, numbered from 1 in the order they were taken.
+    savepoints: Vec<Savepoint>,
+}
+
+impl Session {
+    fn execute(&mut self, words: &[&str], mut out: impl Write) -> Result<(), anyhow::Error> {
+        match words {
+            ["savepoint"] => {
+                self.savepoints.push(self.engine.savepoint());
+                writeln!(out, "savepoint {}", self.savepoints.len())?;
+            }
+            ["rollback"] => {
+                let number = self.savepoints.len();
+                self.rollback(number)?;
+            }
+            ["rollback", number] => {
+                let number = number
+                    .parse::<usize>()
+                    .with_context(|| format!("invalid savepoint: {number}"))?;
+                self.rollback(number)?;
+            }
+            ["undo"] => self.engine.undo(1)?,
+            ["undo", count] => {
+                let count = count
+                    .parse::<usize>()
+                    .with_context(|| format!("invalid count: {count}"))?;
+                self.engine.undo(count)?;
+            }
+            words => execute(&mut self.engine, words, out)?,
+        }
+
+        Ok(())
+    }
+
+    /// Rolls back to a savepoint by number, which closes it and every later one.
+    fn rollback(&mut self, number: usize) -> Result<(), anyhow::Error> {
+        let index = number
+            .checked_sub(1)
+            .filter(|&index| index < self.savepoints.len())
+            .with_context(|| format!("no such savepoint: {number}"))?;
+
+        self.engine.rollback_to(self.savepoints[index])?;
+        self.savepoints.truncate(index);
+
+        Ok(())
+    }
+}
+
+fn execute(engine: &mut Engine, words: &[&str], out: impl Write) -> Result<(), anyhow::Error> {
+    match words {
+        ["accounts"] => {
+            output::write_accounts(out, engine.accounts(), Rounding::default(), None, None)?;
+        }
+        ["account", client] => {
+            let client = parse_client(client)?;
+            let account = engine
+                .account(client)
+                .with_context(|| format!("client not found: {client}"))?;
+            output::write_accounts(out, [(&client, account)], Rounding::default(), None, None)?;
+        }
+        ["locks"] => query::write_locks(out, engine.locks())?,
+        ["lock", client] => engine.lock(parse_client(client)?, LockReason::Admin, None)?,
+        ["tx", tx] => {
+            let tx = parse_tx(tx)?;
+            let deposit = engine
+                .deposit_by_id(tx)
+                .with_context(|| format!("deposit not found: {tx}"))?;
+            query::write_deposit(out, tx, deposit)?;
+        }
+        ["batch", rest @ ..] => {
+            // Transactions are separated by semicolons, which may or may not have spaces around
+            // them.
+            let batch = rest
+                .join(" ")
+                .split(';')
+                .map(|command| command.split_whitespace().collect::<Vec<_>>())
+                .filter(|words| !words.is_empty())
+                .map(|words| parse_transaction(&words))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            engine.apply_atomic(&batch)?;
+        }
+        words => engine.apply(&parse_transaction(words)?)?,
+    }
+
+    Ok(())
+}
+
+fn parse_transaction(words: &[&str]) -> Result<TransactionRecord, anyhow::Error> {
+    let [r#type, rest @ ..] = words else {
+        bail!("missing command, try: help");
+    };
+
+    let r#type = r#type
+        .parse::<TransactionType>()
+        .context("unknown command, try: help")?;
+
+    let (client, tx, amount) = match (r#type, rest) {
+        (TransactionType::Deposit | TransactionType::Withdrawal, [client, tx, amount]) => {
+            (client, tx, Some(parse_amount(amount)?))
+        }
+        (
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback,
+            [client, tx],
+        ) => (client, tx, None),
+        _ => bail!("wrong number of arguments for {type}, try: help"),
+    };
+
+    Ok(TransactionRecord {
+        r#type,
+        client: parse_client(client)?,
+        tx: parse_tx(tx)?,
+        amount,
+        seq: None,
+        wallet: None,
+    })
+}
+
+fn parse_client(s: &str) -> Result<ClientId, anyhow::Error> {
+    let id = s
+        .parse::<RawClientId>()
+        .with_context(|| format!("invalid client ID: {s}"))?;
+    Ok(ClientId(id))
+}
+
+fn parse_tx(s: &str) -> Result<TransactionId, anyhow::Error> {
+    let id = s
+        .parse::<RawTransactionId>()
+        .with_context(|| format!("invalid transaction ID: {s}"))?;
+    Ok(TransactionId(id))
+}
+
+fn parse_amount(s: &str) -> Result<Decimal, anyhow::Error> {
+    s.parse::<Decimal>()
+        .map_err(|err| anyhow!("{err}"))
+        .with_context(|| format!("invalid amount: {s}"))
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    engine::{Account, Engine},
+    output::Rounding,
+    ClientId, TransactionId, TransactionRecord, TransactionType,
+};
+
+#[derive(Clone, Debug, Serialize)]
+struct RiskRecord {
+    client: ClientId,
+    deposits: u64,
+    deposit_volume: Decimal,
+    disputes: u64,
+    open_disputes: usize,
+    held: Decimal,
+    chargebacks: u64,
+    chargeback_volume: Decimal,
+    /// Disputes per deposit.
+    dispute_rate: Decimal,
+}
+
+#[derive(Clone, Debug, Default)]
+struct History {
+    deposits: u64,
+    deposit_volume: Decimal,
+    disputes: u64,
+    open_disputes: BTreeSet<TransactionId>,
+    chargebacks: u64,
+    chargeback_volume: Decimal,
+}
+
+/// Follows each client's deposits, disputes, and chargebacks over the run, for the risk team's
+/// monitoring.
+pub struct RiskReport<'a> {
+    clients: BTreeMap<ClientId, History>,
+    rounding: Rounding,
+    writer: csv::Writer<Box<dyn io::Write + 'a>>,
+}
+
+impl<'a> RiskReport<'a> {
+    pub fn new(writer: impl io::Write + 'a, rounding: Rounding) -> Self {
+        Self {
+            clients: BTreeMap::new(),
+            rounding,
+            writer: csv::Writer::from_writer(Box::new(writer)),
+        }
+    }
+
+    /// Counts a transaction that has just been applied. `disputed` is the amount of the deposit
+    /// it disputes, resolves, or charges back.
+    pub fn record(&mut self, transaction: &TransactionRecord, disputed: Option<Decimal>) {
+        let history = self.clients.entry(transaction.client).or_default();
+
+        match transaction.r#type {
+            TransactionType::Deposit => {
+                history.deposits += 1;
+                history.deposit_volume += transaction.amount.unwrap_or_default();
+            }
+            TransactionType::Dispute => {
+                history.disputes += 1;
+                _ = history.open_disputes.insert(transaction.tx);
+            }
+            TransactionType::Resolve => _ = history.open_disputes.remove(&transaction.tx),
+            TransactionType::Chargeback => {
+                history.chargebacks += 1;
+                history.chargeback_volume += disputed.unwrap_or_default();
+                _ = history.open_disputes.remove(&transaction.tx);
+            }
+            TransactionType::Withdrawal => (),
+        }
+    }
+
+    /// Writes one row per client that had any applied transactions, with their held funds as of
+    /// `engine`.
+    pub fn finish(&mut self, engine: &Engine) -> Result<(), csv::Error> {
+        for (&client, history) in &self.clients {
+            let held = engine.account(client).map_or(Decimal::ZERO, Account::held);
+
+            let dispute_rate = if history.deposits == 0 {
+                Decimal::ZERO
+            } else {
+                Decimal::from(history.disputes) / Decimal::from(history.deposits)
+            };
+
+            self.writer.serialize(RiskRecord {
+                client,
+                deposits: history.deposits,
+                deposit_volume: self.rounding.round(history.deposit_volume),
+                disputes: history.disputes,
+                open_disputes: history.open_disputes.len(),
+                held: self.rounding.round(held),
+                chargebacks: history.chargebacks,
+                chargeback_volume: self.rounding.round(history.chargeback_volume),
+                dispute_rate: dispute_rate.round_dp(4),
+            })?;
+        }
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use std::{collections::BTreeMap, io};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{output::Rounding, ClientId, TransactionRecord, TransactionType};
+
+#[derive(Clone, Debug, Serialize)]
+struct SettlementRecord {
+    client: ClientId,
+    deposits: Decimal,
+    withdrawals: Decimal,
+    chargebacks: Decimal,
+    net: Decimal,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Totals {
+    deposits: Decimal,
+    withdrawals: Decimal,
+    chargebacks: Decimal,
+}
+
+/// Adds up each client's applied deposits, withdrawals, and chargebacks over the run, for the
+/// payout system.
+///
+/// Disputes and resolutions only move funds between available and held, so they do not count
+/// towards settlement.
+pub struct Settlement<'a> {
+    totals: BTreeMap<ClientId, Totals>,
+    rounding: Rounding,
+    writer: csv::Writer<Box<dyn io::Write + 'a>>,
+}
+
+impl<'a> Settlement<'a> {
+    pub fn new(writer: impl io::Write + 'a, rounding: Rounding) -> Self {
+        Self {
+            totals: BTreeMap::new(),
+            rounding,
+            writer: csv::Writer::from_writer(Box::new(writer)),
+        }
+    }
+
+    /// Counts a transaction that has just been applied. `disputed` is the amount of the deposit
+    /// it disputes, resolves, or charges back.
+    pub fn record(&mut self, transaction: &TransactionRecord, disputed: Option<Decimal>) {
+        let totals = self.totals.entry(transaction.client).or_default();
+
+        match transaction.r#type {
+            TransactionType::Deposit => totals.deposits += transaction.amount.unwrap_or_default(),
+            TransactionType::Withdrawal => {
+                totals.withdrawals += transaction.amount.unwrap_or_default();
+            }
+            TransactionType::Chargeback => totals.chargebacks += disputed.unwrap_or_default(),
+            TransactionType::Dispute | TransactionType::Resolve => (),
+        }
+    }
+
+    /// Writes one row per client that had any applied transactions.
+    pub fn finish(&mut self) -> Result<(), csv::Error> {
+        for (&client, totals) in &self.totals {
+            self.writer.serialize(SettlementRecord {
+                client,
+                deposits: self.rounding.round(totals.deposits),
+                withdrawals: self.rounding.round(totals.withdrawals),
+                chargebacks: self.rounding.round(totals.chargebacks),
+                net: self
+                    .rounding
+                    .round(totals.deposits - totals.withdrawals - totals.chargebacks),
+            })?;
+        }
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::bail;
+use serde::Deserialize;
+
+use crate::config::SourceConfig;
+
+#[cfg(any(feature = "nats", feature = "amqp"))]
+pub use consumer::{run, Consumer};
+
+/// How many messages to have delivered ahead of the one being applied, unless set.
+pub const DEFAULT_PREFETCH: u16 = 100;
+
+/// A message broker to consume transactions from, in place of input files. Each one is only
+/// built in with the feature of the same name.
+///
+/// Both clients connect over plain TCP, without TLS, so a broker must be reachable without it,
+/// e.g. through a local proxy that terminates TLS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+    /// A durable pull consumer on a NATS `JetStream` stream. Servers that require TLS are
+    /// refused when connecting.
+    Nats,
+    /// A consumer on an AMQP 0-9-1 queue, such as one on `RabbitMQ`. Heartbeats are turned off,
+    /// so a connection that drops without closing may go unnoticed until TCP gives up on it.
+    Amqp,
+}
+
+impl SourceKind {
+    /// The feature that builds in this kind of broker, if it isn't enabled.
+    pub fn missing_feature(self) -> Option<&'static str> {
+        match self {
+            Self::Nats => (!cfg!(feature = "nats")).then_some("nats"),
+            Self::Amqp => (!cfg!(feature = "amqp")).then_some("amqp"),
+        }
+    }
+}
+
+impl FromStr for SourceKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nats" => Ok(Self::Nats),
+            "amqp" => Ok(Self::Amqp),
+            _ => bail!("expected one of: nats, amqp"),
+        }
+    }
+}
+
+/// Where and how to consume transactions from a broker.
+#[derive(Clone, Debug)]
+pub struct SourceOptions {
+    /// The kind of broker, if transactions come from one.
+    pub kind: Option<SourceKind>,
+    /// The broker's address, as `host:port`.
+    pub broker: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// The stream to consume from, with NATS.
+    pub stream: Option<String>,
+    /// The name of the consumer that keeps its place in the stream across restarts, with NATS.
+    pub durable: Option<String>,
+    /// The queue to consume from, with AMQP.
+    pub queue: Option<String>,
+    /// The virtual host the queue is in, with AMQP, if not the default.
+    pub vhost: Option<String>,
+    /// How many messages to have delivered ahead of the one being applied.
+    pub prefetch: u16,
+    /// Where the account report is rewritten after every message.
+    pub report: Option<PathBuf>,
+}
+
+impl Default for SourceOptions {
+    fn default() -> Self {
+        Self {
+            kind: None,
+            broker: None,
+            user: None,
+            password: None,
+            stream: None,
+            durable: None,
+            queue: None,
+            vhost: None,
+            prefetch: DEFAULT_PREFETCH,
+            report: None,
+        }
+    }
+}
+
+impl From<SourceConfig> for SourceOptions {
+    fn from(config: SourceConfig) -> Self {
+        Self {
+            kind: config.kind,
+            broker: config.broker,
+            user: config.user,
+            password: config.password,
+            stream: config.stream,
+            durable: config.durable,
+            queue: config.queue,
+            vhost: config.vhost,
+            prefetch: config.prefetch.unwrap_or(DEFAULT_PREFETCH),
+            report: config.report,
+        }
+    }
+}
+
+#[cfg(any(feature = "nats", feature = "amqp"))]
+mod consumer {
+    use std::{convert::Infallible, path::Path};
+
+    use anyhow::Context;
+
+    use super::{SourceKind, SourceOptions};
+    #[cfg(feature = "amqp")]
+    use crate::amqp;
+    #[cfg(feature = "nats")]
+    use crate::nats;
+    use crate::{
+        clients::Clients,
+        follow,
+        input::{ReadOptions, RowParser},
+        output::Rounding,
+        processor::Processor,
+        state, TransactionRecord,
+    };
+
+    /// Applies every message that `kind` of broker delivers, rewriting the account report at
+    /// `options.report` after each one. This only returns on error.
+    ///
+    /// Each message holds a transaction file, header row and all. Like files with `--watch`,
+    /// each message is applied in full or not at all, and records are only reordered within a
+    /// message. A message is only acknowledged once it has been applied and, if `save_state` is
+    /// given, the engine has been saved there, so a restart carries on from the first message
+    /// that wasn't. A message that fails to parse or to apply is rejected, so that the broker
+    /// doesn't deliver it again.
+    pub fn run(
+        kind: SourceKind,
+        options: &SourceOptions,
+        read: &ReadOptions,
+        processor: Processor,
+        save_state: Option<&Path>,
+        rounding: Rounding,
+        clients: Option<&Clients>,
+    ) -> Result<Infallible, anyhow::Error> {
+        let consumer = Consumer {
+            read,
+            processor,
+            save_state,
+            report: options.report.as_deref().context("missing --report")?,
+            rounding,
+            clients,
+        };
+
+        match kind {
+            #[cfg(feature = "nats")]
+            SourceKind::Nats => nats::run(options, consumer),
+            #[cfg(feature = "amqp")]
+            SourceKind::Amqp => amqp::run(options, consumer),
+            #[allow(unreachable_patterns)]
+            kind => unreachable!("--source {kind:?} is rejected without its feature"),
+        }
+    }
+
+    /// Applies messages as a broker delivers them.
+    pub struct Consumer<'a, 'p> {
+        read: &'a ReadOptions,
+        processor: Processor<'p>,
+        save_state: Option<&'a Path>,
+        report: &'a Path,
+        rounding: Rounding,
+        clients: Option<&'a Clients>,
+    }
+
+    impl Consumer<'_, '_> {
+        /// Applies the transaction file in `body`, returning whether it was applied. `name` says
+        /// where it came from, for messages.
+        pub fn apply(&mut self, body: &[u8], name: &str) -> Result<bool, anyhow::Error> {
+            let name = Path::new(name);
+
+            let apply_res = self.parse(body, name).and_then(|transactions| {
+                self.processor
+                    .push_batch(transactions)
+                    .with_context(|| format!("failed to apply {}", name.display()))
+            });
+
+            let applied = match apply_res {
+                Ok(()) => {
+                    if let Some(save_state) = self.save_state {
+                        state::save(self.processor.engine(), save_state)?;
+                    }
+
+                    true
+                }
+                Err(err) => {
+                    eprintln!("warning: {err:#}");
+                    false
+                }
+            };
+
+            self.processor.flush()?;
+            follow::write_report(&self.processor, self.report, self.rounding, self.clients)?;
+
+            Ok(applied)
+        }
+
+        fn parse(&self, body: &[u8], name: &Path) -> Result<Vec<TransactionRecord>, anyhow::Error> {
+            let mut csv_reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .trim(csv::Trim::All)
+                .from_reader(body);
+
+            let mut parser = None;
+            let mut transactions = Vec::new();
+
+            for record_res in csv_reader.byte_records() {
+                let record =
+                    record_res.with_context(|| format!("failed to read {}", name.display()))?;
+
+                let Some(parser) = &mut parser else {
+                    parser = Some(RowParser::new(record, self.read));
+                    continue;
+                };
+
+                if let Some(transaction) =
+                    parser.parse_row(&record, self.processor.policy(), name)?
+                {
+                    transactions.push(transaction);
+                }
+            }
+
+            Ok(transactions)
+        }
+    }
+}
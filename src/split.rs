@@ -0,0 +1,66 @@
+use std::{fs::File, path::Path};
+
+use anyhow::Context;
+
+use crate::{cli::SplitArgs, RawClientId};
+
+/// Partitions a transaction file into one file per shard for `--sharded` processing.
+///
+/// A client's rows all go to shard `client % shards`, in their original order, so each shard can
+/// be processed independently.
+pub fn run(args: &SplitArgs) -> Result<(), anyhow::Error> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(&args.path)
+        .with_context(|| format!("failed to read {}", args.path.display()))?;
+
+    let headers = csv_reader.headers()?.clone();
+    let client_column = headers
+        .iter()
+        .position(|header| header == "client")
+        .context("missing column: client")?;
+
+    let dir = match &args.output_dir {
+        Some(dir) => dir.as_path(),
+        None => args.path.parent().unwrap_or(Path::new("")),
+    };
+    let stem = args
+        .path
+        .file_stem()
+        .context("input path has no file name")?
+        .to_string_lossy();
+
+    let mut csv_writers = (0..args.shards)
+        .map(|shard| {
+            let path = dir.join(format!("{stem}-shard{shard}.csv"));
+            let file = File::create(&path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            let mut csv_writer = csv::Writer::from_writer(file);
+            csv_writer.write_record(&headers)?;
+            Ok(csv_writer)
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+    for record_res in csv_reader.records() {
+        let record = record_res?;
+
+        let client = record
+            .get(client_column)
+            .and_then(|client| client.parse::<RawClientId>().ok())
+            .with_context(|| format!("invalid client on line {}", line(&record)))?;
+
+        let shard = u128::from(client) % args.shards as u128;
+        let shard = usize::try_from(shard).expect("shard is below the number of shards");
+        csv_writers[shard].write_record(&record)?;
+    }
+
+    for csv_writer in &mut csv_writers {
+        csv_writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn line(record: &csv::StringRecord) -> u64 {
+    record.position().map_or(0, csv::Position::line)
+}
@@ -0,0 +1,125 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+
+use crate::engine::Engine;
+
+/// Identifies a saved engine state file and the version of its layout.
+const MAGIC: &[u8; 8] = &[b'S', b'P', b'O', b'R', b'K', 0, FEATURES, 8];
+
+/// Amounts and IDs are laid out differently with the `fixed-point` and `wide-ids` features, so
+/// each combination of them has its own magic.
+const FEATURES: u8 = {
+    let mut features = 0;
+
+    if cfg!(feature = "fixed-point") {
+        features |= 1;
+    }
+
+    if cfg!(feature = "wide-ids") {
+        features |= 2;
+    }
+
+    features
+};
+
+/// Writes the engine's accounts and deposits to `path`, so they can be loaded again later without
+/// reprocessing the input.
+///
+/// The state is written beside `path` and then moved over it, so a run that is stopped part way
+/// through saving leaves the previous state in place.
+pub fn save(engine: &Engine, path: &Path) -> Result<(), anyhow::Error> {
+    let save = || -> Result<(), anyhow::Error> {
+        let Some(name) = path.file_name() else {
+            bail!("invalid state path");
+        };
+
+        let mut temp_name = name.to_owned();
+        temp_name.push(".tmp");
+        let temp = path.with_file_name(temp_name);
+
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        writer.write_all(MAGIC)?;
+        bincode::serialize_into(&mut writer, engine)?;
+        writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+
+        fs::rename(&temp, path)?;
+        Ok(())
+    };
+
+    save().with_context(|| format!("failed to save state to {}", path.display()))
+}
+
+pub fn load(path: &Path) -> Result<Engine, anyhow::Error> {
+    let load = || -> Result<Engine, anyhow::Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; MAGIC.len()];
+        match reader.read_exact(&mut magic) {
+            Ok(()) if magic == *MAGIC => (),
+            Ok(()) => bail!("not a state file, or from an incompatible version"),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => bail!("not a state file"),
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(bincode::deserialize_from(reader)?)
+    };
+
+    load().with_context(|| format!("failed to load state from {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::{
+        engine::{DepositState, DisputeAges, Lock, LockReason},
+        ClientId, TransactionId,
+    };
+
+    #[test]
+    fn loads_saved_state() {
+        let mut engine = Engine::new();
+        engine
+            .deposit(ClientId(1), TransactionId(1), Decimal::new(12_345, 4))
+            .unwrap();
+        engine.dispute(ClientId(1), TransactionId(1)).unwrap();
+        engine.lock(ClientId(2), LockReason::Admin, None).unwrap();
+        let mut ages = DisputeAges::default();
+        ages.tick();
+        ages.open(TransactionId(1));
+        ages.tick();
+        engine.set_dispute_ages(ages.clone());
+
+        let path = env::temp_dir().join(format!("spork-state-{}", process::id()));
+        save(&engine, &path).unwrap();
+        let loaded = load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        let account = loaded.account(ClientId(1)).unwrap();
+        assert_eq!(account.held(), Decimal::new(12_345, 4));
+        assert_eq!(
+            loaded.deposit_by_id(TransactionId(1)).unwrap().state,
+            DepositState::Dispute
+        );
+
+        let lock = Lock {
+            reason: LockReason::Admin,
+            tx: None,
+        };
+        assert_eq!(loaded.account(ClientId(2)).unwrap().lock(), Some(lock));
+        assert_eq!(loaded.locks(), [(ClientId(2), lock)]);
+        assert_eq!(loaded.dispute_ages(), &ages);
+    }
+}
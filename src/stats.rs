@@ -0,0 +1,108 @@
+use std::{collections::BTreeSet, io};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{cli::StatsArgs, ClientId, TransactionRecord, TransactionType};
+
+#[derive(Clone, Debug, Serialize)]
+struct StatRecord {
+    metric: &'static str,
+    value: String,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    rows: u64,
+    malformed: u64,
+    deposits: u64,
+    withdrawals: u64,
+    disputes: u64,
+    resolves: u64,
+    chargebacks: u64,
+    clients: BTreeSet<ClientId>,
+    amounts: u64,
+    amount_min: Option<Decimal>,
+    amount_max: Option<Decimal>,
+    amount_sum: Decimal,
+}
+
+impl Stats {
+    fn add(&mut self, record: &TransactionRecord) {
+        match record.r#type {
+            TransactionType::Deposit => self.deposits += 1,
+            TransactionType::Withdrawal => self.withdrawals += 1,
+            TransactionType::Dispute => self.disputes += 1,
+            TransactionType::Resolve => self.resolves += 1,
+            TransactionType::Chargeback => self.chargebacks += 1,
+        }
+
+        _ = self.clients.insert(record.client);
+
+        if let Some(amount) = record.amount {
+            self.amounts += 1;
+            self.amount_min = Some(self.amount_min.map_or(amount, |min| min.min(amount)));
+            self.amount_max = Some(self.amount_max.map_or(amount, |max| max.max(amount)));
+            self.amount_sum += amount;
+        }
+    }
+
+    fn records(&self) -> Vec<StatRecord> {
+        let optional = |amount: Option<Decimal>| amount.map(|amount| amount.to_string());
+
+        let amount_mean = (self.amounts > 0).then(|| self.amount_sum / Decimal::from(self.amounts));
+
+        [
+            ("rows", Some(self.rows.to_string())),
+            ("malformed", Some(self.malformed.to_string())),
+            ("deposits", Some(self.deposits.to_string())),
+            ("withdrawals", Some(self.withdrawals.to_string())),
+            ("disputes", Some(self.disputes.to_string())),
+            ("resolves", Some(self.resolves.to_string())),
+            ("chargebacks", Some(self.chargebacks.to_string())),
+            ("clients", Some(self.clients.len().to_string())),
+            ("amounts", Some(self.amounts.to_string())),
+            ("amount_min", optional(self.amount_min)),
+            ("amount_max", optional(self.amount_max)),
+            ("amount_sum", Some(self.amount_sum.to_string())),
+            ("amount_mean", optional(amount_mean)),
+        ]
+        .into_iter()
+        .map(|(metric, value)| StatRecord {
+            metric,
+            value: value.unwrap_or_default(),
+        })
+        .collect()
+    }
+}
+
+/// Scans a transaction file without applying it, and reports what it contains.
+pub fn run(args: &StatsArgs) -> Result<(), anyhow::Error> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(&args.path)
+        .with_context(|| format!("failed to read {}", args.path.display()))?;
+
+    let mut stats = Stats::default();
+
+    for record_res in csv_reader.deserialize::<TransactionRecord>() {
+        stats.rows += 1;
+
+        match record_res {
+            Ok(record) => stats.add(&record),
+            Err(err) if err.is_io_error() => {
+                return Err(err).with_context(|| format!("failed to read {}", args.path.display()))
+            }
+            Err(_) => stats.malformed += 1,
+        }
+    }
+
+    let mut csv_writer = csv::Writer::from_writer(io::stdout().lock());
+    for record in stats.records() {
+        csv_writer.serialize(record)?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{RawTransactionId, TransactionId};
+
+/// Transaction IDs per page, as a power of two.
+const PAGE_BITS: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const OFFSET_MASK: RawTransactionId = (1 << PAGE_BITS) - 1;
+
+/// How many empty pages may be skipped to keep an ID in the paged part.
+const MAX_PAGE_GAP: usize = 4;
+
+type Page<T> = Box<[Option<T>]>;
+
+/// A page in a loaded store that isn't the size of a page, so it can't be indexed by offset.
+#[derive(Clone, Copy, Debug, Error)]
+#[error("invalid transaction page (index: {index}, len: {len}, expected: {PAGE_SIZE})")]
+pub struct InvalidPage {
+    index: usize,
+    len: usize,
+}
+
+/// Values by transaction ID, laid out for IDs that are roughly sequential.
+///
+/// Values are kept in fixed-size pages indexed by ID, which costs a fraction of a map entry per
+/// value. The pages cover one contiguous range of IDs, starting from the page of the first value
+/// stored; an ID that falls too far outside that range goes into an ordinary map instead, so a few
+/// stray IDs don't allocate pages that stay almost empty.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(try_from = "StoredPages<T>")]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub struct TransactionStore<T> {
+    /// The page number of `pages[0]`.
+    first_page: RawTransactionId,
+    pages: Vec<Option<Page<T>>>,
+    sparse: BTreeMap<TransactionId, T>,
+}
+
+/// A store as it is loaded, before its pages have been checked.
+#[derive(Deserialize)]
+struct StoredPages<T> {
+    first_page: RawTransactionId,
+    pages: Vec<Option<Page<T>>>,
+    sparse: BTreeMap<TransactionId, T>,
+}
+
+impl<T> TryFrom<StoredPages<T>> for TransactionStore<T> {
+    type Error = InvalidPage;
+
+    fn try_from(stored: StoredPages<T>) -> Result<Self, Self::Error> {
+        for (index, page) in stored.pages.iter().enumerate() {
+            if let Some(page) = page {
+                if page.len() != PAGE_SIZE {
+                    return Err(InvalidPage {
+                        index,
+                        len: page.len(),
+                    });
+                }
+            }
+        }
+
+        Ok(Self {
+            first_page: stored.first_page,
+            pages: stored.pages,
+            sparse: stored.sparse,
+        })
+    }
+}
+
+impl<T: Clone> TransactionStore<T> {
+    pub fn new() -> Self {
+        Self {
+            first_page: 0,
+            pages: Vec::new(),
+            sparse: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, tx: TransactionId) -> Option<&T> {
+        match self.slot(tx) {
+            Some(Some(value)) => Some(value),
+            _ => self.sparse.get(&tx),
+        }
+    }
+
+    pub fn get_mut(&mut self, tx: TransactionId) -> Option<&mut T> {
+        let (page, offset) = split(tx);
+
+        match page_index(page, self.first_page).and_then(|index| self.pages.get_mut(index)) {
+            Some(Some(page)) if page[offset].is_some() => page[offset].as_mut(),
+            _ => self.sparse.get_mut(&tx),
+        }
+    }
+
+    pub fn contains_key(&self, tx: TransactionId) -> bool {
+        self.get(tx).is_some()
+    }
+
+    /// Adds a value, unless one with the same ID is already stored, in which case this returns
+    /// `false` and leaves the store unchanged.
+    pub fn insert_new(&mut self, tx: TransactionId, value: T) -> bool {
+        if self.contains_key(tx) {
+            return false;
+        }
+
+        let (page, offset) = split(tx);
+
+        if self.pages.is_empty() {
+            self.first_page = page;
+        }
+
+        match page_index(page, self.first_page) {
+            Some(index) if index < self.pages.len() + MAX_PAGE_GAP => {
+                if index >= self.pages.len() {
+                    self.pages.resize_with(index + 1, || None);
+                }
+
+                let page = self.pages[index]
+                    .get_or_insert_with(|| vec![None; PAGE_SIZE].into_boxed_slice());
+                page[offset] = Some(value);
+            }
+            _ => {
+                _ = self.sparse.insert(tx, value);
+            }
+        }
+
+        true
+    }
+
+    pub fn remove(&mut self, tx: TransactionId) {
+        let (page, offset) = split(tx);
+
+        match page_index(page, self.first_page).and_then(|index| self.pages.get_mut(index)) {
+            Some(Some(page)) if page[offset].is_some() => page[offset] = None,
+            _ => _ = self.sparse.remove(&tx),
+        }
+    }
+
+    /// The highest ID of any stored value.
+    pub fn last_id(&self) -> Option<TransactionId> {
+        let paged = self.iter_paged().last().map(|(tx, _)| tx);
+        let sparse = self.sparse.last_key_value().map(|(&tx, _)| tx);
+        paged.max(sparse)
+    }
+
+    /// Every value, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (TransactionId, &T)> {
+        self.iter_paged()
+            .chain(self.sparse.iter().map(|(&tx, value)| (tx, value)))
+    }
+
+    fn iter_paged(&self) -> impl DoubleEndedIterator<Item = (TransactionId, &T)> {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter_map(|(index, page)| {
+                Some((page_number(self.first_page, index)?, page.as_ref()?))
+            })
+            .flat_map(|(page, values)| {
+                values
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(offset, value)| Some((join(page, offset), value.as_ref()?)))
+            })
+    }
+
+    /// Every value, in no particular order.
+    pub fn into_entries(self) -> impl Iterator<Item = (TransactionId, T)> {
+        let first_page = self.first_page;
+
+        let paged = self
+            .pages
+            .into_iter()
+            .enumerate()
+            .filter_map(move |(index, page)| Some((page_number(first_page, index)?, page?)))
+            .flat_map(|(page, values)| {
+                values
+                    .into_vec()
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(move |(offset, value)| Some((join(page, offset), value?)))
+            });
+
+        paged.chain(self.sparse)
+    }
+
+    fn slot(&self, tx: TransactionId) -> Option<&Option<T>> {
+        let (page, offset) = split(tx);
+        let page = self
+            .pages
+            .get(page_index(page, self.first_page)?)?
+            .as_ref()?;
+        Some(&page[offset])
+    }
+}
+
+fn split(tx: TransactionId) -> (RawTransactionId, usize) {
+    let offset = usize::try_from(tx.0 & OFFSET_MASK).expect("offset is within a page");
+    (tx.0 >> PAGE_BITS, offset)
+}
+
+fn join(page: RawTransactionId, offset: usize) -> TransactionId {
+    let offset = RawTransactionId::try_from(offset).expect("offset is within a page");
+    TransactionId((page << PAGE_BITS) | offset)
+}
+
+fn page_number(first_page: RawTransactionId, index: usize) -> Option<RawTransactionId> {
+    first_page.checked_add(RawTransactionId::try_from(index).ok()?)
+}
+
+/// Where a page is in `pages`, if it is after the first page and not too far to index.
+fn page_index(page: RawTransactionId, first_page: RawTransactionId) -> Option<usize> {
+    usize::try_from(page.checked_sub(first_page)?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store with values in pages 2 and 3, and below the first page in the sparse part.
+    fn store() -> TransactionStore<u32> {
+        let mut store = TransactionStore::new();
+        assert!(store.insert_new(join(2, 1), 21));
+        assert!(store.insert_new(join(3, 4), 34));
+        assert!(store.insert_new(join(0, 5), 5));
+        store
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut store = store();
+
+        assert!(!store.insert_new(join(2, 1), 0));
+        assert!(!store.insert_new(join(0, 5), 0));
+        assert_eq!(store.get(join(2, 1)), Some(&21));
+        assert_eq!(store.get(join(0, 5)), Some(&5));
+    }
+
+    #[test]
+    fn keeps_stray_ids_sparse() {
+        let mut store = store();
+        assert_eq!(store.pages.len(), 2);
+        assert!(store.sparse.contains_key(&join(0, 5)));
+
+        // Up to `MAX_PAGE_GAP` empty pages may be skipped after the last one, but no more.
+        assert_eq!(MAX_PAGE_GAP, 4);
+        assert!(store.insert_new(join(7, 0), 70));
+        assert_eq!(store.pages.len(), 6);
+        assert!(store.insert_new(join(12, 0), 120));
+        assert_eq!(store.pages.len(), 6);
+        assert_eq!(
+            store.sparse.keys().copied().collect::<Vec<_>>(),
+            [join(0, 5), join(12, 0)]
+        );
+
+        assert_eq!(store.get(join(7, 0)), Some(&70));
+        assert_eq!(store.get(join(12, 0)), Some(&120));
+        assert_eq!(store.get(join(4, 0)), None);
+    }
+
+    #[test]
+    fn updates_and_removes_from_pages_and_sparse() {
+        let mut store = store();
+
+        *store.get_mut(join(2, 1)).unwrap() += 1;
+        *store.get_mut(join(0, 5)).unwrap() += 1;
+        assert!(store.get_mut(join(2, 2)).is_none());
+        assert_eq!(store.get(join(2, 1)), Some(&22));
+        assert_eq!(store.get(join(0, 5)), Some(&6));
+
+        store.remove(join(2, 1));
+        store.remove(join(0, 5));
+        store.remove(join(9, 0));
+        assert!(!store.contains_key(join(2, 1)));
+        assert!(!store.contains_key(join(0, 5)));
+        assert_eq!(store.get(join(3, 4)), Some(&34));
+    }
+
+    #[test]
+    fn finds_last_id_across_pages_and_sparse() {
+        let mut store = TransactionStore::new();
+        assert_eq!(store.last_id(), None);
+
+        assert!(store.insert_new(join(2, 1), 21));
+        assert!(store.insert_new(join(0, 5), 5));
+        assert_eq!(store.last_id(), Some(join(2, 1)));
+
+        assert!(store.insert_new(join(20, 0), 200));
+        assert_eq!(store.last_id(), Some(join(20, 0)));
+
+        store.remove(join(20, 0));
+        assert_eq!(store.last_id(), Some(join(2, 1)));
+    }
+
+    #[test]
+    fn takes_every_entry() {
+        let mut entries: Vec<_> = store().into_entries().collect();
+        entries.sort_unstable();
+
+        assert_eq!(
+            entries,
+            [(join(0, 5), 5), (join(2, 1), 21), (join(3, 4), 34)]
+        );
+    }
+
+    #[test]
+    fn rejects_short_pages() {
+        let mut store = TransactionStore::new();
+        assert!(store.insert_new(TransactionId(1), ()));
+
+        let mut bytes = bincode::serialize(&store).unwrap();
+        assert!(bincode::deserialize::<TransactionStore<()>>(&bytes).is_ok());
+
+        store.pages[0] = Some(vec![None; 2].into_boxed_slice());
+        bytes = bincode::serialize(&store).unwrap();
+        let err = bincode::deserialize::<TransactionStore<()>>(&bytes).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid transaction page (index: 0, len: 2, expected: 4096)"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use std::{collections::BTreeMap, io, str::FromStr};
+
+use anyhow::bail;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    clients::Clients,
+    engine::Engine,
+    output::{self, Rounding},
+    ClientId, TransactionRecord,
+};
+
+/// What to rank accounts by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopBy {
+    #[default]
+    Total,
+    Held,
+    Available,
+    /// How many of the client's transactions were applied.
+    Transactions,
+}
+
+impl FromStr for TopBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "total" => Ok(Self::Total),
+            "held" => Ok(Self::Held),
+            "available" => Ok(Self::Available),
+            "transactions" => Ok(Self::Transactions),
+            _ => bail!("expected one of: total, held, available, transactions"),
+        }
+    }
+}
+
+/// Writes only the largest accounts, in place of the full account report.
+///
+/// Accounts are written largest first, with ties in client order.
+pub struct TopAccounts<'a> {
+    by: TopBy,
+    limit: usize,
+    /// Applied transactions per client, only counted when ranking by them.
+    counts: BTreeMap<ClientId, u64>,
+    rounding: Rounding,
+    clients: Option<&'a Clients>,
+    writer: Box<dyn io::Write + 'a>,
+}
+
+impl<'a> TopAccounts<'a> {
+    pub fn new(by: TopBy, limit: usize, writer: impl io::Write + 'a, rounding: Rounding) -> Self {
+        Self {
+            by,
+            limit,
+            counts: BTreeMap::new(),
+            rounding,
+            clients: None,
+            writer: Box::new(writer),
+        }
+    }
+
+    /// Adds each client's details from `clients` to their rows.
+    pub fn with_clients(mut self, clients: &'a Clients) -> Self {
+        self.clients = Some(clients);
+        self
+    }
+
+    /// Counts a transaction that has just been applied.
+    pub fn record(&mut self, transaction: &TransactionRecord) {
+        if self.by == TopBy::Transactions {
+            *self.counts.entry(transaction.client).or_default() += 1;
+        }
+    }
+
+    pub fn finish(&mut self, engine: &Engine) -> Result<(), csv::Error> {
+        let mut ranked: Vec<_> = engine
+            .accounts()
+            .map(|(client, account)| {
+                let key = match self.by {
+                    TopBy::Total => account.total(),
+                    TopBy::Held => account.held(),
+                    TopBy::Available => account.available(),
+                    TopBy::Transactions => {
+                        Decimal::from(self.counts.get(client).copied().unwrap_or_default())
+                    }
+                };
+
+                (key, client, account)
+            })
+            .collect();
+
+        // Accounts are already in client order, so a stable sort keeps ties that way.
+        ranked.sort_by(|(a, _, _), (b, _, _)| b.cmp(a));
+        ranked.truncate(self.limit);
+
+        output::write_accounts(
+            &mut self.writer,
+            ranked
+                .into_iter()
+                .map(|(_, client, account)| (client, account)),
+            self.rounding,
+            self.clients,
+            None,
+        )
+    }
+}
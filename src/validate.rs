@@ -0,0 +1,238 @@
+use std::{io, str};
+
+use anyhow::{bail, Context};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{cli::ValidateArgs, RawClientId, RawTransactionId, TransactionType};
+
+/// The columns every file must start with, in order.
+const REQUIRED: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Columns that may follow the required ones.
+const OPTIONAL: [&str; 2] = ["seq", "wallet"];
+
+/// One way a file doesn't match the input format.
+#[derive(Clone, Debug, Serialize)]
+struct Violation {
+    line: u64,
+    /// The header of the column at fault, or empty for the row as a whole.
+    column: String,
+    problem: String,
+}
+
+/// Where each required column is, if it's there at all.
+#[derive(Clone, Copy, Debug)]
+struct Columns {
+    r#type: Option<usize>,
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+}
+
+/// Checks a transaction file against the input format more strictly than processing does, and
+/// writes every violation found with its line and column. Nothing is applied.
+///
+/// Fails if there were any violations, once they have all been written.
+pub fn run(args: &ValidateArgs) -> Result<(), anyhow::Error> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_path(&args.path)
+        .with_context(|| format!("failed to read {}", args.path.display()))?;
+
+    let headers = csv_reader
+        .byte_headers()
+        .with_context(|| format!("failed to read {}", args.path.display()))?
+        .iter()
+        .map(|header| String::from_utf8_lossy(header).into_owned())
+        .collect::<Vec<_>>();
+
+    let mut violations = check_headers(&headers);
+
+    let position = |name: &str| headers.iter().position(|header| header == name);
+    let columns = Columns {
+        r#type: position("type"),
+        client: position("client"),
+        tx: position("tx"),
+        amount: position("amount"),
+    };
+
+    let mut record = csv::ByteRecord::new();
+
+    while csv_reader
+        .read_byte_record(&mut record)
+        .with_context(|| format!("failed to read {}", args.path.display()))?
+    {
+        check_row(&record, &headers, columns, args.max_scale, &mut violations);
+    }
+
+    let mut csv_writer = csv::Writer::from_writer(io::stdout().lock());
+    for violation in &violations {
+        csv_writer.serialize(violation)?;
+    }
+    csv_writer.flush()?;
+
+    if !violations.is_empty() {
+        bail!(
+            "{} violations found in {}",
+            violations.len(),
+            args.path.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn check_headers(headers: &[String]) -> Vec<Violation> {
+    let violation = |column: &str, problem: String| Violation {
+        line: 1,
+        column: column.to_owned(),
+        problem,
+    };
+
+    let mut violations = Vec::new();
+
+    for (index, &name) in REQUIRED.iter().enumerate() {
+        match headers.iter().position(|header| header == name) {
+            None => violations.push(violation(name, "missing column".to_owned())),
+            Some(position) if position != index => violations.push(violation(
+                name,
+                format!(
+                    "expected column {}, found column {}",
+                    index + 1,
+                    position + 1
+                ),
+            )),
+            Some(_) => (),
+        }
+    }
+
+    for (position, header) in headers.iter().enumerate() {
+        let known = REQUIRED.contains(&header.as_str()) || OPTIONAL.contains(&header.as_str());
+
+        if !known {
+            violations.push(violation(header, "unknown column".to_owned()));
+        } else if headers[..position].contains(header) {
+            violations.push(violation(header, "repeated column".to_owned()));
+        }
+    }
+
+    violations
+}
+
+fn check_row(
+    record: &csv::ByteRecord,
+    headers: &[String],
+    columns: Columns,
+    max_scale: u32,
+    violations: &mut Vec<Violation>,
+) {
+    let line = record.position().map_or(0, csv::Position::line);
+
+    let mut violation = |column: &str, problem: String| {
+        violations.push(Violation {
+            line,
+            column: column.to_owned(),
+            problem,
+        });
+    };
+
+    if record.len() != headers.len() {
+        violation(
+            "",
+            format!("expected {} fields, found {}", headers.len(), record.len()),
+        );
+    }
+
+    let field = |column: Option<usize>| record.get(column?).map(str::from_utf8);
+
+    let r#type = match field(columns.r#type) {
+        Some(Ok(name)) => match name.parse::<TransactionType>() {
+            Ok(r#type) => Some(r#type),
+            Err(err) => {
+                violation("type", format!("invalid type {name:?}: {err}"));
+                None
+            }
+        },
+        Some(Err(_)) => {
+            violation("type", "not valid UTF-8".to_owned());
+            None
+        }
+        None => None,
+    };
+
+    for (name, column, max) in [
+        ("client", columns.client, u128::from(RawClientId::MAX)),
+        ("tx", columns.tx, u128::from(RawTransactionId::MAX)),
+    ] {
+        match field(column) {
+            Some(Ok(value)) => {
+                if let Some(problem) = check_id(value, max) {
+                    violation(name, problem);
+                }
+            }
+            Some(Err(_)) => violation(name, "not valid UTF-8".to_owned()),
+            None => (),
+        }
+    }
+
+    let amount = match field(columns.amount) {
+        Some(Ok(value)) => Some(value).filter(|value| !value.is_empty()),
+        Some(Err(_)) => {
+            violation("amount", "not valid UTF-8".to_owned());
+            return;
+        }
+        None => None,
+    };
+
+    let needs_amount = match r#type {
+        Some(TransactionType::Deposit | TransactionType::Withdrawal) => true,
+        Some(TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback) => {
+            false
+        }
+        // Without a type there's no telling whether an amount belongs.
+        None => return,
+    };
+
+    match amount {
+        None if needs_amount && columns.amount.is_some() => {
+            violation("amount", "missing amount".to_owned());
+        }
+        Some(_) if !needs_amount => violation("amount", "unexpected amount".to_owned()),
+        Some(value) => {
+            if let Some(problem) = check_amount(value, max_scale) {
+                violation("amount", problem);
+            }
+        }
+        None => (),
+    }
+}
+
+fn check_id(value: &str, max: u128) -> Option<String> {
+    if value.is_empty() {
+        return Some("missing ID".to_owned());
+    }
+
+    if !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Some(format!("invalid ID {value:?}"));
+    }
+
+    value
+        .parse::<u128>()
+        .map_or(true, |id| id > max)
+        .then(|| format!("ID {value} out of range (max: {max})"))
+}
+
+fn check_amount(value: &str, max_scale: u32) -> Option<String> {
+    let Ok(amount) = value.parse::<Decimal>() else {
+        return Some(format!("invalid amount {value:?}"));
+    };
+
+    if amount <= Decimal::ZERO {
+        return Some(format!("amount {value} not positive"));
+    }
+
+    (amount.scale() > max_scale)
+        .then(|| format!("amount {value} has more than {max_scale} decimal places"))
+}
@@ -0,0 +1,78 @@
+use std::{collections::BTreeMap, io};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{engine::Engine, output::Rounding, ClientId, TransactionId};
+
+#[derive(Clone, Debug, Serialize)]
+struct WalletRecord<'a> {
+    client: ClientId,
+    wallet: &'a str,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+/// Keeps labelled balances, such as `bonus`, apart from clients' main accounts.
+///
+/// Each wallet is an engine of its own, so a dispute only finds deposits made to the same wallet.
+/// Wallets share one ID space with the main accounts, which the processor checks with
+/// [`Wallets::is_known_id`].
+pub struct Wallets<'a> {
+    engines: BTreeMap<String, Engine>,
+    rounding: Rounding,
+    writer: csv::Writer<Box<dyn io::Write + 'a>>,
+}
+
+impl<'a> Wallets<'a> {
+    pub fn new(writer: impl io::Write + 'a, rounding: Rounding) -> Self {
+        Self {
+            engines: BTreeMap::new(),
+            rounding,
+            writer: csv::Writer::from_writer(Box::new(writer)),
+        }
+    }
+
+    /// The engine for `wallet`, created by `new` the first time the wallet is seen.
+    pub fn engine(&mut self, wallet: &str, new: impl FnOnce() -> Engine) -> &mut Engine {
+        self.engines.entry(wallet.to_owned()).or_insert_with(new)
+    }
+
+    /// Whether a deposit or withdrawal with this ID has been applied to any wallet but `except`.
+    pub fn is_known_id(&self, tx: TransactionId, except: Option<&str>) -> bool {
+        self.engines
+            .iter()
+            .any(|(wallet, engine)| Some(wallet.as_str()) != except && engine.is_known_id(tx))
+    }
+
+    /// Writes one row per client and wallet, ordered by client.
+    pub fn finish(&mut self) -> Result<(), csv::Error> {
+        let mut rows: Vec<_> = self
+            .engines
+            .iter()
+            .flat_map(|(wallet, engine)| {
+                engine
+                    .accounts()
+                    .map(move |(&client, account)| (client, wallet.as_str(), account))
+            })
+            .collect();
+        rows.sort_by_key(|&(client, wallet, _)| (client, wallet));
+
+        for (client, wallet, account) in rows {
+            self.writer.serialize(WalletRecord {
+                client,
+                wallet,
+                available: self.rounding.round(account.available()),
+                held: self.rounding.round(account.held()),
+                total: self.rounding.round(account.total()),
+                locked: account.locked(),
+            })?;
+        }
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    convert::Infallible,
+    fs,
+    path::{Path, PathBuf},
+    slice, thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    clients::Clients,
+    follow,
+    input::{self, ReadOptions},
+    output::Rounding,
+    processor::Processor,
+    state,
+};
+
+/// How long to wait before checking the directory for new files again.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where files are moved once they are applied, or once they fail to read.
+const PROCESSED: &str = "processed";
+const FAILED: &str = "failed";
+
+/// Where the account report is rewritten after every file.
+const REPORT: &str = "accounts.csv";
+
+/// Applies every file that appears in `dir`, in order of name, rewriting the account report in
+/// `dir` after each one. This only returns on error.
+///
+/// Each file is read in full and then applied in full or not at all, so a file that fails to
+/// read or to apply is moved to `failed` without changing the engine. Records are only reordered
+/// within a file. Files whose names start with a dot are left alone, so they can be written under
+/// such a name and renamed once they are complete.
+///
+/// If `save_state` is given, the engine is saved there after every file that is applied, so a
+/// restart can carry on from it.
+pub fn run(
+    dir: &Path,
+    options: &ReadOptions,
+    mut processor: Processor,
+    save_state: Option<&Path>,
+    rounding: Rounding,
+    clients: Option<&Clients>,
+) -> Result<Infallible, anyhow::Error> {
+    let processed = dir.join(PROCESSED);
+    let failed = dir.join(FAILED);
+    let report = dir.join(REPORT);
+
+    for subdir in [&processed, &failed] {
+        fs::create_dir_all(subdir)
+            .with_context(|| format!("failed to create {}", subdir.display()))?;
+    }
+
+    loop {
+        let paths = new_files(dir)?;
+
+        if paths.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        for path in paths {
+            let mut transactions = Vec::new();
+
+            let read_res = input::for_each_record(
+                slice::from_ref(&path),
+                options,
+                processor.policy(),
+                |transaction| {
+                    transactions.push(transaction);
+                    Ok(())
+                },
+            );
+
+            let apply_res = read_res.and_then(|()| {
+                processor
+                    .push_batch(transactions)
+                    .with_context(|| format!("failed to apply {}", path.display()))
+            });
+
+            let dest = match apply_res {
+                Ok(()) => {
+                    if let Some(save_state) = save_state {
+                        state::save(processor.engine(), save_state)?;
+                    }
+
+                    &processed
+                }
+                Err(err) => {
+                    eprintln!("warning: {err:#}");
+                    &failed
+                }
+            };
+
+            processor.flush()?;
+            follow::write_report(&processor, &report, rounding, clients)?;
+
+            move_into(&path, dest)?;
+        }
+    }
+}
+
+/// Lists the files waiting in `dir`, sorted by name.
+fn new_files(dir: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut paths = Vec::new();
+
+    let entries = fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+
+    for entry_res in entries {
+        let entry = entry_res.with_context(|| format!("failed to read {}", dir.display()))?;
+        let name = entry.file_name();
+
+        // The report's temporary file is skipped along with the report.
+        let skipped = name
+            .to_str()
+            .is_none_or(|name| name.starts_with('.') || name.starts_with(REPORT));
+
+        if skipped || !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        paths.push(entry.path());
+    }
+
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn move_into(path: &Path, dir: &Path) -> Result<(), anyhow::Error> {
+    let Some(name) = path.file_name() else {
+        bail!("invalid input path: {}", path.display());
+    };
+
+    let dest = dir.join(name);
+
+    fs::rename(path, &dest)
+        .with_context(|| format!("failed to move {} to {}", path.display(), dest.display()))
+}